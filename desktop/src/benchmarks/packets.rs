@@ -0,0 +1,79 @@
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use renderer::{Hittable, PacketHits, Ray, RayPacket, Scene, PACKET_SIZE};
+
+// Primary rays in the same order the renderer visits pixels: 16x16 tiles, row by row
+fn primary_rays(scene: &Scene, width: u32, height: u32, tile_size: u32) -> Vec<Ray> {
+    let mut rng = rand::thread_rng();
+    let mut rays = Vec::with_capacity((width * height) as usize);
+
+    for tile_y in (0..height).step_by(tile_size as usize) {
+        for tile_x in (0..width).step_by(tile_size as usize) {
+            for y in tile_y..u32::min(tile_y + tile_size, height) {
+                for x in tile_x..u32::min(tile_x + tile_size, width) {
+                    let u = (x as f64 + rng.gen::<f64>()) / (width - 1) as f64;
+                    let v = (y as f64 + rng.gen::<f64>()) / (height - 1) as f64;
+                    rays.push(scene.camera.get_ray(u, v));
+                }
+            }
+        }
+    }
+
+    rays
+}
+
+fn time_single(scene: &Scene, rays: &[Ray]) -> (Duration, usize) {
+    let start = Instant::now();
+    let hits = rays
+        .iter()
+        .filter(|ray| scene.hit(ray, 0.001, 100000.0).is_some())
+        .count();
+
+    (start.elapsed(), hits)
+}
+
+fn time_packets(scene: &Scene, rays: &[Ray]) -> (Duration, usize) {
+    let start = Instant::now();
+    let hits = rays
+        .chunks(PACKET_SIZE)
+        .map(|chunk| {
+            let packet = RayPacket::new(chunk);
+            let mut hits = PacketHits::new(100000.0);
+            scene.hit_packet(&packet, packet.full_mask(), 0.001, &mut hits);
+            hits.hits.iter().filter(|hit| hit.is_some()).count()
+        })
+        .sum();
+
+    (start.elapsed(), hits)
+}
+
+pub fn run(scene: &Scene, width: u32, height: u32, iterations: u32) {
+    let rays = primary_rays(scene, width, height, 16);
+    println!(
+        "Tracing {} primary rays {} times, packets of {}",
+        rays.len(),
+        iterations,
+        PACKET_SIZE
+    );
+
+    let mut single_time = Duration::ZERO;
+    let mut packet_time = Duration::ZERO;
+
+    for _ in 0..iterations {
+        let (time, single_hits) = time_single(scene, &rays);
+        single_time += time;
+
+        let (time, packet_hits) = time_packets(scene, &rays);
+        packet_time += time;
+
+        assert_eq!(single_hits, packet_hits, "Packet tracing disagrees");
+    }
+
+    println!("Single rays: {:?}", single_time / iterations);
+    println!("Packets:     {:?}", packet_time / iterations);
+    println!(
+        "Speedup:     {:.2}x",
+        single_time.as_secs_f64() / packet_time.as_secs_f64()
+    );
+}
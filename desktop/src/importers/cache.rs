@@ -0,0 +1,106 @@
+use renderer::{BVHBuildParams, Material, MeshBVH};
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
+
+use super::obj::{load_obj, VertexPrecision};
+
+const MAGIC: &[u8; 8] = b"RTMESH\0\0";
+/// Bump whenever the layout written by `MeshBVH::write` or the meshes built by `load_obj` change
+const FORMAT_VERSION: u32 = 3;
+
+const HEADER_SIZE: usize = 8 + 4 + 8 + 4;
+
+/// FNV-1a, stable across runs and platforms unlike `DefaultHasher`
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn cache_key(source: &[u8], params: &BVHBuildParams, precision: VertexPrecision) -> u64 {
+    let hash = fnv1a(0xcbf29ce484222325, source);
+    let hash = fnv1a(hash, &params.max_leaf_triangles.to_le_bytes());
+    let hash = fnv1a(hash, &[precision as u8]);
+    fnv1a(hash, &FORMAT_VERSION.to_le_bytes())
+}
+
+fn cache_path(file_name: &str) -> String {
+    format!("{}.cache", file_name)
+}
+
+fn read_cache(path: &str, key: u64, material: &Arc<dyn Material>) -> Option<Vec<MeshBVH>> {
+    // The meshes own their buffers, so the whole file is copied into them either way
+    let bytes = fs::read(path).ok()?;
+
+    if bytes.len() < HEADER_SIZE || &bytes[0..8] != MAGIC {
+        return None;
+    }
+    let version = u32::from_le_bytes(bytes[8..12].try_into().ok()?);
+    let stored_key = u64::from_le_bytes(bytes[12..20].try_into().ok()?);
+    if version != FORMAT_VERSION || stored_key != key {
+        return None;
+    }
+    let mesh_count = u32::from_le_bytes(bytes[20..24].try_into().ok()?);
+
+    let mut position = HEADER_SIZE;
+    let mut meshes = Vec::new();
+    for _ in 0..mesh_count {
+        let (mesh, consumed) = MeshBVH::read(&bytes[position..], material.clone())?;
+        meshes.push(mesh);
+        position += consumed;
+    }
+
+    if position != bytes.len() {
+        return None;
+    }
+
+    Some(meshes)
+}
+
+fn write_cache(path: &str, key: u64, meshes: &[MeshBVH]) -> std::io::Result<()> {
+    let temp_path = format!("{}.tmp", path);
+    {
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&key.to_le_bytes())?;
+        writer.write_all(&(meshes.len() as u32).to_le_bytes())?;
+        for mesh in meshes {
+            mesh.write(&mut writer)?;
+        }
+        writer.flush()?;
+    }
+    fs::rename(temp_path, path)
+}
+
+/// Loads an OBJ with a BVH built per object, reusing `<file_name>.cache` when it was built from
+/// the same file contents, parameters and precision. A stale or missing cache is rebuilt and rewritten.
+pub fn load_obj_cached(
+    file_name: &str,
+    material: Arc<dyn Material>,
+    params: BVHBuildParams,
+    precision: VertexPrecision,
+) -> Option<Vec<MeshBVH>> {
+    let source = fs::read(file_name).ok()?;
+    let key = cache_key(&source, &params, precision);
+    let path = cache_path(file_name);
+
+    if let Some(meshes) = read_cache(&path, key, &material) {
+        return Some(meshes);
+    }
+
+    println!("Building BVH cache for {}", file_name);
+    let meshes = load_obj(file_name, material, precision)?
+        .into_iter()
+        .filter(|triangles| !triangles.is_empty())
+        .map(|triangles| MeshBVH::new(triangles, params))
+        .collect::<Vec<_>>();
+
+    if let Err(err) = write_cache(&path, key, &meshes) {
+        println!("Failed to write mesh cache {}: {}", path, err);
+    }
+
+    Some(meshes)
+}
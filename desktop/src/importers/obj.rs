@@ -1,11 +1,26 @@
-use glam::{DVec2, DVec3};
+use glam::{Vec2, Vec3};
 use obj::*;
 use renderer::{create_mesh, Material, Triangle};
+use std::collections::HashMap;
 use std::sync::Arc;
 
-pub fn load_obj(file_name: &str, material: Arc<dyn Material>) -> Option<Vec<Vec<Triangle>>> {
+/// Precision the vertex buffers of loaded meshes are stored in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VertexPrecision {
+    Single,
+    Double,
+}
+
+pub fn load_obj(
+    file_name: &str,
+    material: Arc<dyn Material>,
+    precision: VertexPrecision,
+) -> Option<Vec<Vec<Triangle>>> {
     let obj = Obj::load(file_name).ok()?;
 
+    let (positions, texture_coords, object_normals) =
+        (&obj.data.position, &obj.data.texture, &obj.data.normal);
+
     let mut meshes = Vec::new();
 
     for object in obj.data.objects {
@@ -14,44 +29,56 @@ pub fn load_obj(file_name: &str, material: Arc<dyn Material>) -> Option<Vec<Vec<
         let mut uvs = Vec::new();
         let mut indices = Vec::new();
 
-        let mut index = 0;
+        // Faces share vertices that have the same position, uv and normal
+        let mut unique_vertices = HashMap::new();
+        let mut has_normals = true;
 
         for group in object.groups {
             for poly in group.polys {
                 assert!(poly.0.len() == 3);
 
-                for index_tuple in poly.0 {
-                    let pos = obj.data.position[index_tuple.0];
-                    vertices.push(DVec3::new(pos[0] as f64, pos[1] as f64, pos[2] as f64));
+                let mut triangle = [0; 3];
+                for (corner, index_tuple) in triangle.iter_mut().zip(poly.0) {
+                    *corner = *unique_vertices.entry(index_tuple).or_insert_with(|| {
+                        vertices.push(Vec3::from(positions[index_tuple.0]));
 
-                    uvs.push(match index_tuple.1 {
-                        Some(uv_index) => {
-                            let uv = obj.data.texture[uv_index];
-                            DVec2::new(uv[0] as f64, uv[1] as f64)
-                        }
-                        None => DVec2::new(0.0, 0.0),
-                    });
+                        uvs.push(match index_tuple.1 {
+                            Some(uv_index) => Vec2::from(texture_coords[uv_index]),
+                            None => Vec2::new(0.0, 0.0),
+                        });
 
-                    normals.push(match index_tuple.2 {
-                        Some(normal_index) => {
-                            let normal = obj.data.normal[normal_index];
-                            DVec3::new(normal[0] as f64, normal[1] as f64, normal[2] as f64)
-                        }
-                        None => DVec3::X,
+                        normals.push(match index_tuple.2 {
+                            Some(normal_index) => Vec3::from(object_normals[normal_index]),
+                            None => {
+                                has_normals = false;
+                                Vec3::ZERO
+                            }
+                        });
+
+                        vertices.len() as u32 - 1
                     });
                 }
-                indices.push([index, index + 1, index + 2]);
-                index = index + 3
+                indices.push(triangle);
             }
         }
 
-        meshes.push(create_mesh(
-            vertices,
-            normals,
-            uvs,
-            indices,
-            material.clone(),
-        ));
+        // Triangles fall back to their face normals when any vertex is missing one
+        if !has_normals {
+            normals.clear();
+        }
+
+        meshes.push(match precision {
+            VertexPrecision::Single => {
+                create_mesh(vertices, normals, uvs, indices, material.clone())
+            }
+            VertexPrecision::Double => create_mesh(
+                vertices.iter().map(|v| v.as_f64()).collect::<Vec<_>>(),
+                normals.iter().map(|n| n.as_f64()).collect::<Vec<_>>(),
+                uvs.iter().map(|uv| uv.as_f64()).collect::<Vec<_>>(),
+                indices,
+                material.clone(),
+            ),
+        });
     }
 
     Some(meshes)
@@ -13,12 +13,13 @@ use renderer::Transformable;
 use renderer::Transformed;
 use renderer::UniformSampledPathIntegrator;
 use renderer::{
-    rand_in_range, random, AARect, BRDFSampledPathIntegrator, BVHNode, Camera, CheckerTexture,
-    Dielectric, DiffuseLight, Hittable, Image, Lambertian, Material, Metal, MovingSphere, Ray,
-    SolidColour, Sphere,
+    rand_in_range, random, AARect, BRDFSampledPathIntegrator, BVHBuildParams, BVHNode, Camera,
+    CheckerTexture, ComplexIor, Dielectric, DiffuseLight, EnvironmentLight, Hittable, Image,
+    Lambertian, Material, Metal, MovingSphere, NormalMapped, PrincipledBSDF, Ray, RayPacket,
+    SolidColour, Sphere, SurfaceDetail, PACKET_SIZE,
 };
 
-use glam::{DMat4, DVec3};
+use glam::{DMat4, DQuat, DVec3};
 
 use rayon::prelude::*;
 
@@ -27,11 +28,14 @@ use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Instant;
 
-use importers::obj::load_obj;
+use importers::cache::load_obj_cached;
+use importers::obj::{load_obj, VertexPrecision};
 mod importers;
 
 mod exporters;
 
+mod benchmarks;
+
 type SceneDescription = (Vec<Arc<dyn Hittable>>, Camera, fn(Ray) -> DVec3);
 
 fn skybox(ray: Ray) -> DVec3 {
@@ -115,10 +119,14 @@ fn create_cornell_scene() -> SceneDescription {
 
     let grey_material = Arc::new(Lambertian::new(DVec3::splat(0.1)));
     let grey_cube = Arc::new(BVHNode::from_mesh(
-        load_obj("F:\\Models\\cube.obj", grey_material.clone())
-            .unwrap()
-            .pop()
-            .unwrap(),
+        load_obj(
+            "F:\\Models\\cube.obj",
+            grey_material.clone(),
+            VertexPrecision::Double,
+        )
+        .unwrap()
+        .pop()
+        .unwrap(),
         0.0,
         0.0,
     ));
@@ -130,30 +138,42 @@ fn create_cornell_scene() -> SceneDescription {
     });
 
     let lit_cube = Arc::new(BVHNode::from_mesh(
-        load_obj("F:\\Models\\cube.obj", lit_material.clone())
-            .unwrap()
-            .pop()
-            .unwrap(),
+        load_obj(
+            "F:\\Models\\cube.obj",
+            lit_material.clone(),
+            VertexPrecision::Double,
+        )
+        .unwrap()
+        .pop()
+        .unwrap(),
         0.0,
         0.0,
     ));
 
     let red_material = Arc::new(Lambertian::new(DVec3::new(1.0, 0.0, 0.0)));
     let red_cube = Arc::new(BVHNode::from_mesh(
-        load_obj("F:\\Models\\cube.obj", red_material.clone())
-            .unwrap()
-            .pop()
-            .unwrap(),
+        load_obj(
+            "F:\\Models\\cube.obj",
+            red_material.clone(),
+            VertexPrecision::Double,
+        )
+        .unwrap()
+        .pop()
+        .unwrap(),
         0.0,
         0.0,
     ));
 
     let green_material = Arc::new(Lambertian::new(DVec3::new(0.0, 1.0, 0.0)));
     let green_cube = Arc::new(BVHNode::from_mesh(
-        load_obj("F:\\Models\\cube.obj", green_material.clone())
-            .unwrap()
-            .pop()
-            .unwrap(),
+        load_obj(
+            "F:\\Models\\cube.obj",
+            green_material.clone(),
+            VertexPrecision::Double,
+        )
+        .unwrap()
+        .pop()
+        .unwrap(),
         0.0,
         0.0,
     ));
@@ -239,6 +259,7 @@ fn create_sphere_scene() -> SceneDescription {
         "F:\\Models\\cube.obj",
         //"F:\\Models\\JapaneseTemple\\model_triangulated.obj",
         sphere_material.clone(),
+        VertexPrecision::Double,
     )
     .unwrap();
 
@@ -292,7 +313,7 @@ fn create_simple_scene() -> SceneDescription {
 
     let shiny_metal_material = Arc::new(Metal::new(DVec3::new(0.1, 0.1, 0.1), 0.0));
 
-    let left_material = Arc::new(Dielectric { ior: 1.5 });
+    let left_material = Arc::new(Dielectric::new(1.5));
 
     let right_material: Arc<dyn Material> = Arc::new(Metal::new(DVec3::new(0.8, 0.6, 0.2), 1.0));
 
@@ -413,7 +434,7 @@ fn create_random_scene(motion_blur: bool) -> Scene {
                     }));
                 } else {
                     // Glass
-                    let material = Arc::new(Dielectric { ior: 1.5 });
+                    let material = Arc::new(Dielectric::new(1.5));
                     scene_builder.add_object(Arc::new(Sphere {
                         center,
                         radius: 0.2,
@@ -424,7 +445,8 @@ fn create_random_scene(motion_blur: bool) -> Scene {
         }
     }
 
-    let material = Arc::new(Dielectric { ior: 1.5 });
+    let material = Arc::new(Dielectric::new(1.5));
+    // let material = Arc::new(Dielectric::new(2.42).with_dispersion(Dispersion::diamond()));
     scene_builder.add_object(Arc::new(Sphere {
         center: DVec3::new(0.0, 1.0, 0.0),
         radius: 1.0,
@@ -489,7 +511,13 @@ fn create_random_scene(motion_blur: bool) -> Scene {
         if motion_blur { 1.0 } else { 0.0 },
     ));
 
-    //scene_builder.background(skybox).build_bvh().build()
+    //scene_builder
+    //    .background(GradientBackground {
+    //        bottom: DVec3::ONE,
+    //        top: DVec3::new(0.5, 0.7, 1.0),
+    //    })
+    //    .build_bvh()
+    //    .build()
     scene_builder.background(no_light).build_bvh().build()
 }
 
@@ -786,10 +814,82 @@ fn single_sphere_light_scene() -> Scene {
         .build()
 }
 
+#[allow(dead_code)]
+fn environment_scene() -> Scene {
+    let environment = EnvironmentLight::new(
+        load_hdr("textures/environment.hdr").expect("Couldn't load environment map"),
+        DQuat::from_rotation_y(0.5),
+        1.0,
+    );
+
+    let mut scene_builder = Scene::build();
+    // A daylight sky instead, with its sun
+    //let sky = PreethamSky::new(DVec3::new(1.0, 0.5, 0.3), 3.0, DVec3::splat(0.2))
+    //    .with_intensity(0.1);
+    //let environment = sky.environment_light(1024);
+    //scene_builder.add_light(Arc::new(sky.sun()));
+
+    scene_builder.add_object(Arc::new(Sphere {
+        center: DVec3::new(0.0, -1000.0, 0.0),
+        radius: 1000.0,
+        material: Arc::new(Lambertian::new(DVec3::splat(0.5))),
+    }));
+    scene_builder.add_object(Arc::new(Sphere {
+        center: DVec3::new(-2.2, 1.0, 0.0),
+        radius: 1.0,
+        material: Arc::new(Metal::conductor(ComplexIor::gold(), 0.2)),
+    }));
+    scene_builder.add_object(Arc::new(Sphere {
+        center: DVec3::new(0.0, 1.0, 0.0),
+        radius: 1.0,
+        // Car paint
+        material: Arc::new(
+            PrincipledBSDF::new(DVec3::new(0.7, 0.3, 0.3))
+                .with_roughness(0.4)
+                .with_clearcoat(1.0),
+        ),
+    }));
+    scene_builder.add_object(Arc::new(Sphere {
+        center: DVec3::new(2.2, 1.0, 0.0),
+        radius: 1.0,
+        material: Arc::new(Dielectric::new(1.5)),
+    }));
+
+    let look_from = DVec3::new(0.0, 2.0, 8.0);
+    let look_at = DVec3::new(0.0, 1.0, 0.0);
+
+    let camera = Camera::new_instant(
+        look_from,
+        look_at,
+        DVec3::new(0.0, 1.0, 0.0),
+        40.0,
+        16.0 / 9.0,
+        0.0,
+        (look_at - look_from).length(),
+    );
+
+    scene_builder
+        .camera(camera)
+        .environment(Arc::new(environment))
+        .build_bvh()
+        .build()
+}
+
 fn mesh_scene() -> Scene {
-    let triangle_mat = Arc::new(Lambertian {
-        albedo: Arc::new(load_texture("models/pagoda/textures/albedo.png").unwrap()),
-    });
+    let albedo_mat: Arc<dyn Material> = match load_texture("models/pagoda/textures/albedo.png") {
+        Some(albedo) => Arc::new(Lambertian {
+            albedo: Arc::new(albedo),
+        }),
+        None => Arc::new(Lambertian::new(DVec3::splat(0.5))),
+    };
+    let triangle_mat: Arc<dyn Material> =
+        match load_linear_texture("models/pagoda/textures/normal.png") {
+            Some(normal_map) => Arc::new(NormalMapped {
+                material: albedo_mat,
+                detail: SurfaceDetail::NormalMap(Arc::new(normal_map)),
+            }),
+            None => albedo_mat,
+        };
 
     //let triangle_mat = Arc::new(Lambertian::new(DVec3::splat(0.5)));
 
@@ -800,24 +900,28 @@ fn mesh_scene() -> Scene {
     //)
     //.unwrap();
 
-    let test_mesh = load_obj(
+    let test_mesh = load_obj_cached(
         //"F:\\Models\\cube.obj",
         "models/pagoda/model_triangulated.obj",
         triangle_mat,
+        BVHBuildParams::default(),
+        VertexPrecision::Single,
     )
     .unwrap();
 
     let mut scene_builder = Scene::build();
 
     test_mesh.into_iter().for_each(|mesh| {
-        mesh.into_iter()
-            .map(|triangle| {
-                Arc::new(Transformed::new(
-                    DMat4::from_translation(DVec3::splat(5.0)),
-                    Arc::new(triangle),
-                )) as Arc<dyn Hittable>
-            })
-            .for_each(|triangle| scene_builder.add_object(triangle))
+        println!(
+            "Mesh with {} triangles, {} vertices: {:.2} MiB",
+            mesh.triangles().len(),
+            mesh.mesh().vertices.len(),
+            mesh.memory_usage() as f64 / (1024.0 * 1024.0)
+        );
+        scene_builder.add_object(Arc::new(Transformed::new(
+            DMat4::from_translation(DVec3::splat(5.0)),
+            Arc::new(mesh),
+        )))
     });
 
     let ground_material: Arc<dyn Material> = Arc::new(Lambertian {
@@ -889,6 +993,15 @@ fn mesh_scene() -> Scene {
 }
 
 fn load_texture(filename: &str) -> Option<Image> {
+    load_image(filename, 2) // Gamma correction approximation
+}
+
+/// For textures holding data rather than colours, like normal maps, which are already linear
+fn load_linear_texture(filename: &str) -> Option<Image> {
+    load_image(filename, 1)
+}
+
+fn load_image(filename: &str, gamma: i32) -> Option<Image> {
     let tex = image::open(filename).ok()?.into_rgb8();
     let mut tex_image = Image::new(tex.dimensions());
     for y in 0..tex.dimensions().1 {
@@ -898,9 +1011,9 @@ fn load_texture(filename: &str) -> Option<Image> {
                 x,
                 y,
                 &DVec3::new(
-                    (pixel[0] as f64 / 255.0).powi(2), // Gamma correction approximation
-                    (pixel[1] as f64 / 255.0).powi(2),
-                    (pixel[2] as f64 / 255.0).powi(2),
+                    (pixel[0] as f64 / 255.0).powi(gamma),
+                    (pixel[1] as f64 / 255.0).powi(gamma),
+                    (pixel[2] as f64 / 255.0).powi(gamma),
                 ),
             );
         }
@@ -909,6 +1022,25 @@ fn load_texture(filename: &str) -> Option<Image> {
     Some(tex_image)
 }
 
+/// Loads an HDR image as linear radiance
+fn load_hdr(filename: &str) -> Option<Image> {
+    let file = std::io::BufReader::new(std::fs::File::open(filename).ok()?);
+    let decoder = image::codecs::hdr::HdrDecoder::new(file).ok()?;
+    let metadata = decoder.metadata();
+    let pixels = decoder.read_image_hdr().ok()?;
+
+    let mut hdr_image = Image::new((metadata.width, metadata.height));
+    for (i, pixel) in pixels.iter().enumerate() {
+        hdr_image.put(
+            i as u32 % metadata.width,
+            i as u32 / metadata.width,
+            &DVec3::new(pixel[0] as f64, pixel[1] as f64, pixel[2] as f64),
+        );
+    }
+
+    Some(hdr_image)
+}
+
 // https://knarkowicz.wordpress.com/2016/01/06/aces-filmic-tone-mapping-curve/
 fn aces_tonemapping(pixel: DVec3) -> DVec3 {
     let a = 2.51;
@@ -925,11 +1057,21 @@ fn main() {
     let aspect_ratio = 16.0 / 9.0;
     let height = (width as f64 / aspect_ratio) as u32;
 
+    if std::env::args().any(|arg| arg == "--bench-packets") {
+        benchmarks::packets::run(&create_random_scene(false), width, height, 5);
+        return;
+    }
+
     //let (world, camera, background_colour) = simple_triangle_scene();
     //let (world, camera, background_colour) = mesh_scene();
     //let scene = single_sphere_light_scene();
-    let scene = create_random_scene(false);
-    //let scene = mesh_scene();
+    // Loads the pagoda through the BVH cache
+    let scene = if std::env::args().any(|arg| arg == "--mesh") {
+        mesh_scene()
+    } else {
+        create_random_scene(false)
+    };
+    //let scene = environment_scene();
     //let (world, camera, background_colour) = create_simple_scene();
     //let (world, camera, background_colour) = create_sphere_scene();
     //let (world, camera, background_colour) = create_cornell_scene();
@@ -951,6 +1093,9 @@ fn main() {
     //let integrator = UniformSampledPathIntegrator {};
     //let integrator = ImportanceSampleLightIntegrator {};
     let integrator = MultipleImportanceSampleIntegrator {};
+    //let integrator = SpectralIntegrator {
+    //    integrator: MultipleImportanceSampleIntegrator {},
+    //};
 
     let tile_size = 16;
     let num_tiles = (
@@ -988,29 +1133,33 @@ fn main() {
             };
 
             let mut rng = rand::thread_rng();
-            for index in 0..(tile.size.0 * tile.size.1) {
-                let (x, y) = tile.get_xy(index);
-
-                let mut colour = DVec3::new(0.0, 0.0, 0.0);
-                for _ in 0..samples_per_pixel {
-                    let u = (x as f64 + rng.gen::<f64>()) / (width - 1) as f64;
-                    let v = (y as f64 + rng.gen::<f64>()) / (height - 1) as f64;
-
-                    let ray = scene.camera.get_ray(u, v);
-                    colour += integrator.ray_colour(
-                        ray, &scene,
-                        //&background_colour,
-                        //&world.as_slice(),
-                        //&bvh,
-                        max_depth,
-                    );
+            let pixel_count = tile.size.0 * tile.size.1;
+            let mut colours = vec![DVec3::ZERO; pixel_count as usize];
+
+            // Neighbouring pixels share a packet for their primary rays
+            for _ in 0..samples_per_pixel {
+                for first in (0..pixel_count).step_by(PACKET_SIZE) {
+                    let indices = first..u32::min(first + PACKET_SIZE as u32, pixel_count);
+                    let rays = indices
+                        .clone()
+                        .map(|index| {
+                            let (x, y) = tile.get_xy(index);
+                            let u = (x as f64 + rng.gen::<f64>()) / (width - 1) as f64;
+                            let v = (y as f64 + rng.gen::<f64>()) / (height - 1) as f64;
+                            scene.camera.get_ray(u, v)
+                        })
+                        .collect::<Vec<_>>();
+
+                    let packet = RayPacket::new(&rays);
+                    let packet_colours = integrator.packet_colours(&packet, &scene, max_depth);
+                    for (lane, index) in indices.enumerate() {
+                        colours[index as usize] += packet_colours[lane];
+                    }
                 }
+            }
 
-                colour /= (samples_per_pixel as f64);
-
-                colour = aces_tonemapping(colour);
-
-                tile.pixels[index as usize] = colour;
+            for (pixel, colour) in tile.pixels.iter_mut().zip(colours) {
+                *pixel = aces_tonemapping(colour / samples_per_pixel as f64);
             }
 
             let mut locked_img = img.lock().unwrap();
@@ -0,0 +1,110 @@
+/// Samples an index with probability proportional to its weight in constant time (Vose's method)
+#[derive(Clone, Debug)]
+pub struct AliasTable {
+    /// Probability of keeping each bin rather than taking its alias
+    threshold: Vec<f64>,
+    alias: Vec<usize>,
+    pmf: Vec<f64>,
+}
+
+impl AliasTable {
+    /// Weights must be non-negative. If they are all zero every index is equally likely.
+    pub fn new(weights: &[f64]) -> AliasTable {
+        assert!(!weights.is_empty(), "Alias table needs at least one weight");
+
+        let total: f64 = weights.iter().sum();
+        let pmf = if total > 0.0 {
+            weights
+                .iter()
+                .map(|weight| weight / total)
+                .collect::<Vec<_>>()
+        } else {
+            vec![1.0 / weights.len() as f64; weights.len()]
+        };
+
+        let count = pmf.len();
+        let mut scaled = pmf.iter().map(|p| p * count as f64).collect::<Vec<_>>();
+        let mut threshold = vec![1.0; count];
+        let mut alias = (0..count).collect::<Vec<_>>();
+
+        let (mut small, mut large): (Vec<usize>, Vec<usize>) =
+            (0..count).partition(|&index| scaled[index] < 1.0);
+
+        while let (Some(&under), Some(&over)) = (small.last(), large.last()) {
+            small.pop();
+            threshold[under] = scaled[under];
+            alias[under] = over;
+
+            scaled[over] -= 1.0 - scaled[under];
+            if scaled[over] < 1.0 {
+                large.pop();
+                small.push(over);
+            }
+        }
+        // Anything left over is 1 up to rounding error, so it keeps its own bin
+
+        AliasTable {
+            threshold,
+            alias,
+            pmf,
+        }
+    }
+
+    /// Returns the sampled index and its probability, `u` must be in [0, 1)
+    pub fn sample(&self, u: f64) -> (usize, f64) {
+        let scaled = u * self.len() as f64;
+        let bin = (scaled as usize).min(self.len() - 1);
+        let remainder = scaled - bin as f64;
+
+        let index = if remainder < self.threshold[bin] {
+            bin
+        } else {
+            self.alias[bin]
+        };
+
+        (index, self.pmf[index])
+    }
+
+    pub fn pmf(&self, index: usize) -> f64 {
+        self.pmf[index]
+    }
+
+    pub fn len(&self) -> usize {
+        self.pmf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pmf.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_weights() {
+        let weights = [1.0, 0.0, 3.0, 6.0];
+        let table = AliasTable::new(&weights);
+
+        let samples = 100_000;
+        let mut counts = [0usize; 4];
+        for i in 0..samples {
+            let (index, pmf) = table.sample((i as f64 + 0.5) / samples as f64);
+            assert_eq!(pmf, table.pmf(index));
+            counts[index] += 1;
+        }
+
+        for (count, weight) in counts.iter().zip(weights.iter()) {
+            let expected = weight / 10.0;
+            assert!((*count as f64 / samples as f64 - expected).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn all_zero_weights_are_uniform() {
+        let table = AliasTable::new(&[0.0, 0.0]);
+        assert_eq!(table.pmf(0), 0.5);
+        assert_eq!(table.sample(0.75).0, 1);
+    }
+}
@@ -0,0 +1,202 @@
+use std::{hash::Hash, sync::Arc};
+
+use glam::DVec3;
+
+use crate::{hash_to_unit, HitRecord, Material, Ray, Texture, PDF};
+
+/// How an `AlphaMask` turns opacity into holes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AlphaMode {
+    /// Rays pass through wherever the opacity is below the cutoff
+    Threshold(f64),
+    /// Rays pass through with probability `1 - opacity`, for soft edges and partly
+    /// transparent surfaces without sorting
+    Stochastic,
+}
+
+/// Opacity texture cutting holes in surfaces, e.g. leaves or a chain-link fence on a quad
+#[derive(Clone, Debug)]
+pub struct AlphaMask {
+    pub opacity: Arc<dyn Texture>,
+    pub mode: AlphaMode,
+}
+
+impl AlphaMask {
+    /// Cuts out wherever `opacity` is below a half
+    pub fn new(opacity: Arc<dyn Texture>) -> AlphaMask {
+        AlphaMask {
+            opacity,
+            mode: AlphaMode::Threshold(0.5),
+        }
+    }
+
+    pub fn stochastic(mut self) -> AlphaMask {
+        self.mode = AlphaMode::Stochastic;
+        self
+    }
+
+    /// Mean of the texture's channels
+    pub fn opacity(&self, u: f64, v: f64, p: DVec3) -> f64 {
+        let sample = self.opacity.sample(u, v, p);
+        ((sample.x + sample.y + sample.z) / 3.0).clamp(0.0, 1.0)
+    }
+
+    /// Whether `ray` passes through the surface at `u`, `v`, `p`. `surface` identifies what was
+    /// hit so overlapping surfaces decide independently. The same ray always gets the same
+    /// answer, so closest hits and shadow queries agree.
+    pub fn passes(&self, ray: &Ray, surface: impl Hash, u: f64, v: f64, p: DVec3) -> bool {
+        let opacity = self.opacity(u, v, p);
+        match self.mode {
+            AlphaMode::Threshold(cutoff) => opacity < cutoff,
+            AlphaMode::Stochastic => hash_to_unit(surface, &[ray.origin, ray.dir]) >= opacity,
+        }
+    }
+}
+
+/// Wraps a material, cutting holes in meshes using it with an `AlphaMask`
+#[derive(Debug)]
+pub struct AlphaMasked {
+    pub material: Arc<dyn Material>,
+    pub mask: AlphaMask,
+}
+
+impl AlphaMasked {
+    pub fn new(material: Arc<dyn Material>, mask: AlphaMask) -> AlphaMasked {
+        AlphaMasked { material, mask }
+    }
+}
+
+impl Material for AlphaMasked {
+    fn emitted(&self, u: f64, v: f64, p: DVec3) -> DVec3 {
+        self.material.emitted(u, v, p)
+    }
+
+    fn scattering_pdf(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<Box<dyn PDF>> {
+        self.material.scattering_pdf(ray_in, hit_record)
+    }
+
+    fn brdf(&self, ray_in: &Ray, hit_record: &HitRecord, ray_out: &Ray) -> DVec3 {
+        self.material.brdf(ray_in, hit_record, ray_out)
+    }
+
+    fn is_specular(&self) -> bool {
+        self.material.is_specular()
+    }
+
+    fn shading_normal(&self, hit_record: &HitRecord) -> Option<DVec3> {
+        self.material.shading_normal(hit_record)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn emitted_spectral(&self, u: f64, v: f64, p: DVec3, wavelengths: DVec3) -> DVec3 {
+        self.material.emitted_spectral(u, v, p, wavelengths)
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.material.is_dispersive()
+    }
+
+    fn alpha_mask(&self) -> Option<&AlphaMask> {
+        Some(&self.mask)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::DVec2;
+
+    use crate::{create_mesh, Hittable, Lambertian, MeshBVH, Triangle};
+
+    use super::*;
+
+    /// Opaque on the right half of the texture, `left` opacity on the left
+    #[derive(Debug)]
+    struct Split {
+        left: f64,
+    }
+
+    impl Texture for Split {
+        fn sample(&self, u: f64, _: f64, _: DVec3) -> DVec3 {
+            if u > 0.5 {
+                DVec3::ONE
+            } else {
+                DVec3::splat(self.left)
+            }
+        }
+    }
+
+    /// Two unit quads facing +z, a masked one at z = -1 in front of an opaque one at z = -2
+    fn layers(mask: AlphaMask) -> MeshBVH {
+        let quad = |z: f64, material: Arc<dyn Material>| {
+            create_mesh(
+                vec![
+                    DVec3::new(0.0, 0.0, z),
+                    DVec3::new(1.0, 0.0, z),
+                    DVec3::new(1.0, 1.0, z),
+                    DVec3::new(0.0, 1.0, z),
+                ],
+                vec![DVec3::Z; 4],
+                vec![
+                    DVec2::new(0.0, 0.0),
+                    DVec2::new(1.0, 0.0),
+                    DVec2::new(1.0, 1.0),
+                    DVec2::new(0.0, 1.0),
+                ],
+                vec![[0, 1, 2], [0, 2, 3]],
+                material,
+            )
+        };
+        let lambertian = Arc::new(Lambertian::new(DVec3::splat(0.5)));
+        let mut triangles: Vec<Triangle> =
+            quad(-1.0, Arc::new(AlphaMasked::new(lambertian.clone(), mask)));
+        triangles.extend(quad(-2.0, lambertian));
+        MeshBVH::new(triangles, Default::default())
+    }
+
+    fn ray(x: f64, y: f64) -> Ray {
+        Ray {
+            origin: DVec3::new(x, y, 0.0),
+            dir: DVec3::new(0.0, 0.0, -1.0),
+            time: 0.0,
+            wavelengths: None,
+        }
+    }
+
+    #[test]
+    fn threshold_mask_cuts_holes() {
+        let bvh = layers(AlphaMask::new(Arc::new(Split { left: 0.2 })));
+
+        let opaque = bvh.hit(&ray(0.75, 0.5), 0.001, 10.0).unwrap();
+        assert!((opaque.t - 1.0).abs() < 1e-12);
+        assert!(bvh.occluded(&ray(0.75, 0.5), 0.001, 1.5));
+
+        // Rays through the hole find the quad behind, and shadow rays stopping short of it
+        // aren't blocked
+        let through = bvh.hit(&ray(0.25, 0.5), 0.001, 10.0).unwrap();
+        assert!((through.t - 2.0).abs() < 1e-12);
+        assert!(!bvh.occluded(&ray(0.25, 0.5), 0.001, 1.5));
+    }
+
+    #[test]
+    fn stochastic_mask_passes_in_proportion() {
+        let bvh = layers(AlphaMask::new(Arc::new(Split { left: 0.25 })).stochastic());
+
+        let samples = 10_000;
+        let mut passed = 0;
+        for i in 0..samples {
+            let ray = ray(0.5 * (i as f64 + 0.5) / samples as f64, 0.9);
+            let hr = bvh.hit(&ray, 0.001, 10.0).unwrap();
+            let through = hr.t > 1.5;
+            assert_eq!(through, !bvh.occluded(&ray, 0.001, 1.5));
+            if through {
+                passed += 1;
+            }
+        }
+
+        let fraction = passed as f64 / samples as f64;
+        assert!((fraction - 0.75).abs() < 0.02, "fraction was {}", fraction);
+    }
+}
@@ -0,0 +1,99 @@
+use glam::{DQuat, DVec3};
+
+use crate::{environment::equirectangular_lookup, Image, Ray};
+
+/// What rays see when they escape the scene
+pub trait Background: Send + Sync {
+    fn radiance(&self, ray: &Ray) -> DVec3;
+}
+
+// Lets plain functions and closures be used as backgrounds
+impl<F: Fn(Ray) -> DVec3 + Send + Sync> Background for F {
+    fn radiance(&self, ray: &Ray) -> DVec3 {
+        self(*ray)
+    }
+}
+
+pub struct ConstantBackground {
+    pub colour: DVec3,
+}
+
+impl Background for ConstantBackground {
+    fn radiance(&self, _: &Ray) -> DVec3 {
+        self.colour
+    }
+}
+
+/// Blends from `bottom` looking straight down to `top` looking straight up
+pub struct GradientBackground {
+    pub bottom: DVec3,
+    pub top: DVec3,
+}
+
+impl Background for GradientBackground {
+    fn radiance(&self, ray: &Ray) -> DVec3 {
+        let t = 0.5 * (ray.dir.normalize().y + 1.0);
+        self.bottom.lerp(self.top, t)
+    }
+}
+
+/// Equirectangular image around the scene, seen but not sampled as a light unlike
+/// `EnvironmentLight`
+pub struct ImageBackground {
+    image: Image,
+    /// Local to world
+    rotation: DQuat,
+    intensity: f64,
+}
+
+impl ImageBackground {
+    pub fn new(image: Image, rotation: DQuat, intensity: f64) -> ImageBackground {
+        ImageBackground {
+            image,
+            rotation,
+            intensity,
+        }
+    }
+}
+
+impl Background for ImageBackground {
+    fn radiance(&self, ray: &Ray) -> DVec3 {
+        let local = self.rotation.inverse() * ray.dir.normalize();
+        equirectangular_lookup(&self.image, local) * self.intensity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ray(dir: DVec3) -> Ray {
+        Ray {
+            origin: DVec3::ZERO,
+            dir,
+            time: 0.0,
+            wavelengths: None,
+        }
+    }
+
+    #[test]
+    fn gradient_blends_vertically() {
+        let background = GradientBackground {
+            bottom: DVec3::ZERO,
+            top: DVec3::new(0.5, 0.7, 1.0),
+        };
+        assert_eq!(background.radiance(&ray(DVec3::Y * 3.0)), background.top);
+        assert_eq!(background.radiance(&ray(-DVec3::Y)), background.bottom);
+        assert_eq!(
+            background.radiance(&ray(DVec3::X)),
+            DVec3::new(0.25, 0.35, 0.5)
+        );
+    }
+
+    #[test]
+    fn closures_capture_state() {
+        let colour = DVec3::new(0.1, 0.2, 0.3);
+        let background: Box<dyn Background> = Box::new(move |_: Ray| colour);
+        assert_eq!(background.radiance(&ray(DVec3::Z)), colour);
+    }
+}
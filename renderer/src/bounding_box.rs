@@ -1,5 +1,5 @@
-use super::Ray;
-use glam::DVec3;
+use super::{packet_lanes, PacketMask, Ray, RayPacket, PACKET_SIZE};
+use glam::{DMat4, DVec3};
 
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct AABB {
@@ -24,6 +24,41 @@ impl AABB {
         }
     }
 
+    /// Box enclosing all eight transformed corners, so rotations and negative scales stay bounded
+    pub fn transform(&self, t: &DMat4) -> AABB {
+        (0..8)
+            .map(|corner| {
+                DVec3::new(
+                    if corner & 1 == 0 {
+                        self.min.x
+                    } else {
+                        self.max.x
+                    },
+                    if corner & 2 == 0 {
+                        self.min.y
+                    } else {
+                        self.max.y
+                    },
+                    if corner & 4 == 0 {
+                        self.min.z
+                    } else {
+                        self.max.z
+                    },
+                )
+            })
+            .map(|corner| t.transform_point3(corner))
+            .fold(
+                AABB {
+                    min: DVec3::splat(f64::MAX),
+                    max: DVec3::splat(f64::MIN),
+                },
+                |bbox, corner| AABB {
+                    min: bbox.min.min(corner),
+                    max: bbox.max.max(corner),
+                },
+            )
+    }
+
     pub fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
         for a in 0..3 {
             let inv_d = 1.0 / ray.dir[a];
@@ -44,6 +79,32 @@ impl AABB {
 
         true
     }
+
+    /// Returns the subset of `mask` whose rays overlap the box within their own `t_max`
+    pub fn hit_packet(
+        &self,
+        packet: &RayPacket,
+        mask: PacketMask,
+        t_min: f64,
+        t_max: &[f64; PACKET_SIZE],
+    ) -> PacketMask {
+        packet_lanes(mask).fold(0, |hit_mask, lane| {
+            let ray = &packet.rays[lane];
+            let inv_d = packet.inv_dir[lane];
+
+            let t0 = (self.min - ray.origin) * inv_d;
+            let t1 = (self.max - ray.origin) * inv_d;
+
+            let near = t0.min(t1).max_element().max(t_min);
+            let far = t0.max(t1).min_element().min(t_max[lane]);
+
+            if near <= far {
+                hit_mask | (1 << lane)
+            } else {
+                hit_mask
+            }
+        })
+    }
 }
 
 #[cfg(test)]
@@ -61,8 +122,41 @@ mod tests {
             origin: DVec3::new(-2.0, 0.0, 0.0),
             dir: DVec3::new(2.0, 0.0, -1.0),
             time: 1.0,
+            wavelengths: None,
         };
 
         assert!(aabb.hit(&ray, 0.0001, 10000.));
     }
+
+    #[test]
+    fn transform_rotated() {
+        let aabb = AABB {
+            min: DVec3::splat(-1.0),
+            max: DVec3::splat(1.0),
+        };
+
+        let rotated = aabb.transform(&DMat4::from_rotation_z(std::f64::consts::FRAC_PI_4));
+        let sqrt_2 = std::f64::consts::SQRT_2;
+
+        assert!((rotated.min - DVec3::new(-sqrt_2, -sqrt_2, -1.0)).length() < 1e-9);
+        assert!((rotated.max - DVec3::new(sqrt_2, sqrt_2, 1.0)).length() < 1e-9);
+    }
+
+    #[test]
+    fn transform_negative_scale() {
+        let aabb = AABB {
+            min: DVec3::new(1.0, 2.0, 3.0),
+            max: DVec3::new(2.0, 3.0, 4.0),
+        };
+
+        let flipped = aabb.transform(&DMat4::from_scale(DVec3::new(-1.0, 1.0, 1.0)));
+
+        assert_eq!(
+            flipped,
+            AABB {
+                min: DVec3::new(-2.0, 2.0, 3.0),
+                max: DVec3::new(-1.0, 3.0, 4.0),
+            }
+        );
+    }
 }
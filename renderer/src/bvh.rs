@@ -2,7 +2,10 @@ use std::{cmp::Ordering, sync::Arc};
 
 use crate::hittable::NullHittable;
 
-use super::{HitRecord, Hittable, Triangle, AABB};
+use super::{
+    packet_lanes, HitRecord, Hittable, PacketHits, PacketMask, RayPacket, SampleableLight,
+    Triangle, AABB, PACKET_SIZE,
+};
 use glam::DVec3;
 use rand::{self, Rng};
 
@@ -11,6 +14,8 @@ pub struct BVHNode {
     left: Arc<dyn Hittable>,
     right: Arc<dyn Hittable>,
     bbox: AABB,
+    /// Axis the children were split along, the left one starting lower on it
+    axis: usize,
 }
 
 impl Default for BVHNode {
@@ -19,6 +24,7 @@ impl Default for BVHNode {
             left: Arc::new(NullHittable {}),
             right: Arc::new(NullHittable {}),
             bbox: Default::default(),
+            axis: 0,
         }
     }
 }
@@ -39,6 +45,7 @@ impl BVHNode {
                 left: hittables[0].clone(),
                 right: hittables[0].clone(),
                 bbox: hittables.bounding_box(time_0, time_1).unwrap(),
+                axis: 0,
             };
         }
 
@@ -47,6 +54,7 @@ impl BVHNode {
                 left: hittables[0].clone(),
                 right: hittables[1].clone(),
                 bbox: hittables.bounding_box(time_0, time_1).unwrap(),
+                axis: 0,
             };
         }
 
@@ -83,6 +91,7 @@ impl BVHNode {
             left,
             right,
             bbox: AABB::surrounding_box(&left_bbox.unwrap(), &right_bbox.unwrap()),
+            axis: sort_axis,
         }
     }
 }
@@ -105,6 +114,60 @@ impl Hittable for BVHNode {
         right_hit.or(left_hit)
     }
 
+    fn occluded(&self, ray: &crate::Ray, t_min: f64, t_max: f64) -> bool {
+        self.bbox.hit(ray, t_min, t_max)
+            && (self.left.occluded(ray, t_min, t_max) || self.right.occluded(ray, t_min, t_max))
+    }
+
+    // The whole packet walks the tree together, lanes that miss a node's box are masked off
+    fn hit_packet<'a>(
+        &'a self,
+        packet: &RayPacket,
+        mask: PacketMask,
+        t_min: f64,
+        hits: &mut PacketHits<'a>,
+    ) {
+        let mask = self.bbox.hit_packet(packet, mask, t_min, &hits.t_max);
+        if mask == 0 {
+            return;
+        }
+
+        // Visit the nearer child first along the packet's dominant direction, so its hits shrink
+        // `t_max` before the further child is tested
+        let (first, second) = if packet_lanes(mask)
+            .map(|lane| packet.rays[lane].dir[self.axis])
+            .sum::<f64>()
+            < 0.0
+        {
+            (&self.right, &self.left)
+        } else {
+            (&self.left, &self.right)
+        };
+        first.hit_packet(packet, mask, t_min, hits);
+        second.hit_packet(packet, mask, t_min, hits);
+    }
+
+    fn occluded_packet(
+        &self,
+        packet: &RayPacket,
+        mask: PacketMask,
+        t_min: f64,
+        t_max: &[f64; PACKET_SIZE],
+    ) -> PacketMask {
+        let mask = self.bbox.hit_packet(packet, mask, t_min, t_max);
+        if mask == 0 {
+            return 0;
+        }
+
+        let occluded = self.left.occluded_packet(packet, mask, t_min, t_max);
+        let remaining = mask & !occluded;
+        if remaining == 0 {
+            return occluded;
+        }
+
+        occluded | self.right.occluded_packet(packet, remaining, t_min, t_max)
+    }
+
     fn bounding_box(&self, _: f64, _: f64) -> Option<AABB> {
         Some(self.bbox.clone())
     }
@@ -116,13 +179,22 @@ impl Hittable for BVHNode {
     fn pdf_uniform(&self, point: glam::DVec3) -> f64 {
         todo!()
     }
+
+    fn emissive_lights(self: Arc<Self>) -> Vec<Arc<dyn SampleableLight>> {
+        let mut lights = self.left.clone().emissive_lights();
+        // Single object nodes point both children at it
+        if !Arc::ptr_eq(&self.left, &self.right) {
+            lights.extend(self.right.clone().emissive_lights());
+        }
+        lights
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use glam::{DVec2, DVec3};
 
-    use crate::{create_mesh, Lambertian, Ray, SolidColour};
+    use crate::{create_mesh, Lambertian, Ray, SolidColour, Sphere};
 
     use super::*;
 
@@ -164,6 +236,7 @@ mod tests {
             origin: DVec3::new(-0.5, 0.0, 1.0),
             dir: DVec3::new(0., 0., -1.),
             time: 0.0,
+            wavelengths: None,
         };
 
         let hit = bvh.hit(&ray, 0.00001, 10000.);
@@ -171,4 +244,38 @@ mod tests {
         assert!(hit.is_some());
         assert_eq!(hit.unwrap().t, 1.0);
     }
+
+    #[test]
+    fn occluded() {
+        let material = Arc::new(Lambertian::new(DVec3::splat(0.5)));
+        let world: Vec<Arc<dyn Hittable>> = (0..5)
+            .map(|i| {
+                Arc::new(Sphere {
+                    center: DVec3::new(0.0, 0.0, -2.0 * (i + 1) as f64),
+                    radius: 0.5,
+                    material: material.clone(),
+                }) as Arc<dyn Hittable>
+            })
+            .collect();
+        let bvh = BVHNode::new(world.as_slice(), 0.0, 0.0);
+
+        let ray = Ray {
+            origin: DVec3::ZERO,
+            dir: DVec3::new(0., 0., -1.),
+            time: 0.0,
+            wavelengths: None,
+        };
+
+        assert!(bvh.occluded(&ray, 0.001, 10000.));
+        // Closest sphere starts at t = 1.5
+        assert!(!bvh.occluded(&ray, 0.001, 1.4));
+
+        let miss = Ray {
+            origin: DVec3::ZERO,
+            dir: DVec3::new(0., 1., 0.),
+            time: 0.0,
+            wavelengths: None,
+        };
+        assert!(!bvh.occluded(&miss, 0.001, 10000.));
+    }
 }
@@ -80,6 +80,11 @@ impl Camera {
         )
     }
 
+    /// Time interval the camera samples rays over
+    pub fn shutter(&self) -> (f64, f64) {
+        (self.time_0, self.time_1)
+    }
+
     pub fn get_ray(&self, s: f64, t: f64) -> Ray {
         let mut rng = rand::thread_rng();
         let random_in_lens = rand_in_unit_sphere(&mut rng) * self.lens_radius;
@@ -88,7 +93,7 @@ impl Camera {
         let time = if (self.time_1 - self.time_0) > 0.000001 {
             rng.gen_range(self.time_0..self.time_1)
         } else {
-            0.0
+            self.time_0
         };
 
         Ray {
@@ -97,6 +102,7 @@ impl Camera {
                 - self.origin
                 - offset,
             time,
+            wavelengths: None,
         }
     }
 }
@@ -0,0 +1,163 @@
+use glam::DVec2;
+
+/// Piecewise-constant density over [0, 1) with one piece per value
+#[derive(Clone, Debug)]
+pub struct Distribution1D {
+    function: Vec<f64>,
+    cdf: Vec<f64>,
+    integral: f64,
+}
+
+impl Distribution1D {
+    /// Values must be non-negative, all zero values are sampled uniformly
+    pub fn new(function: &[f64]) -> Distribution1D {
+        assert!(
+            !function.is_empty(),
+            "Distribution needs at least one value"
+        );
+
+        let count = function.len();
+        let mut cdf = vec![0.0; count + 1];
+        for i in 0..count {
+            cdf[i + 1] = cdf[i] + function[i] / count as f64;
+        }
+
+        let integral = cdf[count];
+        if integral == 0.0 {
+            for (i, value) in cdf.iter_mut().enumerate() {
+                *value = i as f64 / count as f64;
+            }
+        } else {
+            for value in cdf.iter_mut() {
+                *value /= integral;
+            }
+        }
+
+        Distribution1D {
+            function: function.to_vec(),
+            cdf,
+            integral,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.function.len()
+    }
+
+    /// Average of the function over [0, 1)
+    pub fn integral(&self) -> f64 {
+        self.integral
+    }
+
+    /// Returns a point in [0, 1), its density and the piece it's in
+    pub fn sample_continuous(&self, u: f64) -> (f64, f64, usize) {
+        // Last cdf entry that's <= u
+        let offset = (self.cdf.partition_point(|&c| c <= u).max(1) - 1).min(self.count() - 1);
+
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        let du = if width > 0.0 {
+            (u - self.cdf[offset]) / width
+        } else {
+            0.0
+        };
+
+        let x = ((offset as f64 + du) / self.count() as f64).min(1.0 - f64::EPSILON / 2.0);
+        (x, self.pdf(x), offset)
+    }
+
+    /// Density at `x` in [0, 1)
+    pub fn pdf(&self, x: f64) -> f64 {
+        if self.integral == 0.0 {
+            return 1.0;
+        }
+        let offset = ((x * self.count() as f64) as usize).min(self.count() - 1);
+        self.function[offset] / self.integral
+    }
+}
+
+/// Piecewise-constant density over [0, 1)², sampling a row from the marginal then a column
+#[derive(Clone, Debug)]
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    /// `function` is `width` values per row, row by row
+    pub fn new(function: &[f64], width: usize) -> Distribution2D {
+        let conditional = function
+            .chunks(width)
+            .map(Distribution1D::new)
+            .collect::<Vec<_>>();
+        let marginal = Distribution1D::new(
+            &conditional
+                .iter()
+                .map(Distribution1D::integral)
+                .collect::<Vec<_>>(),
+        );
+
+        Distribution2D {
+            conditional,
+            marginal,
+        }
+    }
+
+    /// Returns a point in [0, 1)², with x along rows, and its density
+    pub fn sample_continuous(&self, u: DVec2) -> (DVec2, f64) {
+        let (y, pdf_y, row) = self.marginal.sample_continuous(u.y);
+        let (x, pdf_x, _) = self.conditional[row].sample_continuous(u.x);
+        (DVec2::new(x, y), pdf_x * pdf_y)
+    }
+
+    pub fn pdf(&self, point: DVec2) -> f64 {
+        let rows = self.conditional.len();
+        let row = ((point.y * rows as f64).max(0.0) as usize).min(rows - 1);
+        self.marginal.pdf(point.y) * self.conditional[row].pdf(point.x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_dimensional() {
+        let distribution = Distribution1D::new(&[1.0, 0.0, 3.0]);
+        assert!((distribution.integral() - 4.0 / 3.0).abs() < 1e-12);
+
+        let (x, pdf, offset) = distribution.sample_continuous(0.125);
+        assert_eq!(offset, 0);
+        assert!((x - 0.5 / 3.0).abs() < 1e-12);
+        assert!((pdf - 0.75).abs() < 1e-12);
+
+        // The empty piece is skipped
+        let (x, pdf, offset) = distribution.sample_continuous(0.25);
+        assert_eq!(offset, 2);
+        assert!((x - 2.0 / 3.0).abs() < 1e-12);
+        assert!((pdf - 2.25).abs() < 1e-12);
+        assert_eq!(distribution.pdf(0.5), 0.0);
+    }
+
+    #[test]
+    fn two_dimensional_pdf_matches_samples() {
+        let function = [1.0, 2.0, 0.0, 4.0, 8.0, 1.0];
+        let distribution = Distribution2D::new(&function, 3);
+
+        let n = 200;
+        let mut counts = [0usize; 6];
+        for i in 0..n {
+            for j in 0..n {
+                let u = DVec2::new((i as f64 + 0.5) / n as f64, (j as f64 + 0.5) / n as f64);
+                let (point, pdf) = distribution.sample_continuous(u);
+                assert!((pdf - distribution.pdf(point)).abs() < 1e-9);
+                counts[(point.y * 2.0) as usize * 3 + (point.x * 3.0) as usize] += 1;
+            }
+        }
+
+        let total: f64 = function.iter().sum();
+        for (count, value) in counts.iter().zip(function.iter()) {
+            let expected = value / total;
+            assert!((*count as f64 / (n * n) as f64 - expected).abs() < 1e-2);
+        }
+    }
+}
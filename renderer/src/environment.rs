@@ -0,0 +1,270 @@
+use std::{f64::consts::PI, sync::Arc};
+
+use glam::{DQuat, DVec2, DVec3};
+use rand::Rng;
+
+use crate::{
+    luminance, rgb_at, Distribution2D, HitRecord, Hittable, Image, LightBounds, LightSample, Ray,
+    SampleableLight, AABB, PDF,
+};
+
+struct EnvironmentData {
+    /// Linear radiance in equirectangular layout, +y up at the top row
+    image: Image,
+    /// Local to world
+    rotation: DQuat,
+    intensity: f64,
+    distribution: Distribution2D,
+    /// Irradiance of the environment on average, see `SampleableLight::power`
+    irradiance: f64,
+}
+
+/// Radiance arriving from infinitely far away in every direction, from an HDR image
+#[derive(Clone)]
+pub struct EnvironmentLight {
+    data: Arc<EnvironmentData>,
+}
+
+/// Equirectangular coordinates of a unit direction, u around +y and v down from it
+fn direction_to_uv(direction: DVec3) -> DVec2 {
+    let theta = direction.y.clamp(-1.0, 1.0).acos();
+    let phi = direction.z.atan2(direction.x);
+    DVec2::new((phi + PI) / (2.0 * PI), theta / PI)
+}
+
+pub(crate) fn uv_to_direction(uv: DVec2) -> DVec3 {
+    let theta = uv.y * PI;
+    let phi = uv.x * 2.0 * PI - PI;
+    let (sin_theta, cos_theta) = theta.sin_cos();
+    DVec3::new(sin_theta * phi.cos(), cos_theta, sin_theta * phi.sin())
+}
+
+/// Pixel of an equirectangular image seen looking in the unit `direction`
+pub(crate) fn equirectangular_lookup(image: &Image, direction: DVec3) -> DVec3 {
+    let uv = direction_to_uv(direction);
+
+    let (width, height) = image.size;
+    let x = ((uv.x * width as f64) as u32).min(width - 1);
+    let y = ((uv.y * height as f64) as u32).min(height - 1);
+    *image.get(x, y).unwrap()
+}
+
+impl EnvironmentLight {
+    /// `rotation` turns the image's frame into the world's, `intensity` scales its radiance
+    pub fn new(image: Image, rotation: DQuat, intensity: f64) -> EnvironmentLight {
+        let (width, height) = (image.size.0 as usize, image.size.1 as usize);
+
+        // Rows near the poles cover less of the sphere
+        let weights = (0..height)
+            .flat_map(|y| {
+                let sin_theta = ((y as f64 + 0.5) / height as f64 * PI).sin();
+                let image = &image;
+                (0..width).map(move |x| luminance(image.data[y * width + x]).max(0.0) * sin_theta)
+            })
+            .collect::<Vec<_>>();
+        // Average radiance is pi/2 times the average weight, and irradiance pi times that
+        let irradiance =
+            weights.iter().sum::<f64>() / (width * height) as f64 * PI * PI / 2.0 * intensity;
+
+        EnvironmentLight {
+            data: Arc::new(EnvironmentData {
+                distribution: Distribution2D::new(&weights, width),
+                image,
+                rotation,
+                intensity,
+                irradiance,
+            }),
+        }
+    }
+
+    /// Radiance arriving along `-direction`, i.e. seen looking in `direction`
+    pub fn radiance(&self, direction: DVec3) -> DVec3 {
+        let local = self.data.rotation.inverse() * direction.normalize();
+        equirectangular_lookup(&self.data.image, local) * self.data.intensity
+    }
+
+    fn pdf(&self, direction: DVec3) -> f64 {
+        let local = self.data.rotation.inverse() * direction.normalize();
+        let uv = direction_to_uv(local);
+        let sin_theta = (uv.y * PI).sin();
+        if sin_theta == 0.0 {
+            return 0.0;
+        }
+        self.data.distribution.pdf(uv) / (2.0 * PI * PI * sin_theta)
+    }
+
+    fn sample(&self, rng: &mut dyn rand::RngCore) -> Option<(DVec3, f64)> {
+        let (uv, pdf_uv) = self
+            .data
+            .distribution
+            .sample_continuous(DVec2::new(rng.gen(), rng.gen()));
+        let sin_theta = (uv.y * PI).sin();
+        if pdf_uv == 0.0 || sin_theta == 0.0 {
+            return None;
+        }
+
+        let direction = self.data.rotation * uv_to_direction(uv);
+        Some((direction, pdf_uv / (2.0 * PI * PI * sin_theta)))
+    }
+}
+
+// At infinity, rays that miss everything else see it instead
+impl Hittable for EnvironmentLight {
+    fn hit(&self, _: &Ray, _: f64, _: f64) -> Option<HitRecord<'_>> {
+        None
+    }
+
+    fn occluded(&self, _: &Ray, _: f64, _: f64) -> bool {
+        false
+    }
+
+    fn bounding_box(&self, _: f64, _: f64) -> Option<AABB> {
+        None
+    }
+
+    fn sample_uniform(&self, _: &mut dyn rand::RngCore) -> DVec3 {
+        DVec3::ZERO
+    }
+
+    fn pdf_uniform(&self, _: DVec3) -> f64 {
+        0.0
+    }
+}
+
+/// Directions sampled by the environment's brightness
+pub struct EnvironmentPDF {
+    light: EnvironmentLight,
+}
+
+impl PDF for EnvironmentPDF {
+    fn value(&self, direction: DVec3) -> f64 {
+        self.light.pdf(direction)
+    }
+
+    fn generate(&self, rng: &mut dyn rand::RngCore) -> DVec3 {
+        self.light
+            .sample(rng)
+            .map_or(DVec3::Y, |(direction, _)| direction)
+    }
+}
+
+impl SampleableLight for EnvironmentLight {
+    fn pdf_for_point(&self, _: DVec3) -> Box<dyn PDF> {
+        Box::new(EnvironmentPDF {
+            light: self.clone(),
+        })
+    }
+
+    fn power(&self) -> f64 {
+        self.data.irradiance
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        None
+    }
+
+    fn sample_li(
+        &self,
+        _: DVec3,
+        _: f64,
+        wavelengths: Option<DVec3>,
+        rng: &mut dyn rand::RngCore,
+    ) -> Option<LightSample> {
+        let (direction, pdf) = self.sample(rng)?;
+        Some(LightSample {
+            direction,
+            distance: f64::INFINITY,
+            radiance: rgb_at(self.radiance(direction), wavelengths),
+            pdf,
+        })
+    }
+
+    fn pdf_li(&self, _: DVec3, direction: DVec3) -> f64 {
+        self.pdf(direction)
+    }
+
+    fn escaped_radiance(&self, direction: DVec3) -> Option<DVec3> {
+        Some(self.radiance(direction))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    /// Dim everywhere except one bright pixel
+    fn hotspot() -> Image {
+        let mut image = Image::new((16, 8));
+        for y in 0..8 {
+            for x in 0..16 {
+                image.put(x, y, &DVec3::splat(0.1));
+            }
+        }
+        image.put(4, 2, &DVec3::splat(1000.0));
+        image
+    }
+
+    #[test]
+    fn uv_round_trip() {
+        for &direction in &[
+            DVec3::new(1.0, 0.0, 0.0),
+            DVec3::new(0.3, 0.5, -0.8).normalize(),
+            DVec3::new(-0.6, -0.7, 0.2).normalize(),
+        ] {
+            assert!(uv_to_direction(direction_to_uv(direction)).distance(direction) < 1e-12);
+        }
+        assert!(direction_to_uv(DVec3::Y).y.abs() < 1e-12);
+    }
+
+    #[test]
+    fn samples_follow_radiance() {
+        let light = EnvironmentLight::new(hotspot(), DQuat::IDENTITY, 1.0);
+        let mut rng = StdRng::seed_from_u64(2);
+
+        // Estimate the radiance integrated over the sphere with importance sampling
+        let samples = 20_000;
+        let mut near_hotspot = 0;
+        let mut estimate = 0.0;
+        for _ in 0..samples {
+            let sample = light.sample_li(DVec3::ZERO, 0.0, None, &mut rng).unwrap();
+            assert!((sample.pdf - light.pdf_li(DVec3::ZERO, sample.direction)).abs() < 1e-6);
+            estimate += sample.radiance.x / sample.pdf;
+
+            let uv = direction_to_uv(sample.direction);
+            if (uv.x * 16.0) as u32 == 4 && (uv.y * 8.0) as u32 == 2 {
+                near_hotspot += 1;
+            }
+        }
+        estimate /= samples as f64;
+        assert!(near_hotspot as f64 / samples as f64 > 0.9);
+
+        // Pixels cover solid angles in proportion to sin(theta)
+        let pixel_solid_angle = |y: u32| {
+            (2.0 * PI / 16.0)
+                * (((y as f64) * PI / 8.0).cos() - ((y as f64 + 1.0) * PI / 8.0).cos())
+        };
+        let exact = 0.1 * 4.0 * PI + (1000.0 - 0.1) * pixel_solid_angle(2);
+        assert!(
+            (estimate / exact - 1.0).abs() < 0.02,
+            "{} vs {}",
+            estimate,
+            exact
+        );
+    }
+
+    #[test]
+    fn rotation_and_intensity() {
+        let rotation = DQuat::from_rotation_y(1.0);
+        let rotated = EnvironmentLight::new(hotspot(), rotation, 2.0);
+        let original = EnvironmentLight::new(hotspot(), DQuat::IDENTITY, 1.0);
+
+        let direction = DVec3::new(0.2, 0.6, 0.4).normalize();
+        assert_eq!(
+            rotated.radiance(rotation * direction),
+            original.radiance(direction) * 2.0
+        );
+        assert!((rotated.pdf(rotation * direction) - original.pdf(direction)).abs() < 1e-9);
+    }
+}
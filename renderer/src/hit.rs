@@ -1,13 +1,19 @@
 use std::sync::Arc;
 
-use super::{Material, Ray};
+use super::{Material, OrthoNormalBasis, Ray};
 
 use glam::DVec3;
 
 #[derive(Debug)]
 pub struct HitRecord<'material> {
     pub point: DVec3,
+    /// Shading normal, always on the same side of the surface as `geometric_normal`
     pub normal: DVec3,
+    /// True normal of the surface, facing against the ray
+    pub geometric_normal: DVec3,
+    /// Partial derivatives of the surface position with respect to `u` and `v`
+    pub dpdu: DVec3,
+    pub dpdv: DVec3,
     pub material: &'material dyn Material,
     pub t: f64,
     pub u: f64,
@@ -25,9 +31,16 @@ impl<'material> HitRecord<'material> {
         u: f64,
         v: f64,
     ) -> HitRecord<'material> {
+        // Any frame around the normal for surfaces that don't provide derivatives,
+        // ordered so that dpdu x dpdv points along the normal
+        let basis = OrthoNormalBasis::from_w(&normal);
+
         let mut hr = HitRecord {
             point: *point,
             normal,
+            geometric_normal: normal,
+            dpdu: basis.v,
+            dpdv: basis.u,
             material,
             t,
             u,
@@ -37,12 +50,31 @@ impl<'material> HitRecord<'material> {
         hr.set_face_normal(ray, normal);
         hr
     }
+
+    /// Replaces the shading normal, e.g. with one interpolated from vertex normals.
+    /// `shading_normal` is flipped onto the side of the surface the ray hit.
+    pub fn with_shading_normal(mut self, shading_normal: DVec3) -> HitRecord<'material> {
+        self.normal = if shading_normal.dot(self.geometric_normal) < 0.0 {
+            -shading_normal
+        } else {
+            shading_normal
+        };
+        self
+    }
+
+    pub fn with_uv_derivatives(mut self, dpdu: DVec3, dpdv: DVec3) -> HitRecord<'material> {
+        self.dpdu = dpdu;
+        self.dpdv = dpdv;
+        self
+    }
+
     fn set_face_normal(&mut self, ray: &Ray, outward_normal: DVec3) {
         self.front_face = DVec3::dot(ray.dir, outward_normal) < 0.0;
-        self.normal = if self.front_face {
+        self.geometric_normal = if self.front_face {
             outward_normal
         } else {
             -outward_normal
-        }
+        };
+        self.normal = self.geometric_normal;
     }
 }
@@ -2,10 +2,20 @@ use std::sync::Arc;
 
 use glam::DVec3;
 
-use super::{HitRecord, Ray, AABB};
+use super::{
+    packet_lanes, HitRecord, PacketHits, PacketMask, Ray, RayPacket, SampleableLight, AABB,
+    PACKET_SIZE,
+};
 
 pub trait Hittable: Sync + Send {
     fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord>;
+
+    /// Any-hit query for shadow rays, implementors should stop at the first intersection
+    /// and avoid building a `HitRecord`
+    fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+        self.hit(ray, t_min, t_max).is_some()
+    }
+
     fn bounding_box(&self, time_0: f64, time_1: f64) -> Option<AABB>;
 
     fn sample_uniform(&self, rng: &mut dyn rand::RngCore) -> DVec3;
@@ -17,6 +27,41 @@ pub trait Hittable: Sync + Send {
     fn pdf_from_ref(&self, reference_point: DVec3, pt: DVec3) -> f64 {
         todo!()
     }
+
+    /// Lights for the emissive surfaces of this object, registered by `SceneBuilder::build`
+    fn emissive_lights(self: Arc<Self>) -> Vec<Arc<dyn SampleableLight>> {
+        Vec::new()
+    }
+
+    /// Closest hit for every active lane, only replacing hits closer than `hits.t_max`.
+    /// The default traces each lane on its own.
+    fn hit_packet<'a>(
+        &'a self,
+        packet: &RayPacket,
+        mask: PacketMask,
+        t_min: f64,
+        hits: &mut PacketHits<'a>,
+    ) {
+        for lane in packet_lanes(mask) {
+            if let Some(hr) = self.hit(&packet.rays[lane], t_min, hits.t_max[lane]) {
+                hits.t_max[lane] = hr.t;
+                hits.hits[lane] = Some(hr);
+            }
+        }
+    }
+
+    /// Mask of the active lanes that hit anything between `t_min` and their `t_max`
+    fn occluded_packet(
+        &self,
+        packet: &RayPacket,
+        mask: PacketMask,
+        t_min: f64,
+        t_max: &[f64; PACKET_SIZE],
+    ) -> PacketMask {
+        packet_lanes(mask)
+            .filter(|&lane| self.occluded(&packet.rays[lane], t_min, t_max[lane]))
+            .fold(0, |occluded, lane| occluded | (1 << lane))
+    }
 }
 
 pub struct NullHittable {}
@@ -52,6 +97,16 @@ impl Hittable for Vec<Arc<dyn Hittable>> {
         })
     }
 
+    fn emissive_lights(self: Arc<Self>) -> Vec<Arc<dyn SampleableLight>> {
+        self.iter()
+            .flat_map(|object| object.clone().emissive_lights())
+            .collect()
+    }
+
+    fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+        self.iter().any(|object| object.occluded(ray, t_min, t_max))
+    }
+
     fn bounding_box(&self, time_0: f64, time_1: f64) -> Option<AABB> {
         if self.is_empty() {
             return None;
@@ -75,6 +130,35 @@ impl Hittable for Vec<Arc<dyn Hittable>> {
     fn pdf_uniform(&self, point: DVec3) -> f64 {
         todo!()
     }
+
+    fn hit_packet<'a>(
+        &'a self,
+        packet: &RayPacket,
+        mask: PacketMask,
+        t_min: f64,
+        hits: &mut PacketHits<'a>,
+    ) {
+        for object in self.iter() {
+            object.hit_packet(packet, mask, t_min, hits);
+        }
+    }
+
+    fn occluded_packet(
+        &self,
+        packet: &RayPacket,
+        mask: PacketMask,
+        t_min: f64,
+        t_max: &[f64; PACKET_SIZE],
+    ) -> PacketMask {
+        let mut occluded = 0;
+        for object in self.iter() {
+            occluded |= object.occluded_packet(packet, mask & !occluded, t_min, t_max);
+            if occluded == mask {
+                break;
+            }
+        }
+        occluded
+    }
 }
 
 impl Hittable for &[Arc<dyn Hittable>] {
@@ -90,6 +174,10 @@ impl Hittable for &[Arc<dyn Hittable>] {
             }
         })
     }
+    fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+        self.iter().any(|object| object.occluded(ray, t_min, t_max))
+    }
+
     fn bounding_box(&self, time_0: f64, time_1: f64) -> Option<AABB> {
         if self.is_empty() {
             return None;
@@ -113,6 +201,35 @@ impl Hittable for &[Arc<dyn Hittable>] {
     fn pdf_uniform(&self, point: DVec3) -> f64 {
         todo!()
     }
+
+    fn hit_packet<'a>(
+        &'a self,
+        packet: &RayPacket,
+        mask: PacketMask,
+        t_min: f64,
+        hits: &mut PacketHits<'a>,
+    ) {
+        for object in self.iter() {
+            object.hit_packet(packet, mask, t_min, hits);
+        }
+    }
+
+    fn occluded_packet(
+        &self,
+        packet: &RayPacket,
+        mask: PacketMask,
+        t_min: f64,
+        t_max: &[f64; PACKET_SIZE],
+    ) -> PacketMask {
+        let mut occluded = 0;
+        for object in self.iter() {
+            occluded |= object.occluded_packet(packet, mask & !occluded, t_min, t_max);
+            if occluded == mask {
+                break;
+            }
+        }
+        occluded
+    }
 }
 
 #[cfg(test)]
@@ -1,10 +1,105 @@
+use std::{f64::consts::PI, sync::Arc};
+
 use glam::DVec3;
-use rand::seq::SliceRandom;
+use rand::Rng;
 
-use crate::{Hittable, MixturePDF, Ray, Scene, UniformHemispherePDF};
+use crate::{
+    packet_lanes, sample_wavelength, sample_wavelengths, wavelength_to_rgb, wavelengths_to_rgb,
+    HitRecord, Hittable, LightSample, MixturePDF, PacketHits, Ray, RayPacket, RgbSpectrum,
+    SampleableLight, Scene, Spectrum, UniformHemispherePDF, PACKET_SIZE, PDF,
+};
 
 pub trait Integrator {
-    fn ray_colour(&self, ray: Ray, scene: &Scene, depth: i32) -> DVec3;
+    /// Radiance arriving along `ray` given its closest hit in `scene`, so the first hits of many
+    /// rays can be found together
+    fn hit_colour(&self, ray: Ray, hit: Option<HitRecord>, scene: &Scene, depth: i32) -> DVec3;
+
+    fn ray_colour(&self, ray: Ray, scene: &Scene, depth: i32) -> DVec3 {
+        if depth <= 0 {
+            return DVec3::ZERO;
+        }
+        let hit = scene.hit(&ray, 0.001, 100000.0);
+        self.hit_colour(ray, hit, scene, depth)
+    }
+
+    /// `hit_colour`, stopping at the shadow ray towards the light sampled at the hit so the
+    /// shadow rays of a packet can be traced together. Integrators without one finish at once.
+    fn shade_hit<'a>(
+        &'a self,
+        ray: Ray,
+        hit: Option<HitRecord<'a>>,
+        scene: &'a Scene,
+        depth: i32,
+    ) -> Shading<'a> {
+        Shading::Done(self.hit_colour(ray, hit, scene, depth))
+    }
+
+    /// `ray_colour` for each active lane of a packet, tracing the first hits and the shadow rays
+    /// from them as packets
+    fn packet_colours(
+        &self,
+        packet: &RayPacket,
+        scene: &Scene,
+        depth: i32,
+    ) -> [DVec3; PACKET_SIZE] {
+        let mut colours = [DVec3::ZERO; PACKET_SIZE];
+        if depth <= 0 {
+            return colours;
+        }
+
+        let mut hits = PacketHits::new(100000.0);
+        scene.hit_packet(packet, packet.full_mask(), 0.001, &mut hits);
+
+        let mut waiting = Vec::new();
+        for lane in packet_lanes(packet.full_mask()) {
+            match self.shade_hit(packet.rays[lane], hits.hits[lane].take(), scene, depth) {
+                Shading::Done(colour) => colours[lane] = colour,
+                Shading::Shadow { ray, t_max, finish } => waiting.push((lane, ray, t_max, finish)),
+            }
+        }
+        if waiting.is_empty() {
+            return colours;
+        }
+
+        let shadow_rays = waiting.iter().map(|(_, ray, ..)| *ray).collect::<Vec<_>>();
+        let shadows = RayPacket::new(&shadow_rays);
+        let mut t_max = [0.0; PACKET_SIZE];
+        for (shadow, (_, _, distance, _)) in waiting.iter().enumerate() {
+            t_max[shadow] = *distance;
+        }
+        let occluded = scene.occluded_packet(&shadows, shadows.full_mask(), SHADOW_T_MIN, &t_max);
+
+        for (shadow, (lane, _, _, finish)) in waiting.into_iter().enumerate() {
+            colours[lane] = finish(occluded & (1 << shadow) != 0);
+        }
+        colours
+    }
+}
+
+/// Start of shadow rays, past the surface they leave
+const SHADOW_T_MIN: f64 = 0.001;
+
+/// Radiance along a ray, possibly still waiting on a shadow ray
+pub enum Shading<'a> {
+    Done(DVec3),
+    /// Finished by `finish` once it's known whether `ray` is blocked before `t_max`
+    Shadow {
+        ray: Ray,
+        t_max: f64,
+        finish: Box<dyn FnOnce(bool) -> DVec3 + 'a>,
+    },
+}
+
+impl Shading<'_> {
+    /// Traces the shadow ray on its own if there is one
+    pub fn resolve(self, scene: &Scene) -> DVec3 {
+        match self {
+            Shading::Done(colour) => colour,
+            Shading::Shadow { ray, t_max, finish } => {
+                finish(scene.occluded(&ray, SHADOW_T_MIN, t_max))
+            }
+        }
+    }
 }
 
 pub fn power_heuristic(num_f: u32, pdf_f: f64, num_g: u32, pdf_g: f64) -> f64 {
@@ -18,208 +113,420 @@ pub fn balance_heuristic(pdf_f: f64, pdf_g: f64) -> f64 {
     pdf_f / (pdf_f + pdf_g)
 }
 
-pub struct IterativeMISIntegrator {}
-
-impl Integrator for IterativeMISIntegrator {
-    fn ray_colour(&self, ray: Ray, scene: &Scene, depth: i32) -> DVec3 {
-        DVec3::ZERO
+/// Light arriving from a sample of `light` and scattered back along `ray` by a material that
+/// samples directions from `material_pdf`
+fn direct_lighting(
+    scene: &Scene,
+    ray: &Ray,
+    hr: &HitRecord,
+    material_pdf: &dyn PDF,
+    light: &dyn SampleableLight,
+    rng: &mut dyn rand::RngCore,
+) -> DVec3 {
+    let sample = match light.sample_li(hr.point, ray.time, ray.wavelengths, rng) {
+        Some(sample) => sample,
+        None => return DVec3::ZERO,
+    };
+
+    if material_pdf.value(sample.direction) == 0.0 {
+        return DVec3::ZERO;
+    }
+    let cos_theta = hr.normal.dot(sample.direction).abs();
+
+    let light_ray = Ray {
+        origin: hr.point,
+        dir: sample.direction,
+        time: ray.time,
+        wavelengths: ray.wavelengths,
+    };
+    if scene.occluded(&light_ray, 0.001, sample.distance - 0.0001) {
+        return DVec3::ZERO;
     }
+
+    brdf(ray, hr, &light_ray) * cos_theta * sample.radiance / sample.pdf
 }
 
-pub struct MultipleImportanceSampleIntegrator {}
+/// Light emitted at the hit back along `ray`, at its wavelengths if it carries any
+fn emitted(ray: &Ray, hr: &HitRecord) -> DVec3 {
+    match ray.wavelengths {
+        Some(wavelengths) => hr
+            .material
+            .emitted_spectral(hr.u, hr.v, hr.point, wavelengths),
+        None => hr.material.emitted(hr.u, hr.v, hr.point),
+    }
+}
 
-impl Integrator for MultipleImportanceSampleIntegrator {
-    fn ray_colour(&self, ray: Ray, scene: &Scene, depth: i32) -> DVec3 {
-        if depth <= 0 {
-            return DVec3::new(0.0, 0.0, 0.0);
-        }
-        let mut rng = rand::thread_rng();
+/// Material's brdf at the wavelengths `ray_in` carries, if any. It's upsampled as the
+/// reflectance of the Lambertian with the same brdf, keeping it within 1 / π.
+fn brdf(ray_in: &Ray, hr: &HitRecord, ray_out: &Ray) -> DVec3 {
+    let brdf = hr.material.brdf(ray_in, hr, ray_out);
+    match ray_in.wavelengths {
+        Some(wavelengths) => RgbSpectrum::new(brdf * PI).sample(wavelengths) / PI,
+        None => brdf,
+    }
+}
 
-        let hit = scene.hit(&ray, 0.001, 100000.0);
-        if hit.is_none() {
-            return (scene.background)(ray);
+/// Narrows a path reaching a dispersive material down to a single wavelength, as each one
+/// refracts differently. Returns the ray carrying it, to be traced again, and the weight
+/// turning its radiance into what the path carried before.
+fn split_wavelength(
+    ray: &Ray,
+    hr: &HitRecord,
+    rng: &mut dyn rand::RngCore,
+) -> Option<(Ray, DVec3)> {
+    if !hr.material.is_dispersive() {
+        return None;
+    }
+    let (wavelength, weight) = match ray.wavelengths {
+        // RGB becomes the colour of a single wavelength
+        None => {
+            let wavelength = sample_wavelength(rng.gen());
+            (wavelength, wavelength_to_rgb(wavelength))
         }
-
-        let hr = hit.unwrap();
-
-        let emitted = hr.material.emitted(hr.u, hr.v, hr.point);
-
-        let material_pdf = hr.material.scattering_pdf(&ray, &hr);
-        if material_pdf.is_none() {
-            return emitted;
+        Some(wavelengths) if wavelengths != DVec3::splat(wavelengths.x) => {
+            // Keep the first wavelength, standing in for all three
+            (wavelengths.x, DVec3::new(3.0, 0.0, 0.0))
         }
+        Some(_) => return None,
+    };
+    let ray = Ray {
+        wavelengths: Some(DVec3::splat(wavelength)),
+        ..*ray
+    };
+    Some((ray, weight))
+}
 
-        let material_pdf = material_pdf.unwrap();
-
-        if material_pdf.is_delta_distribution() {
-            let ray_out = Ray {
-                origin: hr.point,
-                dir: material_pdf.generate(&mut rng).normalize(),
-                time: ray.time,
-            };
-            let cos_theta = ray_out.dir.dot(hr.normal);
+/// Renders with paths carrying radiance at three sampled wavelengths instead of RGB, with RGB
+/// colours upsampled to smooth spectra and lights keeping their own spectra. Turns the result
+/// back into RGB, so it can wrap any other integrator.
+pub struct SpectralIntegrator<I: Integrator> {
+    pub integrator: I,
+}
 
-            return emitted
-                + (hr.material.brdf(&ray, &hr, &ray_out)
-                    * cos_theta
-                    * self.ray_colour(ray_out, scene, depth - 1));
-        }
+impl<I: Integrator> Integrator for SpectralIntegrator<I> {
+    fn hit_colour(&self, ray: Ray, hit: Option<HitRecord>, scene: &Scene, depth: i32) -> DVec3 {
+        let wavelengths = sample_wavelengths(rand::thread_rng().gen());
+        let ray = Ray {
+            wavelengths: Some(wavelengths),
+            ..ray
+        };
+        wavelengths_to_rgb(
+            self.integrator.hit_colour(ray, hit, scene, depth),
+            wavelengths,
+        )
+    }
+}
 
-        let light = scene.lights.choose(&mut rng).and_then(|light| {
-            let light_pdf = light.pdf_for_point(hr.point);
-            let dir = light_pdf.generate(&mut rng).normalize();
+pub struct IterativeMISIntegrator {}
 
-            let visibility_ray = Ray {
-                origin: hr.point,
-                dir,
-                time: ray.time,
-            };
+impl Integrator for IterativeMISIntegrator {
+    fn hit_colour(&self, ray: Ray, _: Option<HitRecord>, scene: &Scene, depth: i32) -> DVec3 {
+        DVec3::ZERO
+    }
+}
 
-            if hr.normal.dot(dir) < 0.0 {
-                return None;
-            }
+pub struct MultipleImportanceSampleIntegrator {}
 
-            let visiblity_hit = scene.hit(&visibility_ray, 0.001, 10000.0)?;
+/// A light sampled for a shading point and the shadow ray towards it
+struct LightChoice<'a> {
+    light: &'a Arc<dyn SampleableLight>,
+    pmf: f64,
+    sample: LightSample,
+    ray: Ray,
+}
 
-            if (visiblity_hit.t - light.hit(&visibility_ray, 0.001, 10000.0)?.t).abs() > 0.0001 {
-                return None;
-            }
-            Some((light, light_pdf, visibility_ray))
-        });
+impl MultipleImportanceSampleIntegrator {
+    /// Light from a sample of the material alone, when no light was sampled or it's blocked
+    fn material_colour(
+        &self,
+        ray: &Ray,
+        hr: &HitRecord,
+        material_pdf: &dyn PDF,
+        scene: &Scene,
+        depth: i32,
+    ) -> DVec3 {
+        let mut rng = rand::thread_rng();
+        let ray_out = Ray {
+            origin: hr.point,
+            dir: material_pdf.generate(&mut rng).normalize(),
+            time: ray.time,
+            wavelengths: ray.wavelengths,
+        };
+        let cos_theta = ray_out.dir.dot(hr.normal).abs();
+        let pdf = material_pdf.value(ray_out.dir);
+        if pdf == 0.0 {
+            return DVec3::ZERO;
+        }
 
-        if let Some((light, light_pdf, light_ray)) = light {
-            // HAVE LIGHT AND IS VISIBLE
+        (brdf(ray, hr, &ray_out) * cos_theta * self.ray_colour(ray_out, scene, depth - 1)) / pdf
+    }
 
-            let material_out = Ray {
-                origin: hr.point,
-                dir: material_pdf.generate(&mut rng).normalize(),
-                time: ray.time,
-            };
+    /// Light from a visible light sample and a material sample, weighted by MIS
+    fn light_and_material_colour(
+        &self,
+        ray: &Ray,
+        hr: &HitRecord,
+        material_pdf: &dyn PDF,
+        choice: LightChoice,
+        scene: &Scene,
+        depth: i32,
+    ) -> DVec3 {
+        let mut rng = rand::thread_rng();
+        let LightChoice {
+            light,
+            pmf: light_pmf,
+            sample: light_sample,
+            ray: light_ray,
+        } = choice;
+
+        let material_out = Ray {
+            origin: hr.point,
+            dir: material_pdf.generate(&mut rng).normalize(),
+            time: ray.time,
+            wavelengths: ray.wavelengths,
+        };
+
+        // Densities of sampling a direction through the light include the chance of choosing it
+        let light_pdf_value = light_pmf * light_sample.pdf;
+        let material_cos_theta = material_out.dir.dot(hr.normal).abs();
+
+        let material_out_pdf = material_pdf.value(material_out.dir);
+
+        let light_emit = light_sample.radiance;
+
+        let material_ray_hit_light = if light.light_bounds().is_some() {
+            light.hit(&material_out, 0.001, 10000.0).is_some()
+        } else {
+            // Lights at infinity are found by rays that escape
+            light.escaped_radiance(material_out.dir).is_some()
+                && !scene.occluded(&material_out, 0.001, f64::INFINITY)
+        };
+
+        let material_ray_colour = self.ray_colour(material_out, scene, depth - 1);
+        let material_ray_has_light = material_ray_colour.x >= 0.001
+            && material_ray_colour.y >= 0.001
+            && material_ray_colour.z >= 0.001;
+
+        let material_weight = if material_ray_has_light {
+            power_heuristic(
+                1,
+                material_out_pdf,
+                1,
+                if material_ray_hit_light {
+                    light_pmf * light.pdf_li(hr.point, material_out.dir)
+                } else {
+                    0.0
+                },
+            )
+        } else {
+            0.0
+        };
 
-            let light_hit = light.hit(&light_ray, 0.001, 10000.0).expect("Should hit");
-            let light_pdf_value = light_pdf.value(light_ray.dir);
-            let material_cos_theta = material_out.dir.dot(hr.normal);
+        // Microfacet samples can leave the surface in directions the material never scatters
+        let material_contribution = if material_out_pdf > 0.0 {
+            brdf(ray, hr, &material_out)
+                * material_cos_theta
+                * material_ray_colour
+                * material_weight
+                / material_out_pdf
+        } else {
+            DVec3::ZERO
+        };
+
+        // Material samples can't find delta lights, or lights at infinity rays can't see
+        let light_weight = if light.is_delta()
+            || (light.light_bounds().is_none() && light.escaped_radiance(light_ray.dir).is_none())
+        {
+            1.0
+        } else {
+            power_heuristic(1, light_pdf_value, 1, material_pdf.value(light_ray.dir))
+        };
 
-            let material_out_pdf = material_pdf.value(material_out.dir);
+        let light_cos_theta = hr.normal.dot(light_ray.dir).abs();
+        let light_contribution =
+            brdf(ray, hr, &light_ray) * light_cos_theta * light_emit * light_weight
+                / light_pdf_value;
 
-            let light_with_material_pdf = material_pdf.value(light_ray.dir);
+        material_contribution + light_contribution
+    }
+}
 
-            let light_emit = light_hit
-                .material
-                .emitted(light_hit.u, light_hit.v, hr.point);
+impl Integrator for MultipleImportanceSampleIntegrator {
+    fn hit_colour(&self, ray: Ray, hit: Option<HitRecord>, scene: &Scene, depth: i32) -> DVec3 {
+        self.shade_hit(ray, hit, scene, depth).resolve(scene)
+    }
 
-            //if let Some(material_ray_hit_light) = light.hit(&material_out, 0.001, 10000.0) {
-            //    // Cannot mix the brdf sampled ray if it hits the same light as our light ray
-            //    return hr.material.brdf(&ray, &hr, &light_ray) * light_cos_theta * light_emit
-            //        / light_pdf_value;
-            //}
-            let material_ray_hit_light = light.hit(&material_out, 0.001, 10000.0).is_some();
-
-            let material_ray_colour = self.ray_colour(material_out.clone(), scene, depth - 1);
-            let material_ray_has_light = material_ray_colour.x >= 0.001
-                && material_ray_colour.y >= 0.001
-                && material_ray_colour.z >= 0.001;
-
-            let material_weight = if material_ray_has_light {
-                power_heuristic(
-                    1,
-                    material_out_pdf,
-                    1,
-                    if material_ray_hit_light {
-                        light_pdf.value(material_out.dir)
-                    } else {
-                        0.0
-                    },
-                )
-            } else {
-                0.0
-            };
+    fn shade_hit<'a>(
+        &'a self,
+        ray: Ray,
+        hit: Option<HitRecord<'a>>,
+        scene: &'a Scene,
+        depth: i32,
+    ) -> Shading<'a> {
+        if depth <= 0 {
+            return Shading::Done(DVec3::new(0.0, 0.0, 0.0));
+        }
+        let mut rng = rand::thread_rng();
 
-            let material_contribution = hr.material.brdf(&ray, &hr, &material_out)
-                * material_cos_theta
-                * material_ray_colour
-                * material_weight
-                / material_out_pdf;
+        let hr = match hit {
+            Some(hr) => hr,
+            None => return Shading::Done(scene.background_radiance(ray)),
+        };
+        if let Some((ray, weight)) = split_wavelength(&ray, &hr, &mut rng) {
+            return Shading::Done(weight * self.ray_colour(ray, scene, depth));
+        }
 
-            let light_weight =
-                power_heuristic(1, light_pdf_value, 1, material_pdf.value(light_ray.dir));
+        let emitted = emitted(&ray, &hr);
 
-            let light_cos_theta = hr.normal.dot(light_ray.dir).max(0.0);
-            let light_contribution = hr.material.brdf(&ray, &hr, &light_ray)
-                * light_cos_theta
-                * light_emit
-                * light_weight
-                / light_pdf_value;
+        let material_pdf = match hr.material.scattering_pdf(&ray, &hr) {
+            Some(material_pdf) => material_pdf,
+            None => return Shading::Done(emitted),
+        };
 
-            emitted + material_contribution + light_contribution
-        } else {
+        if material_pdf.is_delta_distribution() {
             let ray_out = Ray {
                 origin: hr.point,
                 dir: material_pdf.generate(&mut rng).normalize(),
                 time: ray.time,
+                wavelengths: ray.wavelengths,
             };
             let cos_theta = ray_out.dir.dot(hr.normal);
-            let pdf = material_pdf.value(ray_out.dir);
 
-            emitted
-                + (hr.material.brdf(&ray, &hr, &ray_out)
-                    * cos_theta
-                    * self.ray_colour(ray_out, scene, depth - 1))
-                    / pdf
+            return Shading::Done(
+                emitted
+                    + (brdf(&ray, &hr, &ray_out)
+                        * cos_theta
+                        * self.ray_colour(ray_out, scene, depth - 1)),
+            );
+        }
+
+        let choice = scene
+            .sample_light(hr.point, hr.normal, rng.gen())
+            .and_then(|(light, pmf)| {
+                let sample = light.sample_li(hr.point, ray.time, ray.wavelengths, &mut rng)?;
+
+                // Only transmissive materials are lit from below the surface
+                if material_pdf.value(sample.direction) == 0.0 {
+                    return None;
+                }
+
+                let ray = Ray {
+                    origin: hr.point,
+                    dir: sample.direction,
+                    time: ray.time,
+                    wavelengths: ray.wavelengths,
+                };
+                Some(LightChoice {
+                    light,
+                    pmf,
+                    sample,
+                    ray,
+                })
+            });
+
+        match choice {
+            None => Shading::Done(
+                emitted + self.material_colour(&ray, &hr, material_pdf.as_ref(), scene, depth),
+            ),
+            Some(choice) => Shading::Shadow {
+                ray: choice.ray,
+                // Stop just short of the light so it doesn't occlude itself
+                t_max: choice.sample.distance - 0.0001,
+                finish: Box::new(move |occluded| {
+                    let material_pdf = material_pdf.as_ref();
+                    emitted
+                        + if occluded {
+                            self.material_colour(&ray, &hr, material_pdf, scene, depth)
+                        } else {
+                            self.light_and_material_colour(
+                                &ray,
+                                &hr,
+                                material_pdf,
+                                choice,
+                                scene,
+                                depth,
+                            )
+                        }
+                }),
+            },
         }
     }
 }
+
 pub struct ImportanceSampleLightIntegrator {}
 
 impl Integrator for ImportanceSampleLightIntegrator {
-    fn ray_colour(&self, ray: Ray, scene: &Scene, depth: i32) -> DVec3 {
+    fn hit_colour(&self, ray: Ray, hit: Option<HitRecord>, scene: &Scene, depth: i32) -> DVec3 {
         if depth <= 0 {
             return DVec3::new(0.0, 0.0, 0.0);
         }
         let mut rng = rand::thread_rng();
 
-        if let Some(hr) = scene.hit(&ray, 0.001, 100000.0) {
-            let emitted = hr.material.emitted(hr.u, hr.v, hr.point);
+        if let Some(hr) = hit {
+            if let Some((ray, weight)) = split_wavelength(&ray, &hr, &mut rng) {
+                return weight * self.ray_colour(ray, scene, depth);
+            }
+            let emitted = emitted(&ray, &hr);
             if emitted.length() > 0.0 {
                 return emitted;
             }
 
             if let Some(material_pdf) = hr.material.scattering_pdf(&ray, &hr) {
-                let scatter_pdf = if material_pdf.is_delta_distribution() {
-                    material_pdf
-                } else {
-                    let lights = &scene.lights;
-                    if let Some(light) = lights.choose(&mut rng) {
-                        light.pdf_for_point(hr.point)
-                    } else {
-                        material_pdf
+                let mut light_pmf = 1.0;
+                let light_pdf = if material_pdf.is_delta_distribution() {
+                    None
+                } else if let Some((light, pmf)) =
+                    scene.sample_light(hr.point, hr.normal, rng.gen())
+                {
+                    // Rays never hit these lights, so they have to light the point directly
+                    if light.is_delta() || light.light_bounds().is_none() {
+                        return emitted
+                            + direct_lighting(
+                                scene,
+                                &ray,
+                                &hr,
+                                material_pdf.as_ref(),
+                                light.as_ref(),
+                                &mut rng,
+                            ) / pmf;
                     }
+                    light_pmf = pmf;
+                    Some(light.pdf_for_point(hr.point))
+                } else {
+                    None
                 };
-                let mut out_dir = scatter_pdf.generate(&mut rng);
-                let mut ray_out = Ray {
+                let scatter_pdf = light_pdf.as_deref().unwrap_or(material_pdf.as_ref());
+
+                let out_dir = scatter_pdf.generate(&mut rng);
+                let ray_out = Ray {
                     origin: hr.point,
                     dir: out_dir,
                     time: ray.time,
+                    wavelengths: ray.wavelengths,
                 };
                 let mut cos_theta = out_dir.dot(hr.normal);
 
-                if cos_theta < 0.0 {
-                    out_dir = scatter_pdf.generate(&mut rng);
-                    ray_out = Ray {
-                        origin: hr.point,
-                        dir: out_dir,
-                        time: ray.time,
-                    };
-                    cos_theta = out_dir.dot(hr.normal);
-                    return emitted;
+                if scatter_pdf.is_delta_distribution() {
+                    if cos_theta < 0.0 {
+                        return emitted;
+                    }
+                } else {
+                    // Light samples can land where the material doesn't scatter
+                    if material_pdf.value(out_dir) == 0.0 {
+                        return emitted;
+                    }
+                    cos_theta = cos_theta.abs();
                 }
 
                 let pdf = if scatter_pdf.is_delta_distribution() {
                     1.0
                 } else {
-                    scatter_pdf.value(out_dir)
+                    light_pmf * scatter_pdf.value(out_dir)
                 };
-                let brdf = hr.material.brdf(&ray, &hr, &ray_out);
+                if pdf == 0.0 {
+                    return emitted;
+                }
+                let brdf = brdf(&ray, &hr, &ray_out);
 
                 emitted + (brdf * cos_theta * self.ray_colour(ray_out, scene, depth - 1)) / pdf
             } else {
@@ -254,21 +561,24 @@ impl Integrator for ImportanceSampleLightIntegrator {
             //    emitted
             //}
         } else {
-            (scene.background)(ray)
+            scene.background_radiance(ray)
         }
     }
 }
 
 pub struct BRDFSampledPathIntegrator {}
 impl Integrator for BRDFSampledPathIntegrator {
-    fn ray_colour(&self, ray: Ray, scene: &Scene, depth: i32) -> DVec3 {
+    fn hit_colour(&self, ray: Ray, hit: Option<HitRecord>, scene: &Scene, depth: i32) -> DVec3 {
         if depth <= 0 {
             return DVec3::new(0.0, 0.0, 0.0);
         }
         let mut rng = rand::thread_rng();
 
-        if let Some(hr) = scene.hit(&ray, 0.001, 100000.0) {
-            let emitted = hr.material.emitted(hr.u, hr.v, hr.point);
+        if let Some(hr) = hit {
+            if let Some((ray, weight)) = split_wavelength(&ray, &hr, &mut rng) {
+                return weight * self.ray_colour(ray, scene, depth);
+            }
+            let emitted = emitted(&ray, &hr);
 
             if let Some(material_pdf) = hr.material.scattering_pdf(&ray, &hr) {
                 let scatter_pdf = material_pdf;
@@ -278,15 +588,24 @@ impl Integrator for BRDFSampledPathIntegrator {
                     origin: hr.point,
                     dir: out_dir,
                     time: ray.time,
+                    wavelengths: ray.wavelengths,
+                };
+                // Delta materials cancel the signed cosine in their brdf
+                let cos_theta = if scatter_pdf.is_delta_distribution() {
+                    out_dir.dot(hr.normal)
+                } else {
+                    out_dir.dot(hr.normal).abs()
                 };
-                let cos_theta = out_dir.dot(hr.normal);
 
                 let pdf = if scatter_pdf.is_delta_distribution() {
                     1.0
                 } else {
                     scatter_pdf.value(out_dir)
                 };
-                let brdf = hr.material.brdf(&ray, &hr, &ray_out);
+                if pdf == 0.0 {
+                    return emitted;
+                }
+                let brdf = brdf(&ray, &hr, &ray_out);
 
                 emitted + (brdf * cos_theta * self.ray_colour(ray_out, scene, depth - 1)) / pdf
             } else {
@@ -321,21 +640,24 @@ impl Integrator for BRDFSampledPathIntegrator {
             //    emitted
             //}
         } else {
-            (scene.background)(ray)
+            scene.background_radiance(ray)
         }
     }
 }
 
 pub struct UniformSampledPathIntegrator {}
 impl Integrator for UniformSampledPathIntegrator {
-    fn ray_colour(&self, ray: Ray, scene: &Scene, depth: i32) -> DVec3 {
+    fn hit_colour(&self, ray: Ray, hit: Option<HitRecord>, scene: &Scene, depth: i32) -> DVec3 {
         if depth <= 0 {
             return DVec3::new(0.0, 0.0, 0.0);
         }
         let mut rng = rand::thread_rng();
 
-        if let Some(hr) = scene.hit(&ray, 0.001, 100000.0) {
-            let emitted = hr.material.emitted(hr.u, hr.v, hr.point);
+        if let Some(hr) = hit {
+            if let Some((ray, weight)) = split_wavelength(&ray, &hr, &mut rng) {
+                return weight * self.ray_colour(ray, scene, depth);
+            }
+            let emitted = emitted(&ray, &hr);
 
             if let Some(material_pdf) = hr.material.scattering_pdf(&ray, &hr) {
                 let scatter_pdf = if material_pdf.is_delta_distribution() {
@@ -348,6 +670,7 @@ impl Integrator for UniformSampledPathIntegrator {
                     origin: hr.point,
                     dir: out_dir,
                     time: ray.time,
+                    wavelengths: ray.wavelengths,
                 };
                 let cos_theta = out_dir.dot(hr.normal);
 
@@ -356,7 +679,10 @@ impl Integrator for UniformSampledPathIntegrator {
                 } else {
                     scatter_pdf.value(out_dir)
                 };
-                let brdf = hr.material.brdf(&ray, &hr, &ray_out);
+                if pdf == 0.0 {
+                    return emitted;
+                }
+                let brdf = brdf(&ray, &hr, &ray_out);
 
                 emitted + (brdf * cos_theta * self.ray_colour(ray_out, scene, depth - 1)) / pdf
             } else {
@@ -391,7 +717,121 @@ impl Integrator for UniformSampledPathIntegrator {
             //    emitted
             //}
         } else {
-            (scene.background)(ray)
+            scene.background_radiance(ray)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{ConstantBackground, DiffuseLight, Lambertian, PointLight, SolidColour, Sphere};
+
+    #[test]
+    fn spectral_rendering_matches_rgb() {
+        // Every bounce off a sphere under a white sky escapes, leaving the albedo
+        let albedo = DVec3::new(0.7, 0.4, 0.2);
+        let mut builder = Scene::build().background(ConstantBackground { colour: DVec3::ONE });
+        builder.add_object(Arc::new(Sphere {
+            center: DVec3::new(0.0, 0.0, -3.0),
+            radius: 1.0,
+            material: Arc::new(Lambertian::new(albedo)),
+        }));
+        let scene = builder.build();
+
+        let ray = Ray {
+            origin: DVec3::ZERO,
+            dir: DVec3::new(0.0, 0.0, -1.0),
+            time: 0.0,
+            wavelengths: None,
+        };
+        let rgb = BRDFSampledPathIntegrator {}.ray_colour(ray, &scene, 2);
+        assert!((rgb - albedo).abs().max_element() < 1e-9);
+
+        let spectral = SpectralIntegrator {
+            integrator: BRDFSampledPathIntegrator {},
+        };
+        let samples = 20_000;
+        let average = (0..samples).fold(DVec3::ZERO, |sum, _| {
+            sum + spectral.ray_colour(ray, &scene, 2)
+        }) / samples as f64;
+        assert!((average - albedo).abs().max_element() < 0.03, "{}", average);
+    }
+
+    #[test]
+    fn packet_colours_match_single_rays() {
+        // Lights don't scatter, so every colour is exact
+        let mut builder = Scene::build().background(ConstantBackground { colour: DVec3::ONE });
+        for (x, z, colour) in [
+            (-1.5, -4.0, DVec3::new(0.7, 0.4, 0.2)),
+            (1.5, -4.0, DVec3::splat(0.3)),
+            (0.0, -8.0, DVec3::new(0.1, 0.9, 0.5)),
+        ] {
+            builder.add_object(Arc::new(Sphere {
+                center: DVec3::new(x, 0.0, z),
+                radius: 1.0,
+                material: Arc::new(DiffuseLight {
+                    emit_colour: Arc::new(SolidColour { colour }),
+                }),
+            }));
+        }
+        let scene = builder.build_bvh().build();
+
+        let rays = (0..PACKET_SIZE)
+            .map(|lane| Ray {
+                origin: DVec3::ZERO,
+                dir: DVec3::new(lane as f64 - 3.5, 0.0, -4.0).normalize(),
+                time: 0.0,
+                wavelengths: None,
+            })
+            .collect::<Vec<_>>();
+        let integrator = BRDFSampledPathIntegrator {};
+        let colours = integrator.packet_colours(&RayPacket::new(&rays), &scene, 2);
+
+        for (ray, colour) in rays.iter().zip(colours) {
+            assert_eq!(integrator.ray_colour(*ray, &scene, 2), colour);
+        }
+    }
+
+    #[test]
+    fn packet_shadow_rays_match_single_rays() {
+        // Only the point light reaches the floor, so every colour is exact
+        let mut builder = Scene::build().background(ConstantBackground {
+            colour: DVec3::ZERO,
+        });
+        builder.add_object(Arc::new(Sphere {
+            center: DVec3::new(0.0, -1001.0, 0.0),
+            radius: 1000.0,
+            material: Arc::new(Lambertian::new(DVec3::splat(0.5))),
+        }));
+        builder.add_object(Arc::new(Sphere {
+            center: DVec3::new(-1.5, 1.5, -4.0),
+            radius: 0.6,
+            material: Arc::new(Lambertian::new(DVec3::ZERO)),
+        }));
+        builder.add_light(Arc::new(PointLight {
+            position: DVec3::new(0.0, 4.0, -4.0),
+            intensity: DVec3::splat(10.0),
+        }));
+        let scene = builder.build_bvh().build();
+
+        let rays = (0..PACKET_SIZE)
+            .map(|lane| Ray {
+                origin: DVec3::ZERO,
+                dir: DVec3::new(lane as f64 - 3.5, -1.0, -4.0).normalize(),
+                time: 0.0,
+                wavelengths: None,
+            })
+            .collect::<Vec<_>>();
+        let integrator = MultipleImportanceSampleIntegrator {};
+        let colours = integrator.packet_colours(&RayPacket::new(&rays), &scene, 2);
+
+        assert!(colours.contains(&DVec3::ZERO));
+        assert!(colours.iter().any(|colour| colour.x > 0.0));
+        for (ray, colour) in rays.iter().zip(colours) {
+            assert_eq!(integrator.ray_colour(*ray, &scene, 2), colour);
         }
     }
 }
@@ -22,7 +22,10 @@ mod camera;
 pub use camera::Camera;
 
 mod material;
-pub use material::{Dielectric, DiffuseLight, Lambertian, Material, Metal};
+pub use material::{
+    CoatedMaterial, Dielectric, DiffuseLight, Lambertian, Material, Metal, MixMaterial, MixMode,
+    OrenNayar, PrincipledBSDF, RoughDielectric, SpectralLight,
+};
 
 mod bounding_box;
 pub use bounding_box::AABB;
@@ -33,12 +36,45 @@ pub use bvh::BVHNode;
 mod texture;
 pub use texture::{CheckerTexture, SolidColour, Texture};
 
+mod normal_map;
+pub use normal_map::*;
+
+mod alpha;
+pub use alpha::*;
+
 mod math;
 pub use math::*;
 
 mod mesh;
 pub use mesh::*;
 
+mod mesh_bvh;
+pub use mesh_bvh::*;
+
+mod mesh_light;
+pub use mesh_light::*;
+
+mod alias;
+pub use alias::*;
+
+mod light_sampler;
+pub use light_sampler::*;
+
+mod lights;
+pub use lights::*;
+
+mod distribution;
+pub use distribution::*;
+
+mod environment;
+pub use environment::*;
+
+mod background;
+pub use background::*;
+
+mod sky;
+pub use sky::*;
+
 mod transform;
 pub use transform::*;
 
@@ -48,12 +84,21 @@ pub use integrator::*;
 mod pdf;
 pub use pdf::*;
 
+mod microfacet;
+pub use microfacet::*;
+
+mod spectrum;
+pub use spectrum::*;
+
 mod onb;
 pub use onb::*;
 
 mod scene;
 pub use scene::*;
 
+mod packet;
+pub use packet::*;
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -0,0 +1,608 @@
+use std::sync::Arc;
+
+use glam::{DQuat, DVec3};
+
+use crate::{luminance, AliasTable, Material, SampleableLight, AABB};
+
+/// Largest f64 below one, keeps remapped random numbers in [0, 1)
+const ONE_MINUS_EPSILON: f64 = 1.0 - f64::EPSILON / 2.0;
+
+/// Flux of a diffuse emitter with uniform emission `material.emitted(u, v, point)`
+pub(crate) fn surface_power(
+    material: &dyn Material,
+    u: f64,
+    v: f64,
+    point: DVec3,
+    area: f64,
+) -> f64 {
+    luminance(material.emitted(u, v, point)).max(0.0) * area * std::f64::consts::PI
+}
+
+/// Cone of directions around `w`, the whole sphere when `cos_theta` is -1
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DirectionCone {
+    pub w: DVec3,
+    pub cos_theta: f64,
+}
+
+impl DirectionCone {
+    pub fn entire_sphere() -> DirectionCone {
+        DirectionCone {
+            w: DVec3::Z,
+            cos_theta: -1.0,
+        }
+    }
+
+    /// Smallest cone containing both cones
+    pub fn union(a: &DirectionCone, b: &DirectionCone) -> DirectionCone {
+        let theta_a = a.cos_theta.clamp(-1.0, 1.0).acos();
+        let theta_b = b.cos_theta.clamp(-1.0, 1.0).acos();
+        let theta_d = a.w.angle_between(b.w);
+
+        if (theta_d + theta_b).min(std::f64::consts::PI) <= theta_a {
+            return *a;
+        }
+        if (theta_d + theta_a).min(std::f64::consts::PI) <= theta_b {
+            return *b;
+        }
+
+        let theta_o = (theta_a + theta_d + theta_b) / 2.0;
+        if theta_o >= std::f64::consts::PI {
+            return DirectionCone::entire_sphere();
+        }
+
+        // Rotate a's axis towards b's until the cone reaches the far side of b
+        let axis = a.w.cross(b.w).normalize_or_zero();
+        if axis == DVec3::ZERO {
+            return DirectionCone::entire_sphere();
+        }
+        DirectionCone {
+            w: DQuat::from_axis_angle(axis, theta_o - theta_a) * a.w,
+            cos_theta: theta_o.cos(),
+        }
+    }
+}
+
+/// Where a light is, which way it faces and how bright it is, for estimating its contribution
+/// to a point without sampling it (Conty Estevez and Kulla 2018)
+#[derive(Clone, Debug, PartialEq)]
+pub struct LightBounds {
+    pub bounds: AABB,
+    pub power: f64,
+    /// Spread of the surface normals
+    pub normals: DirectionCone,
+    /// Cosine of how far past its normals each point emits, 0 for diffuse emitters
+    pub cos_theta_e: f64,
+    pub two_sided: bool,
+}
+
+/// cos(max(0, a - b)) from the sines and cosines of a and b
+fn cos_sub_clamped(sin_a: f64, cos_a: f64, sin_b: f64, cos_b: f64) -> f64 {
+    if cos_a > cos_b {
+        1.0
+    } else {
+        cos_a * cos_b + sin_a * sin_b
+    }
+}
+
+/// sin(max(0, a - b)) from the sines and cosines of a and b
+fn sin_sub_clamped(sin_a: f64, cos_a: f64, sin_b: f64, cos_b: f64) -> f64 {
+    if cos_a > cos_b {
+        0.0
+    } else {
+        sin_a * cos_b - cos_a * sin_b
+    }
+}
+
+fn sin_from_cos(cos: f64) -> f64 {
+    (1.0 - cos * cos).max(0.0).sqrt()
+}
+
+impl LightBounds {
+    /// Diffuse emitters that could face any direction
+    pub fn omnidirectional(bounds: AABB, power: f64) -> LightBounds {
+        LightBounds {
+            bounds,
+            power,
+            normals: DirectionCone::entire_sphere(),
+            cos_theta_e: 0.0,
+            two_sided: false,
+        }
+    }
+
+    pub fn union(a: &LightBounds, b: &LightBounds) -> LightBounds {
+        if a.power == 0.0 {
+            return b.clone();
+        }
+        if b.power == 0.0 {
+            return a.clone();
+        }
+
+        LightBounds {
+            bounds: AABB::surrounding_box(&a.bounds, &b.bounds),
+            power: a.power + b.power,
+            normals: DirectionCone::union(&a.normals, &b.normals),
+            cos_theta_e: a.cos_theta_e.min(b.cos_theta_e),
+            two_sided: a.two_sided || b.two_sided,
+        }
+    }
+
+    fn centroid(&self) -> DVec3 {
+        (self.bounds.min + self.bounds.max) / 2.0
+    }
+
+    /// Conservative estimate of the light reaching `point`, `normal` may be zero for points in media
+    pub fn importance(&self, point: DVec3, normal: DVec3) -> f64 {
+        let centre = self.centroid();
+        let radius = (self.bounds.max - self.bounds.min).length() / 2.0;
+        // Don't let points inside or near the bounds blow up the estimate
+        let distance_squared = point.distance_squared(centre).max(radius);
+        let to_point = (point - centre).normalize_or_zero();
+
+        let mut cos_theta_w = self.normals.w.dot(to_point);
+        if self.two_sided {
+            cos_theta_w = cos_theta_w.abs();
+        }
+        let sin_theta_w = sin_from_cos(cos_theta_w);
+
+        // Angle subtended by the bounds from the point
+        let cos_theta_b = if point.distance_squared(centre) <= radius * radius {
+            -1.0
+        } else {
+            (1.0 - radius * radius / point.distance_squared(centre))
+                .max(0.0)
+                .sqrt()
+        };
+        let sin_theta_b = sin_from_cos(cos_theta_b);
+
+        // Smallest angle between the point and any normal, then any point in the bounds
+        let cos_theta_o = self.normals.cos_theta;
+        let sin_theta_o = sin_from_cos(cos_theta_o);
+        let cos_theta_x = cos_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, cos_theta_o);
+        let sin_theta_x = sin_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, cos_theta_o);
+        let cos_theta_p = cos_sub_clamped(sin_theta_x, cos_theta_x, sin_theta_b, cos_theta_b);
+        if cos_theta_p <= self.cos_theta_e {
+            return 0.0;
+        }
+
+        let mut importance = self.power * cos_theta_p / distance_squared;
+
+        if normal != DVec3::ZERO {
+            let cos_theta_i = to_point.dot(normal).abs();
+            let sin_theta_i = sin_from_cos(cos_theta_i);
+            importance *= cos_sub_clamped(sin_theta_i, cos_theta_i, sin_theta_b, cos_theta_b);
+        }
+
+        importance.max(0.0)
+    }
+}
+
+/// Picks which of `Scene::lights` to sample for a shading point
+pub trait LightSampler: Send + Sync {
+    /// Index of the chosen light and the probability it was chosen, `u` must be in [0, 1)
+    fn sample(&self, point: DVec3, normal: DVec3, u: f64) -> Option<(usize, f64)>;
+    /// Probability `sample` chooses `light` for the same point and normal
+    fn pmf(&self, point: DVec3, normal: DVec3, light: usize) -> f64;
+}
+
+/// How `SceneBuilder::build` chooses lights to sample
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LightSampling {
+    Uniform,
+    /// In proportion to each light's power
+    #[default]
+    Power,
+    /// Traverse a BVH over the lights using their estimated contribution to the point
+    Tree,
+}
+
+impl LightSampling {
+    /// `scene_radius` bounds the scene, for the power of lights at infinity
+    pub fn build(
+        self,
+        lights: &[Arc<dyn SampleableLight>],
+        scene_radius: f64,
+    ) -> Arc<dyn LightSampler> {
+        match self {
+            LightSampling::Uniform => Arc::new(UniformLightSampler {
+                count: lights.len(),
+            }),
+            LightSampling::Power => Arc::new(PowerLightSampler::new(lights, scene_radius)),
+            LightSampling::Tree => Arc::new(BVHLightSampler::new(lights)),
+        }
+    }
+}
+
+pub struct UniformLightSampler {
+    pub count: usize,
+}
+
+impl LightSampler for UniformLightSampler {
+    fn sample(&self, _: DVec3, _: DVec3, u: f64) -> Option<(usize, f64)> {
+        if self.count == 0 {
+            return None;
+        }
+        let index = ((u * self.count as f64) as usize).min(self.count - 1);
+        Some((index, 1.0 / self.count as f64))
+    }
+
+    fn pmf(&self, _: DVec3, _: DVec3, light: usize) -> f64 {
+        if light < self.count {
+            1.0 / self.count as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+pub struct PowerLightSampler {
+    table: Option<AliasTable>,
+}
+
+impl PowerLightSampler {
+    pub fn new(lights: &[Arc<dyn SampleableLight>], scene_radius: f64) -> PowerLightSampler {
+        let powers = lights
+            .iter()
+            .map(|light| match light.light_bounds() {
+                Some(_) => light.power(),
+                // The flux of lights at infinity falls on a disc the size of the scene
+                None => light.power() * std::f64::consts::PI * scene_radius * scene_radius,
+            })
+            .collect::<Vec<_>>();
+        PowerLightSampler {
+            table: if powers.is_empty() {
+                None
+            } else {
+                Some(AliasTable::new(&powers))
+            },
+        }
+    }
+}
+
+impl LightSampler for PowerLightSampler {
+    fn sample(&self, _: DVec3, _: DVec3, u: f64) -> Option<(usize, f64)> {
+        self.table.as_ref().map(|table| table.sample(u))
+    }
+
+    fn pmf(&self, _: DVec3, _: DVec3, light: usize) -> f64 {
+        match &self.table {
+            Some(table) if light < table.len() => table.pmf(light),
+            _ => 0.0,
+        }
+    }
+}
+
+enum LightNodeKind {
+    Leaf(usize),
+    /// The first child directly follows its parent
+    Interior {
+        second_child: usize,
+    },
+}
+
+struct LightNode {
+    bounds: LightBounds,
+    kind: LightNodeKind,
+}
+
+/// Many-light sampling with a BVH over the lights' bounds (Conty Estevez and Kulla 2018).
+/// Lights without bounds, e.g. at infinity, are chosen uniformly alongside the tree.
+pub struct BVHLightSampler {
+    nodes: Vec<LightNode>,
+    infinite: Vec<usize>,
+    /// Branches taken from the root to reach each bounded light, one bit per level
+    trails: Vec<Option<u64>>,
+}
+
+impl BVHLightSampler {
+    pub fn new(lights: &[Arc<dyn SampleableLight>]) -> BVHLightSampler {
+        let mut infinite = Vec::new();
+        let mut bounded = Vec::new();
+        for (index, light) in lights.iter().enumerate() {
+            match light.light_bounds() {
+                Some(bounds) if bounds.power > 0.0 => bounded.push((index, bounds)),
+                Some(_) => {}
+                None => infinite.push(index),
+            }
+        }
+
+        let mut sampler = BVHLightSampler {
+            nodes: Vec::new(),
+            infinite,
+            trails: vec![None; lights.len()],
+        };
+        if !bounded.is_empty() {
+            sampler.build(&mut bounded, 0, 0);
+        }
+        sampler
+    }
+
+    /// Adds the subtree for `lights` and returns its bounds
+    fn build(
+        &mut self,
+        lights: &mut [(usize, LightBounds)],
+        trail: u64,
+        depth: u32,
+    ) -> LightBounds {
+        if lights.len() == 1 {
+            let (index, bounds) = lights[0].clone();
+            self.trails[index] = Some(trail);
+            self.nodes.push(LightNode {
+                bounds: bounds.clone(),
+                kind: LightNodeKind::Leaf(index),
+            });
+            return bounds;
+        }
+
+        // Split at the median centroid along the widest axis, keeping the tree balanced so the
+        // trails fit in 64 bits
+        let (min, max) = lights.iter().fold(
+            (DVec3::splat(f64::MAX), DVec3::splat(f64::MIN)),
+            |(min, max), (_, bounds)| (min.min(bounds.centroid()), max.max(bounds.centroid())),
+        );
+        let extent = max - min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+        lights.sort_by(|(_, a), (_, b)| {
+            a.centroid()[axis]
+                .partial_cmp(&b.centroid()[axis])
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        let midpoint = lights.len() / 2;
+        let (left, right) = lights.split_at_mut(midpoint);
+
+        let node = self.nodes.len();
+        self.nodes.push(LightNode {
+            bounds: LightBounds::omnidirectional(AABB::default(), 0.0),
+            kind: LightNodeKind::Interior { second_child: 0 },
+        });
+
+        let left_bounds = self.build(left, trail, depth + 1);
+        let second_child = self.nodes.len();
+        let right_bounds = self.build(right, trail | (1 << depth), depth + 1);
+
+        let bounds = LightBounds::union(&left_bounds, &right_bounds);
+        self.nodes[node] = LightNode {
+            bounds: bounds.clone(),
+            kind: LightNodeKind::Interior { second_child },
+        };
+        bounds
+    }
+
+    /// Probability of choosing the tree over the lights at infinity
+    fn tree_probability(&self) -> f64 {
+        if self.nodes.is_empty() {
+            0.0
+        } else {
+            1.0 / (self.infinite.len() + 1) as f64
+        }
+    }
+
+    fn child_probabilities(&self, node: usize, point: DVec3, normal: DVec3) -> Option<[f64; 2]> {
+        let second_child = match self.nodes[node].kind {
+            LightNodeKind::Interior { second_child } => second_child,
+            LightNodeKind::Leaf(_) => return None,
+        };
+        let left = self.nodes[node + 1].bounds.importance(point, normal);
+        let right = self.nodes[second_child].bounds.importance(point, normal);
+        if left + right == 0.0 {
+            return Some([0.0, 0.0]);
+        }
+        Some([left / (left + right), right / (left + right)])
+    }
+}
+
+impl LightSampler for BVHLightSampler {
+    fn sample(&self, point: DVec3, normal: DVec3, u: f64) -> Option<(usize, f64)> {
+        let p_tree = self.tree_probability();
+        let p_infinite = 1.0 - p_tree;
+
+        if u < p_infinite {
+            // Without lights at infinity the tree is empty too
+            if self.infinite.is_empty() {
+                return None;
+            }
+            let u = u / p_infinite;
+            let index = ((u * self.infinite.len() as f64) as usize).min(self.infinite.len() - 1);
+            return Some((
+                self.infinite[index],
+                p_infinite / self.infinite.len() as f64,
+            ));
+        }
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let mut u = ((u - p_infinite) / p_tree).min(ONE_MINUS_EPSILON);
+        let mut pmf = p_tree;
+        let mut node = 0;
+        loop {
+            match self.nodes[node].kind {
+                LightNodeKind::Leaf(light) => {
+                    // A lone light still has to be able to reach the point
+                    if node > 0 || self.nodes[node].bounds.importance(point, normal) > 0.0 {
+                        return Some((light, pmf));
+                    }
+                    return None;
+                }
+                LightNodeKind::Interior { second_child } => {
+                    let [p_left, p_right] = self.child_probabilities(node, point, normal)?;
+                    if p_left == 0.0 && p_right == 0.0 {
+                        return None;
+                    }
+                    if u < p_left {
+                        node += 1;
+                        u = (u / p_left).min(ONE_MINUS_EPSILON);
+                        pmf *= p_left;
+                    } else {
+                        node = second_child;
+                        u = ((u - p_left) / p_right).min(ONE_MINUS_EPSILON);
+                        pmf *= p_right;
+                    }
+                }
+            }
+        }
+    }
+
+    fn pmf(&self, point: DVec3, normal: DVec3, light: usize) -> f64 {
+        if self.infinite.contains(&light) {
+            return (1.0 - self.tree_probability()) / self.infinite.len() as f64;
+        }
+        let mut trail = match self.trails.get(light) {
+            Some(Some(trail)) => *trail,
+            _ => return 0.0,
+        };
+
+        let mut pmf = self.tree_probability();
+        let mut node = 0;
+        loop {
+            match self.nodes[node].kind {
+                LightNodeKind::Leaf(_) => {
+                    if node == 0 && self.nodes[node].bounds.importance(point, normal) == 0.0 {
+                        return 0.0;
+                    }
+                    return pmf;
+                }
+                LightNodeKind::Interior { second_child } => {
+                    let [p_left, p_right] = match self.child_probabilities(node, point, normal) {
+                        Some(probabilities) => probabilities,
+                        None => return 0.0,
+                    };
+                    if trail & 1 == 0 {
+                        node += 1;
+                        pmf *= p_left;
+                    } else {
+                        node = second_child;
+                        pmf *= p_right;
+                    }
+                    trail >>= 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::DVec2;
+
+    use crate::{DiffuseLight, SolidColour, Sphere};
+
+    use super::*;
+
+    fn sphere_light(center: DVec3, radiance: f64) -> Arc<dyn SampleableLight> {
+        Arc::new(Sphere {
+            center,
+            radius: 0.5,
+            material: Arc::new(DiffuseLight {
+                emit_colour: Arc::new(SolidColour {
+                    colour: DVec3::splat(radiance),
+                }),
+            }),
+        })
+    }
+
+    fn lights() -> Vec<Arc<dyn SampleableLight>> {
+        (0..20)
+            .map(|i| {
+                let grid = DVec2::new((i % 5) as f64, (i / 5) as f64) * 4.0;
+                sphere_light(DVec3::new(grid.x, 0.0, grid.y), 1.0 + i as f64)
+            })
+            .collect()
+    }
+
+    /// Sampled frequencies match `pmf`, which sums to one
+    fn check_sampler(sampler: &dyn LightSampler, count: usize, point: DVec3, normal: DVec3) {
+        let samples = 100_000;
+        let mut counts = vec![0usize; count];
+        for i in 0..samples {
+            let (light, pmf) = sampler
+                .sample(point, normal, (i as f64 + 0.5) / samples as f64)
+                .unwrap();
+            assert!((pmf - sampler.pmf(point, normal, light)).abs() < 1e-12);
+            counts[light] += 1;
+        }
+
+        let total: f64 = (0..count)
+            .map(|light| sampler.pmf(point, normal, light))
+            .sum();
+        assert!((total - 1.0).abs() < 1e-9);
+        for (light, count) in counts.iter().enumerate() {
+            let frequency = *count as f64 / samples as f64;
+            assert!((frequency - sampler.pmf(point, normal, light)).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn power_sampling_follows_power() {
+        let lights = lights();
+        let sampler = PowerLightSampler::new(&lights, 10.0);
+        check_sampler(&sampler, lights.len(), DVec3::ZERO, DVec3::Y);
+
+        // Power scales with radiance for equal spheres
+        assert!(
+            (sampler.pmf(DVec3::ZERO, DVec3::Y, 19) / sampler.pmf(DVec3::ZERO, DVec3::Y, 0) - 20.0)
+                .abs()
+                < 1e-9
+        );
+    }
+
+    #[test]
+    fn tree_sampling_is_consistent() {
+        let lights = lights();
+        let sampler = BVHLightSampler::new(&lights);
+        let point = DVec3::new(1.0, 1.0, 1.0);
+        check_sampler(&sampler, lights.len(), point, DVec3::Y);
+        check_sampler(&sampler, lights.len(), point, DVec3::ZERO);
+
+        // Nearby lights are preferred over brighter distant ones
+        assert!(sampler.pmf(point, DVec3::Y, 0) > sampler.pmf(point, DVec3::Y, 19));
+    }
+
+    #[test]
+    fn tree_prefers_lights_above_the_surface() {
+        let lights = vec![
+            sphere_light(DVec3::new(0.0, 5.0, 0.0), 1.0),
+            sphere_light(DVec3::new(5.0, 0.0, 0.0), 1.0),
+        ];
+        let sampler = BVHLightSampler::new(&lights);
+        assert!(
+            sampler.pmf(DVec3::ZERO, DVec3::Y, 0) > 4.0 * sampler.pmf(DVec3::ZERO, DVec3::Y, 1)
+        );
+        assert_eq!(sampler.pmf(DVec3::ZERO, DVec3::ZERO, 0), 0.5);
+    }
+
+    #[test]
+    fn empty_tree_samples_nothing() {
+        let point = DVec3::new(1.0, 1.0, 1.0);
+        let empty = BVHLightSampler::new(&[]);
+        assert!(empty.sample(point, DVec3::Y, 0.5).is_none());
+
+        let dark = BVHLightSampler::new(&[sphere_light(DVec3::ZERO, 0.0)]);
+        assert!(dark.sample(point, DVec3::Y, 0.5).is_none());
+        assert_eq!(dark.pmf(point, DVec3::Y, 0), 0.0);
+    }
+
+    #[test]
+    fn cone_union_contains_both() {
+        let a = DirectionCone {
+            w: DVec3::X,
+            cos_theta: 1.0,
+        };
+        let b = DirectionCone {
+            w: DVec3::Y,
+            cos_theta: 1.0,
+        };
+        let union = DirectionCone::union(&a, &b);
+        assert!(union.w.distance(DVec3::new(1.0, 1.0, 0.0).normalize()) < 1e-12);
+        assert!((union.cos_theta - std::f64::consts::FRAC_PI_4.cos()).abs() < 1e-12);
+
+        let opposite = DirectionCone::union(&a, &DirectionCone { w: -DVec3::X, ..a });
+        assert_eq!(opposite.cos_theta, -1.0);
+    }
+}
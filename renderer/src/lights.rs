@@ -0,0 +1,465 @@
+use glam::DVec3;
+
+use crate::{
+    luminance, rgb_at, DiracDeltaPDF, DirectionCone, HitRecord, Hittable, LightBounds, Ray,
+    SampleableLight, UniformConePDF, AABB, PDF,
+};
+
+/// Incident light at a point from a light sample
+#[derive(Clone, Debug, PartialEq)]
+pub struct LightSample {
+    /// Unit direction from the point towards the light
+    pub direction: DVec3,
+    /// Distance to the sampled point on the light, infinite for lights at infinity
+    pub distance: f64,
+    pub radiance: DVec3,
+    /// Solid angle density of `direction`, 1 for delta lights
+    pub pdf: f64,
+}
+
+/// Tiny box around a point light, so it can go in a light BVH
+fn point_bounds(position: DVec3) -> AABB {
+    AABB {
+        min: position - DVec3::splat(0.0001),
+        max: position + DVec3::splat(0.0001),
+    }
+}
+
+/// Emits `intensity` (radiant intensity) equally in all directions from a point
+#[derive(Clone, Debug)]
+pub struct PointLight {
+    pub position: DVec3,
+    pub intensity: DVec3,
+}
+
+// Only found by sampling it, rays never hit it
+impl Hittable for PointLight {
+    fn hit(&self, _: &Ray, _: f64, _: f64) -> Option<HitRecord<'_>> {
+        None
+    }
+
+    fn occluded(&self, _: &Ray, _: f64, _: f64) -> bool {
+        false
+    }
+
+    fn bounding_box(&self, _: f64, _: f64) -> Option<AABB> {
+        Some(point_bounds(self.position))
+    }
+
+    fn sample_uniform(&self, _: &mut dyn rand::RngCore) -> DVec3 {
+        self.position
+    }
+
+    fn pdf_uniform(&self, _: DVec3) -> f64 {
+        0.0
+    }
+}
+
+impl SampleableLight for PointLight {
+    fn pdf_for_point(&self, point: DVec3) -> Box<dyn PDF> {
+        Box::new(DiracDeltaPDF::new(self.position - point))
+    }
+
+    fn power(&self) -> f64 {
+        4.0 * std::f64::consts::PI * luminance(self.intensity)
+    }
+
+    fn sample_li(
+        &self,
+        point: DVec3,
+        _: f64,
+        wavelengths: Option<DVec3>,
+        _: &mut dyn rand::RngCore,
+    ) -> Option<LightSample> {
+        let distance = self.position.distance(point);
+        if distance == 0.0 {
+            return None;
+        }
+        Some(LightSample {
+            direction: (self.position - point) / distance,
+            distance,
+            radiance: rgb_at(self.intensity, wavelengths) / (distance * distance),
+            pdf: 1.0,
+        })
+    }
+
+    fn pdf_li(&self, _: DVec3, _: DVec3) -> f64 {
+        0.0
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+}
+
+/// Relative intensity by angle from a spot light's axis, like the vertical angles of an IES profile
+#[derive(Clone, Debug)]
+pub struct IntensityProfile {
+    /// Increasing angles in radians
+    angles: Vec<f64>,
+    /// Intensities at each angle, scaled so the brightest is 1
+    values: Vec<f64>,
+}
+
+impl IntensityProfile {
+    /// `angles_degrees` must be increasing and match `candela` in length. Intensity is held at
+    /// the last value beyond the final angle.
+    pub fn new(angles_degrees: &[f64], candela: &[f64]) -> IntensityProfile {
+        assert!(!angles_degrees.is_empty() && angles_degrees.len() == candela.len());
+        let max = candela.iter().cloned().fold(0.0, f64::max);
+        IntensityProfile {
+            angles: angles_degrees
+                .iter()
+                .map(|angle| angle.to_radians())
+                .collect(),
+            values: candela
+                .iter()
+                .map(|value| if max > 0.0 { value / max } else { 0.0 })
+                .collect(),
+        }
+    }
+
+    pub fn sample(&self, angle: f64) -> f64 {
+        let next = self.angles.partition_point(|&a| a <= angle);
+        if next == 0 {
+            return self.values[0];
+        }
+        if next == self.angles.len() {
+            return self.values[next - 1];
+        }
+
+        let (a0, a1) = (self.angles[next - 1], self.angles[next]);
+        let s = (angle - a0) / (a1 - a0);
+        self.values[next - 1] * (1.0 - s) + self.values[next] * s
+    }
+}
+
+/// A point light emitting in a cone, fading out between `falloff_start` and `total_width`
+#[derive(Clone, Debug)]
+pub struct SpotLight {
+    pub position: DVec3,
+    /// Unit axis of the cone
+    pub direction: DVec3,
+    /// Intensity along the axis
+    pub intensity: DVec3,
+    cos_falloff_start: f64,
+    cos_total_width: f64,
+    pub profile: Option<IntensityProfile>,
+}
+
+impl SpotLight {
+    /// Angles are in degrees from the axis
+    pub fn new(
+        position: DVec3,
+        direction: DVec3,
+        intensity: DVec3,
+        falloff_start: f64,
+        total_width: f64,
+    ) -> SpotLight {
+        SpotLight {
+            position,
+            direction: direction.normalize(),
+            intensity,
+            cos_falloff_start: falloff_start.to_radians().cos(),
+            cos_total_width: total_width.to_radians().cos(),
+            profile: None,
+        }
+    }
+
+    pub fn with_profile(mut self, profile: IntensityProfile) -> SpotLight {
+        self.profile = Some(profile);
+        self
+    }
+
+    /// Fraction of `intensity` emitted in the unit direction `w` away from the light
+    fn falloff(&self, w: DVec3) -> f64 {
+        let cos_theta = w.dot(self.direction);
+        if cos_theta <= self.cos_total_width {
+            return 0.0;
+        }
+
+        let s = ((cos_theta - self.cos_total_width)
+            / (self.cos_falloff_start - self.cos_total_width))
+            .clamp(0.0, 1.0);
+        let smooth = s * s * (3.0 - 2.0 * s);
+
+        match &self.profile {
+            Some(profile) => smooth * profile.sample(cos_theta.clamp(-1.0, 1.0).acos()),
+            None => smooth,
+        }
+    }
+}
+
+// Only found by sampling it, rays never hit it
+impl Hittable for SpotLight {
+    fn hit(&self, _: &Ray, _: f64, _: f64) -> Option<HitRecord<'_>> {
+        None
+    }
+
+    fn occluded(&self, _: &Ray, _: f64, _: f64) -> bool {
+        false
+    }
+
+    fn bounding_box(&self, _: f64, _: f64) -> Option<AABB> {
+        Some(point_bounds(self.position))
+    }
+
+    fn sample_uniform(&self, _: &mut dyn rand::RngCore) -> DVec3 {
+        self.position
+    }
+
+    fn pdf_uniform(&self, _: DVec3) -> f64 {
+        0.0
+    }
+}
+
+impl SampleableLight for SpotLight {
+    fn pdf_for_point(&self, point: DVec3) -> Box<dyn PDF> {
+        Box::new(DiracDeltaPDF::new(self.position - point))
+    }
+
+    fn power(&self) -> f64 {
+        // Full intensity inside the falloff and roughly half across it
+        luminance(self.intensity)
+            * 2.0
+            * std::f64::consts::PI
+            * ((1.0 - self.cos_falloff_start)
+                + (self.cos_falloff_start - self.cos_total_width) / 2.0)
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        let theta_e = self.cos_total_width.acos() - self.cos_falloff_start.acos();
+        Some(LightBounds {
+            normals: DirectionCone {
+                w: self.direction,
+                cos_theta: self.cos_falloff_start,
+            },
+            cos_theta_e: theta_e.cos(),
+            ..LightBounds::omnidirectional(point_bounds(self.position), self.power())
+        })
+    }
+
+    fn sample_li(
+        &self,
+        point: DVec3,
+        _: f64,
+        wavelengths: Option<DVec3>,
+        _: &mut dyn rand::RngCore,
+    ) -> Option<LightSample> {
+        let distance = self.position.distance(point);
+        if distance == 0.0 {
+            return None;
+        }
+        let direction = (self.position - point) / distance;
+        let falloff = self.falloff(-direction);
+        if falloff == 0.0 {
+            return None;
+        }
+
+        Some(LightSample {
+            direction,
+            distance,
+            radiance: rgb_at(self.intensity, wavelengths) * falloff / (distance * distance),
+            pdf: 1.0,
+        })
+    }
+
+    fn pdf_li(&self, _: DVec3, _: DVec3) -> f64 {
+        0.0
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+}
+
+/// Parallel light from infinitely far away, e.g. the sun. A non-zero angular radius gives a
+/// disc of uniform radiance with soft shadows.
+#[derive(Clone, Debug)]
+pub struct DistantLight {
+    /// Unit direction towards the light
+    pub direction: DVec3,
+    /// Irradiance on a surface facing the light
+    pub irradiance: DVec3,
+    cos_angular_radius: f64,
+}
+
+impl DistantLight {
+    /// `angular_radius` is in degrees, 0 for a delta light
+    pub fn new(direction: DVec3, irradiance: DVec3, angular_radius: f64) -> DistantLight {
+        DistantLight {
+            direction: direction.normalize(),
+            irradiance,
+            cos_angular_radius: angular_radius.to_radians().cos(),
+        }
+    }
+
+    fn solid_angle(&self) -> f64 {
+        2.0 * std::f64::consts::PI * (1.0 - self.cos_angular_radius)
+    }
+
+    /// Radiance of the disc, which integrates to `irradiance` when facing it
+    fn radiance(&self) -> DVec3 {
+        let sin_2 = 1.0 - self.cos_angular_radius * self.cos_angular_radius;
+        self.irradiance / (std::f64::consts::PI * sin_2)
+    }
+}
+
+// Rays never hit it, those escaping the scene see its disc through `escaped_radiance`
+impl Hittable for DistantLight {
+    fn hit(&self, _: &Ray, _: f64, _: f64) -> Option<HitRecord<'_>> {
+        None
+    }
+
+    fn occluded(&self, _: &Ray, _: f64, _: f64) -> bool {
+        false
+    }
+
+    fn bounding_box(&self, _: f64, _: f64) -> Option<AABB> {
+        None
+    }
+
+    fn sample_uniform(&self, _: &mut dyn rand::RngCore) -> DVec3 {
+        DVec3::ZERO
+    }
+
+    fn pdf_uniform(&self, _: DVec3) -> f64 {
+        0.0
+    }
+}
+
+impl SampleableLight for DistantLight {
+    fn pdf_for_point(&self, _: DVec3) -> Box<dyn PDF> {
+        if self.is_delta() {
+            Box::new(DiracDeltaPDF::new(self.direction))
+        } else {
+            Box::new(UniformConePDF::new(self.direction, self.cos_angular_radius))
+        }
+    }
+
+    /// Irradiance, since the flux depends on the size of the scene it falls on
+    fn power(&self) -> f64 {
+        luminance(self.irradiance)
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        None
+    }
+
+    fn sample_li(
+        &self,
+        point: DVec3,
+        _: f64,
+        wavelengths: Option<DVec3>,
+        rng: &mut dyn rand::RngCore,
+    ) -> Option<LightSample> {
+        if self.is_delta() {
+            return Some(LightSample {
+                direction: self.direction,
+                distance: f64::INFINITY,
+                radiance: rgb_at(self.irradiance, wavelengths),
+                pdf: 1.0,
+            });
+        }
+
+        Some(LightSample {
+            direction: self.pdf_for_point(point).generate(rng).normalize(),
+            distance: f64::INFINITY,
+            radiance: rgb_at(self.radiance(), wavelengths),
+            pdf: 1.0 / self.solid_angle(),
+        })
+    }
+
+    fn pdf_li(&self, _: DVec3, direction: DVec3) -> f64 {
+        if self.is_delta() || direction.normalize().dot(self.direction) < self.cos_angular_radius {
+            0.0
+        } else {
+            1.0 / self.solid_angle()
+        }
+    }
+
+    fn is_delta(&self) -> bool {
+        self.cos_angular_radius >= 1.0
+    }
+
+    fn escaped_radiance(&self, direction: DVec3) -> Option<DVec3> {
+        if self.pdf_li(DVec3::ZERO, direction) > 0.0 {
+            Some(self.radiance())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn point_light_inverse_square() {
+        let light = PointLight {
+            position: DVec3::new(0.0, 2.0, 0.0),
+            intensity: DVec3::splat(8.0),
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        let sample = light.sample_li(DVec3::ZERO, 0.0, None, &mut rng).unwrap();
+        assert_eq!(sample.direction, DVec3::Y);
+        assert_eq!(sample.distance, 2.0);
+        assert_eq!(sample.radiance, DVec3::splat(2.0));
+        assert!(light.is_delta());
+    }
+
+    #[test]
+    fn spot_light_cone() {
+        let light = SpotLight::new(DVec3::new(0.0, 1.0, 0.0), -DVec3::Y, DVec3::ONE, 30.0, 45.0);
+        let mut rng = StdRng::seed_from_u64(0);
+        let radiance_at = |x: f64, rng: &mut StdRng| {
+            light
+                .sample_li(DVec3::new(x, 0.0, 0.0), 0.0, None, rng)
+                .map_or(0.0, |sample| sample.radiance.x * (1.0 + x * x))
+        };
+
+        // Full intensity inside the falloff angle, fading to nothing at the edge
+        assert!((radiance_at(0.5, &mut rng) - 1.0).abs() < 1e-12);
+        let fading = radiance_at(37.5f64.to_radians().tan(), &mut rng);
+        assert!(fading > 0.0 && fading < 1.0);
+        assert_eq!(radiance_at(1.1, &mut rng), 0.0);
+
+        let profiled = light.with_profile(IntensityProfile::new(&[0.0, 30.0], &[100.0, 50.0]));
+        let sample = profiled
+            .sample_li(
+                DVec3::new(15f64.to_radians().tan(), 0.0, 0.0),
+                0.0,
+                None,
+                &mut rng,
+            )
+            .unwrap();
+        let distance_2 = sample.distance * sample.distance;
+        assert!((sample.radiance.x * distance_2 - 0.75).abs() < 1e-12);
+    }
+
+    #[test]
+    fn sun_disc_irradiance() {
+        let sun = DistantLight::new(DVec3::Y, DVec3::ONE, 2.0);
+        assert!(!sun.is_delta());
+
+        // Estimate the irradiance on a surface facing the sun from its samples
+        let mut rng = StdRng::seed_from_u64(1);
+        let samples = 10_000;
+        let irradiance = (0..samples)
+            .map(|_| {
+                let sample = sun.sample_li(DVec3::ZERO, 0.0, None, &mut rng).unwrap();
+                assert!(sample.direction.dot(DVec3::Y) >= sun.cos_angular_radius - 1e-12);
+                assert_eq!(sun.pdf_li(DVec3::ZERO, sample.direction), sample.pdf);
+                sample.radiance.x * sample.direction.y / sample.pdf
+            })
+            .sum::<f64>()
+            / samples as f64;
+        assert!((irradiance - 1.0).abs() < 1e-3);
+
+        assert_eq!(sun.pdf_li(DVec3::ZERO, -DVec3::Y), 0.0);
+        assert!(DistantLight::new(DVec3::Y, DVec3::ONE, 0.0).is_delta());
+    }
+}
@@ -2,7 +2,10 @@ use std::sync::Arc;
 
 use super::{HitRecord, Ray, SolidColour, Texture};
 use crate::{
-    hit, math::reflect, CosineWeightedHemispherePDF, DielectricFresnelPDF, FuzzyDiracDeltaPDF, PDF,
+    fresnel_dielectric, hash_to_unit, hit, luminance, math::reflect, next_id,
+    pdf::dielectric_half_vector, AlphaMask, ComplexIor, CosineWeightedHemispherePDF,
+    DielectricFresnelPDF, DiracDeltaPDF, Dispersion, GGXDielectricPDF, GGXReflectionPDF,
+    MixtureMethod, MixturePDF, OrthoNormalBasis, RgbSpectrum, Spectrum, TrowbridgeReitz, PDF,
 };
 use glam::DVec3;
 
@@ -13,6 +16,26 @@ pub trait Material: std::fmt::Debug + Send + Sync {
     fn scattering_pdf(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<Box<dyn PDF>>;
     fn brdf(&self, ray_in: &Ray, hit_record: &HitRecord, ray_out: &Ray) -> DVec3;
     fn is_specular(&self) -> bool;
+    /// Perturbed shading normal at `hit_record`, e.g. from a normal or bump map
+    fn shading_normal(&self, _: &HitRecord) -> Option<DVec3> {
+        None
+    }
+    /// Whether `emitted` can be non-zero, surfaces using emissive materials are sampled as lights
+    fn is_emissive(&self) -> bool {
+        false
+    }
+    /// Emitted radiance at `wavelengths`, one per channel, for paths carrying a spectrum
+    fn emitted_spectral(&self, u: f64, v: f64, p: DVec3, wavelengths: DVec3) -> DVec3 {
+        RgbSpectrum::emission(self.emitted(u, v, p)).sample(wavelengths)
+    }
+    /// Whether scattering depends on `Ray::wavelengths`, which integrators then split into one
+    fn is_dispersive(&self) -> bool {
+        false
+    }
+    /// Holes cut in meshes using the material, rays pass through them as if nothing was hit
+    fn alpha_mask(&self) -> Option<&AlphaMask> {
+        None
+    }
 }
 
 #[derive(Debug)]
@@ -46,57 +69,225 @@ impl Material for Lambertian {
     }
 }
 
+/// Rough diffuse surface made of tiny V-shaped Lambertian facets, Oren and Nayar 1994, which
+/// stays brighter towards the light than `Lambertian` on e.g. clay, concrete and fabric
+#[derive(Debug)]
+pub struct OrenNayar {
+    pub albedo: Arc<dyn Texture>,
+    /// Standard deviation of the facet angles in degrees, from the red channel
+    pub sigma: Arc<dyn Texture>,
+}
+
+impl OrenNayar {
+    pub fn new(colour: DVec3, sigma: f64) -> OrenNayar {
+        OrenNayar {
+            albedo: Arc::new(SolidColour { colour }),
+            sigma: constant(sigma),
+        }
+    }
+}
+
+impl Material for OrenNayar {
+    fn scattering_pdf(&self, _: &Ray, hit_record: &HitRecord) -> Option<Box<dyn PDF>> {
+        Some(Box::new(CosineWeightedHemispherePDF::new(
+            hit_record.normal,
+        )))
+    }
+
+    fn brdf(&self, ray_in: &Ray, hit_record: &HitRecord, ray_out: &Ray) -> DVec3 {
+        let basis = OrthoNormalBasis::from_w(&hit_record.normal);
+        let wo = basis.to_local(&-ray_in.dir.normalize());
+        let wi = basis.to_local(&ray_out.dir.normalize());
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return DVec3::ZERO;
+        }
+
+        let (u, v, p) = (hit_record.u, hit_record.v, hit_record.point);
+        let sigma = self.sigma.sample(u, v, p).x.max(0.0).to_radians();
+        let sigma_2 = sigma * sigma;
+        let a = 1.0 - sigma_2 / (2.0 * (sigma_2 + 0.33));
+        let b = 0.45 * sigma_2 / (sigma_2 + 0.09);
+
+        let sin_theta_i = (1.0 - wi.z * wi.z).max(0.0).sqrt();
+        let sin_theta_o = (1.0 - wo.z * wo.z).max(0.0).sqrt();
+        // Facets only brighten light scattered back towards where it came from
+        let cos_delta_phi = if sin_theta_i > 1e-6 && sin_theta_o > 1e-6 {
+            ((wi.x * wo.x + wi.y * wo.y) / (sin_theta_i * sin_theta_o)).max(0.0)
+        } else {
+            0.0
+        };
+        let (sin_alpha, tan_beta) = if wi.z > wo.z {
+            (sin_theta_o, sin_theta_i / wi.z)
+        } else {
+            (sin_theta_i, sin_theta_o / wo.z)
+        };
+
+        self.albedo.sample(u, v, p) / std::f64::consts::PI
+            * (a + b * cos_delta_phi * sin_alpha * tan_beta)
+    }
+
+    fn is_specular(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug)]
+enum ConductorFresnel {
+    /// Schlick's approximation from the reflectance at normal incidence
+    Schlick(DVec3),
+    Exact(ComplexIor),
+}
+
+impl ConductorFresnel {
+    fn evaluate(&self, cos_theta: f64) -> DVec3 {
+        match self {
+            ConductorFresnel::Schlick(f0) => {
+                *f0 + (DVec3::ONE - *f0) * (1.0 - cos_theta.clamp(0.0, 1.0)).powi(5)
+            }
+            ConductorFresnel::Exact(ior) => ior.fresnel(cos_theta),
+        }
+    }
+}
+
+/// Conductor with a GGX microfacet surface, perfectly specular when its roughness is zero
 #[derive(Debug)]
 pub struct Metal {
-    albedo: DVec3,
-    fuzz: f64,
+    fresnel: ConductorFresnel,
+    distribution: TrowbridgeReitz,
 }
 
 impl Metal {
+    /// Tinted by `albedo` at normal incidence, with `fuzz` as its roughness
     pub fn new(albedo: DVec3, fuzz: f64) -> Metal {
         Metal {
-            albedo,
-            fuzz: if fuzz > 1.0 { 1.0 } else { fuzz },
+            fresnel: ConductorFresnel::Schlick(albedo),
+            distribution: TrowbridgeReitz::from_roughness(fuzz, fuzz),
+        }
+    }
+
+    /// A real metal, e.g. `ComplexIor::gold()`
+    pub fn conductor(ior: ComplexIor, roughness: f64) -> Metal {
+        Metal {
+            fresnel: ConductorFresnel::Exact(ior),
+            distribution: TrowbridgeReitz::from_roughness(roughness, roughness),
         }
     }
+
+    /// Brushed metal, rougher along the surface's `dpdu` or `dpdv`
+    pub fn with_anisotropic_roughness(mut self, roughness_u: f64, roughness_v: f64) -> Metal {
+        self.distribution = TrowbridgeReitz::from_roughness(roughness_u, roughness_v);
+        self
+    }
+
+    fn basis(hit_record: &HitRecord) -> OrthoNormalBasis {
+        OrthoNormalBasis::from_w_and_tangent(&hit_record.normal, &hit_record.dpdu)
+    }
 }
 
 impl Material for Metal {
     fn brdf(&self, ray_in: &Ray, hit_record: &HitRecord, ray_out: &Ray) -> DVec3 {
-        let cosine = ray_out.dir.dot(hit_record.normal);
+        let basis = Metal::basis(hit_record);
+        let wo = basis.to_local(&-ray_in.dir.normalize());
+        let wi = basis.to_local(&ray_out.dir.normalize());
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return DVec3::ZERO;
+        }
 
-        self.albedo / cosine // Divide by cos(theta) because we need to cancel out the cos(theta) from the rendering equation
+        if self.distribution.is_smooth() {
+            // Divide by cos(theta) to cancel the one the integrator applies to a delta sample
+            return self.fresnel.evaluate(wo.z) / wi.z;
+        }
+
+        let wm = (wo + wi).normalize();
+        self.fresnel.evaluate(wo.dot(wm)) * self.distribution.d(wm) * self.distribution.g(wo, wi)
+            / (4.0 * wo.z * wi.z)
     }
 
     fn scattering_pdf(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<Box<dyn PDF>> {
-        let reflected = reflect(ray_in.dir.normalize(), hit_record.normal);
-        Some(Box::new(FuzzyDiracDeltaPDF::new(reflected, self.fuzz))) // Fuzziness makes this incorrect btw, no longer a dirac delta PDF
+        if self.distribution.is_smooth() {
+            let reflected = reflect(ray_in.dir.normalize(), hit_record.normal);
+            return Some(Box::new(DiracDeltaPDF::new(reflected)));
+        }
+
+        let basis = Metal::basis(hit_record);
+        let wo = basis.to_local(&-ray_in.dir.normalize());
+        Some(Box::new(GGXReflectionPDF::new(
+            basis,
+            wo,
+            self.distribution,
+        )))
     }
 
     fn is_specular(&self) -> bool {
-        true
+        self.distribution.is_smooth()
     }
 }
 
 #[derive(Debug)]
 pub struct Dielectric {
     pub ior: f64,
+    /// Fraction of light absorbed per unit distance inside, per channel
+    pub absorption: DVec3,
+    /// A thin sheet, e.g. a window or soap bubble, which light passes straight through
+    pub thin_walled: bool,
+    /// Wavelength-dependent IOR used instead of `ior` for rays with a wavelength
+    pub dispersion: Option<Dispersion>,
 }
 
-/// Schlick's approximation
-pub fn reflectance(cosine: f64, ref_idx: f64) -> f64 {
-    let r0 = (1.0 - ref_idx) / (1.0 + ref_idx);
-    let r0 = r0 * r0;
+impl Dielectric {
+    pub fn new(ior: f64) -> Dielectric {
+        Dielectric {
+            ior,
+            absorption: DVec3::ZERO,
+            thin_walled: false,
+            dispersion: None,
+        }
+    }
+
+    /// Tints light to `transmittance` after travelling `distance` inside, following
+    /// Beer-Lambert's law
+    pub fn with_absorption(mut self, transmittance: DVec3, distance: f64) -> Dielectric {
+        self.absorption = DVec3::new(
+            -transmittance.x.ln(),
+            -transmittance.y.ln(),
+            -transmittance.z.ln(),
+        ) / distance;
+        self
+    }
+
+    pub fn thin_walled(mut self) -> Dielectric {
+        self.thin_walled = true;
+        self
+    }
 
-    r0 + ((1.0 - r0) * (1.0 - cosine).powi(5))
+    /// Splits white light into a spectrum, e.g. `Dispersion::diamond()`
+    pub fn with_dispersion(mut self, dispersion: Dispersion) -> Dielectric {
+        self.dispersion = Some(dispersion);
+        self
+    }
+
+    fn ior(&self, ray: &Ray) -> f64 {
+        match (self.dispersion, ray.wavelengths) {
+            (Some(dispersion), Some(wavelengths)) => dispersion.ior(wavelengths.x),
+            _ => self.ior,
+        }
+    }
 }
 
 impl Material for Dielectric {
     fn scattering_pdf(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<Box<dyn PDF>> {
+        if self.thin_walled {
+            return Some(Box::new(DielectricFresnelPDF::thin_walled(
+                ray_in,
+                hit_record.normal,
+                1.0 / self.ior(ray_in),
+            )));
+        }
+
         let ior = if hit_record.front_face {
-            1.0 / self.ior
+            1.0 / self.ior(ray_in)
         } else {
-            self.ior
+            self.ior(ray_in)
         };
         Some(Box::new(DielectricFresnelPDF::new(
             ray_in,
@@ -108,12 +299,671 @@ impl Material for Dielectric {
     fn brdf(&self, ray_in: &Ray, hit_record: &HitRecord, ray_out: &Ray) -> DVec3 {
         let cosine = ray_out.dir.dot(hit_record.normal);
 
-        DVec3::ONE / cosine // Divide by cos(theta) because we need to cancel out the cos(theta) from the rendering equation
+        // Rays hitting the inside of the surface travelled through the medium to get here
+        let transmittance = if hit_record.front_face || self.thin_walled {
+            DVec3::ONE
+        } else {
+            let distance = hit_record.t * ray_in.dir.length();
+            let optical_depth = self.absorption * -distance;
+            DVec3::new(
+                optical_depth.x.exp(),
+                optical_depth.y.exp(),
+                optical_depth.z.exp(),
+            )
+        };
+
+        transmittance / cosine // Divide by cos(theta) because we need to cancel out the cos(theta) from the rendering equation
     }
 
     fn is_specular(&self) -> bool {
         true
     }
+
+    fn is_dispersive(&self) -> bool {
+        // Thin sheets barely separate colours
+        self.dispersion.is_some() && !self.thin_walled
+    }
+}
+
+/// Glass with a GGX microfacet surface, e.g. frosted or etched, perfectly smooth where its
+/// roughness is zero
+#[derive(Debug)]
+pub struct RoughDielectric {
+    pub ior: f64,
+    /// Perceptual roughness in [0, 1] from the red channel
+    pub roughness: Arc<dyn Texture>,
+}
+
+impl RoughDielectric {
+    pub fn new(ior: f64, roughness: f64) -> RoughDielectric {
+        RoughDielectric {
+            ior,
+            roughness: Arc::new(SolidColour {
+                colour: DVec3::splat(roughness),
+            }),
+        }
+    }
+
+    fn distribution(&self, hit_record: &HitRecord) -> TrowbridgeReitz {
+        let roughness = self
+            .roughness
+            .sample(hit_record.u, hit_record.v, hit_record.point)
+            .x;
+        TrowbridgeReitz::from_roughness(roughness, roughness)
+    }
+
+    /// IOR on the far side of the surface from the ray over the IOR on its side
+    fn eta(&self, hit_record: &HitRecord) -> f64 {
+        if hit_record.front_face {
+            self.ior
+        } else {
+            1.0 / self.ior
+        }
+    }
+}
+
+impl Material for RoughDielectric {
+    fn scattering_pdf(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<Box<dyn PDF>> {
+        let distribution = self.distribution(hit_record);
+        let eta = self.eta(hit_record);
+        if distribution.is_smooth() {
+            return Some(Box::new(DielectricFresnelPDF::new(
+                ray_in,
+                hit_record.normal,
+                1.0 / eta,
+            )));
+        }
+
+        let basis = OrthoNormalBasis::from_w(&hit_record.normal);
+        let wo = basis.to_local(&-ray_in.dir.normalize());
+        Some(Box::new(GGXDielectricPDF::new(
+            basis,
+            wo,
+            distribution,
+            eta,
+        )))
+    }
+
+    fn brdf(&self, ray_in: &Ray, hit_record: &HitRecord, ray_out: &Ray) -> DVec3 {
+        let distribution = self.distribution(hit_record);
+        if distribution.is_smooth() {
+            let cosine = ray_out.dir.dot(hit_record.normal);
+            return DVec3::ONE / cosine;
+        }
+
+        let basis = OrthoNormalBasis::from_w(&hit_record.normal);
+        let wo = basis.to_local(&-ray_in.dir.normalize());
+        let wi = basis.to_local(&ray_out.dir.normalize());
+        DVec3::splat(rough_dielectric_bsdf(
+            &distribution,
+            wo,
+            wi,
+            self.eta(hit_record),
+        ))
+    }
+
+    fn is_specular(&self) -> bool {
+        false
+    }
+}
+
+/// GGX reflection and transmission of local `wo` into `wi` across an interface with relative
+/// IOR `eta`
+fn rough_dielectric_bsdf(distribution: &TrowbridgeReitz, wo: DVec3, wi: DVec3, eta: f64) -> f64 {
+    let wm = match dielectric_half_vector(wo, wi, eta) {
+        Some(wm) => wm,
+        None => return 0.0,
+    };
+
+    let reflectance = fresnel_dielectric(wo.dot(wm), eta);
+    let d = distribution.d(wm);
+    let g = distribution.g(wo, wi);
+    if wi.z > 0.0 {
+        d * g * reflectance / (4.0 * wo.z * wi.z).abs()
+    } else {
+        let denominator = wi.dot(wm) + wo.dot(wm) / eta;
+        let transmitted = d
+            * g
+            * (1.0 - reflectance)
+            * (wi.dot(wm) * wo.dot(wm) / (denominator * denominator * wi.z * wo.z)).abs();
+        // Radiance is compressed into the smaller solid angle of the denser medium
+        transmitted / (eta * eta)
+    }
+}
+
+/// Microfacet lobes mixed with others are never perfect mirrors, as delta lobes can't be mixed
+const MIN_LOBE_ALPHA: f64 = 1e-3;
+
+/// Disney's principled BSDF, a single material blending diffuse, metal, glass and a clear coat
+/// from artist-friendly parameters in [0, 1]. Scalar parameters come from the red channel of
+/// their texture.
+#[derive(Debug)]
+pub struct PrincipledBSDF {
+    pub base_colour: Arc<dyn Texture>,
+    pub metallic: Arc<dyn Texture>,
+    pub roughness: Arc<dyn Texture>,
+    /// Dielectric reflectance at normal incidence, where 0.5 is the 4% of most plastics
+    pub specular: Arc<dyn Texture>,
+    /// Tints dielectric reflections towards the base colour
+    pub specular_tint: Arc<dyn Texture>,
+    /// Soft rim at grazing angles, for cloth
+    pub sheen: Arc<dyn Texture>,
+    /// White glossy layer on top, e.g. car paint
+    pub clearcoat: Arc<dyn Texture>,
+    /// Turns the dielectric parts into rough glass tinted by the base colour
+    pub transmission: Arc<dyn Texture>,
+    /// Stretches highlights along the surface's `dpdu`
+    pub anisotropic: Arc<dyn Texture>,
+    /// IOR of the glass when transmissive
+    pub ior: f64,
+}
+
+/// Parameters of a `PrincipledBSDF` at a hit, turned into weights and colours of its lobes
+struct PrincipledLobes {
+    base_colour: DVec3,
+    sheen: DVec3,
+    /// Reflectance at normal incidence of the specular lobe
+    specular_f0: DVec3,
+    roughness: f64,
+    distribution: TrowbridgeReitz,
+    diffuse_weight: f64,
+    specular_weight: f64,
+    glass_weight: f64,
+    clearcoat_weight: f64,
+}
+
+fn constant(value: f64) -> Arc<dyn Texture> {
+    Arc::new(SolidColour {
+        colour: DVec3::splat(value),
+    })
+}
+
+fn schlick_weight(cos_theta: f64) -> f64 {
+    (1.0 - cos_theta.clamp(0.0, 1.0)).powi(5)
+}
+
+impl PrincipledBSDF {
+    /// Rough white plastic in the given colour
+    pub fn new(base_colour: DVec3) -> PrincipledBSDF {
+        PrincipledBSDF {
+            base_colour: Arc::new(SolidColour {
+                colour: base_colour,
+            }),
+            metallic: constant(0.0),
+            roughness: constant(0.5),
+            specular: constant(0.5),
+            specular_tint: constant(0.0),
+            sheen: constant(0.0),
+            clearcoat: constant(0.0),
+            transmission: constant(0.0),
+            anisotropic: constant(0.0),
+            ior: 1.5,
+        }
+    }
+
+    pub fn with_metallic(mut self, metallic: f64) -> PrincipledBSDF {
+        self.metallic = constant(metallic);
+        self
+    }
+
+    pub fn with_roughness(mut self, roughness: f64) -> PrincipledBSDF {
+        self.roughness = constant(roughness);
+        self
+    }
+
+    pub fn with_specular(mut self, specular: f64, specular_tint: f64) -> PrincipledBSDF {
+        self.specular = constant(specular);
+        self.specular_tint = constant(specular_tint);
+        self
+    }
+
+    pub fn with_sheen(mut self, sheen: f64) -> PrincipledBSDF {
+        self.sheen = constant(sheen);
+        self
+    }
+
+    pub fn with_clearcoat(mut self, clearcoat: f64) -> PrincipledBSDF {
+        self.clearcoat = constant(clearcoat);
+        self
+    }
+
+    pub fn with_transmission(mut self, transmission: f64, ior: f64) -> PrincipledBSDF {
+        self.transmission = constant(transmission);
+        self.ior = ior;
+        self
+    }
+
+    pub fn with_anisotropic(mut self, anisotropic: f64) -> PrincipledBSDF {
+        self.anisotropic = constant(anisotropic);
+        self
+    }
+
+    fn lobes(&self, hit_record: &HitRecord) -> PrincipledLobes {
+        let sample = |texture: &Arc<dyn Texture>| {
+            texture.sample(hit_record.u, hit_record.v, hit_record.point)
+        };
+        let scalar = |texture: &Arc<dyn Texture>| sample(texture).x.clamp(0.0, 1.0);
+
+        let base_colour = sample(&self.base_colour);
+        let metallic = scalar(&self.metallic);
+        let roughness = scalar(&self.roughness);
+        let transmission = scalar(&self.transmission);
+
+        // Hue and saturation of the base colour without its brightness
+        let tint = if luminance(base_colour) > 0.0 {
+            base_colour / luminance(base_colour)
+        } else {
+            DVec3::ONE
+        };
+        let dielectric_f0 =
+            0.08 * scalar(&self.specular) * DVec3::ONE.lerp(tint, scalar(&self.specular_tint));
+
+        let aspect = (1.0 - 0.9 * scalar(&self.anisotropic)).sqrt();
+        let alpha = roughness * roughness;
+        let distribution = TrowbridgeReitz {
+            alpha_x: (alpha / aspect).max(MIN_LOBE_ALPHA),
+            alpha_y: (alpha * aspect).max(MIN_LOBE_ALPHA),
+        };
+
+        let glass_weight = (1.0 - metallic) * transmission;
+        PrincipledLobes {
+            base_colour,
+            sheen: scalar(&self.sheen) * DVec3::ONE.lerp(tint, 0.5),
+            specular_f0: dielectric_f0.lerp(base_colour, metallic),
+            roughness,
+            distribution,
+            diffuse_weight: (1.0 - metallic) * (1.0 - transmission),
+            specular_weight: 1.0 - glass_weight,
+            glass_weight,
+            clearcoat_weight: 0.25 * scalar(&self.clearcoat),
+        }
+    }
+
+    fn clearcoat_distribution() -> TrowbridgeReitz {
+        TrowbridgeReitz::from_roughness(0.2, 0.2)
+    }
+
+    /// IOR on the far side of the surface from the ray over the IOR on its side
+    fn eta(&self, hit_record: &HitRecord) -> f64 {
+        if hit_record.front_face {
+            self.ior
+        } else {
+            1.0 / self.ior
+        }
+    }
+}
+
+impl Material for PrincipledBSDF {
+    fn brdf(&self, ray_in: &Ray, hit_record: &HitRecord, ray_out: &Ray) -> DVec3 {
+        let lobes = self.lobes(hit_record);
+        let basis = Metal::basis(hit_record);
+        let wo = basis.to_local(&-ray_in.dir.normalize());
+        let wi = basis.to_local(&ray_out.dir.normalize());
+        if wo.z <= 0.0 || wi.z == 0.0 {
+            return DVec3::ZERO;
+        }
+
+        let mut f = DVec3::ZERO;
+        if wi.z > 0.0 {
+            let wm = (wo + wi).normalize();
+            let cos_theta_d = wi.dot(wm);
+
+            // Retroreflection at grazing angles on rough surfaces
+            let fd90 = 0.5 + 2.0 * lobes.roughness * cos_theta_d * cos_theta_d;
+            let diffuse = lobes.base_colour / std::f64::consts::PI
+                * (1.0 + (fd90 - 1.0) * schlick_weight(wi.z))
+                * (1.0 + (fd90 - 1.0) * schlick_weight(wo.z))
+                + lobes.sheen * schlick_weight(cos_theta_d);
+            f += diffuse * lobes.diffuse_weight;
+
+            let distribution = lobes.distribution;
+            let fresnel =
+                lobes.specular_f0 + (DVec3::ONE - lobes.specular_f0) * schlick_weight(wo.dot(wm));
+            f += fresnel * distribution.d(wm) * distribution.g(wo, wi) / (4.0 * wo.z * wi.z)
+                * lobes.specular_weight;
+
+            let clearcoat = PrincipledBSDF::clearcoat_distribution();
+            let fresnel = 0.04 + 0.96 * schlick_weight(wo.dot(wm));
+            f += DVec3::splat(
+                fresnel * clearcoat.d(wm) * clearcoat.g(wo, wi) / (4.0 * wo.z * wi.z)
+                    * lobes.clearcoat_weight,
+            );
+        }
+
+        if lobes.glass_weight > 0.0 {
+            let glass = rough_dielectric_bsdf(&lobes.distribution, wo, wi, self.eta(hit_record));
+            // Tinted on the way in and again on the way out
+            let tint = if wi.z < 0.0 {
+                DVec3::new(
+                    lobes.base_colour.x.sqrt(),
+                    lobes.base_colour.y.sqrt(),
+                    lobes.base_colour.z.sqrt(),
+                )
+            } else {
+                DVec3::ONE
+            };
+            f += tint * glass * lobes.glass_weight;
+        }
+        f
+    }
+
+    fn scattering_pdf(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<Box<dyn PDF>> {
+        let lobes = self.lobes(hit_record);
+        let basis = Metal::basis(hit_record);
+        let wo = basis.to_local(&-ray_in.dir.normalize());
+
+        // Sample lobes in proportion to roughly how much light each reflects
+        let specular_reflectance =
+            luminance(lobes.specular_f0 + (DVec3::ONE - lobes.specular_f0) * schlick_weight(wo.z));
+        let clearcoat_reflectance = 0.04 + 0.96 * schlick_weight(wo.z);
+        let weights = vec![
+            lobes.diffuse_weight * luminance(lobes.base_colour + lobes.sheen).max(0.01),
+            lobes.specular_weight * specular_reflectance.max(0.01),
+            lobes.glass_weight,
+            lobes.clearcoat_weight * clearcoat_reflectance,
+        ];
+        let pdfs: Vec<Box<dyn PDF>> = vec![
+            Box::new(CosineWeightedHemispherePDF::new(hit_record.normal)),
+            Box::new(GGXReflectionPDF::new(basis, wo, lobes.distribution)),
+            Box::new(GGXDielectricPDF::new(
+                basis,
+                wo,
+                lobes.distribution,
+                self.eta(hit_record),
+            )),
+            Box::new(GGXReflectionPDF::new(
+                basis,
+                wo,
+                PrincipledBSDF::clearcoat_distribution(),
+            )),
+        ];
+        Some(Box::new(MixturePDF::new(
+            pdfs,
+            MixtureMethod::Weighted(weights),
+        )))
+    }
+
+    fn is_specular(&self) -> bool {
+        false
+    }
+}
+
+/// Random number in [0, 1) fixed for a material's `seed` at the hit of `ray`, so that its `brdf`
+/// and `scattering_pdf` make the same random choice
+fn hit_random(seed: u64, ray: &Ray, hit_record: &HitRecord) -> f64 {
+    // Nested materials choose independently
+    hash_to_unit(seed, &[hit_record.point, ray.dir])
+}
+
+/// How a `MixMaterial` combines its materials
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MixMode {
+    /// Blends both brdfs and samples directions from both. Picks one material at a time
+    /// instead when either is specular, as their delta distributions can't be blended.
+    Weighted,
+    /// Picks one material per hit, in proportion to the mask
+    Stochastic,
+}
+
+/// Blends two materials by a mask, e.g. rust patches on metal or dirt on tiles
+#[derive(Debug)]
+pub struct MixMaterial {
+    pub first: Arc<dyn Material>,
+    pub second: Arc<dyn Material>,
+    /// Amount of `second` in [0, 1], from the red channel
+    pub mask: Arc<dyn Texture>,
+    pub mode: MixMode,
+    /// Seeds the stochastic choice of material, from `next_id` unless set by hand
+    pub seed: u64,
+}
+
+impl MixMaterial {
+    pub fn new(first: Arc<dyn Material>, second: Arc<dyn Material>, amount: f64) -> MixMaterial {
+        MixMaterial {
+            first,
+            second,
+            mask: constant(amount),
+            mode: MixMode::Weighted,
+            seed: next_id(),
+        }
+    }
+
+    pub fn with_mask(mut self, mask: Arc<dyn Texture>) -> MixMaterial {
+        self.mask = mask;
+        self
+    }
+
+    pub fn stochastic(mut self) -> MixMaterial {
+        self.mode = MixMode::Stochastic;
+        self
+    }
+
+    fn amount(&self, u: f64, v: f64, p: DVec3) -> f64 {
+        self.mask.sample(u, v, p).x.clamp(0.0, 1.0)
+    }
+
+    /// The material to use alone at this hit, or `None` to blend both
+    fn choose(&self, ray: &Ray, hit_record: &HitRecord) -> Option<&dyn Material> {
+        let amount = self.amount(hit_record.u, hit_record.v, hit_record.point);
+        let blend = self.mode == MixMode::Weighted
+            && !self.first.is_specular()
+            && !self.second.is_specular();
+        if amount == 0.0 {
+            Some(self.first.as_ref())
+        } else if amount == 1.0 {
+            Some(self.second.as_ref())
+        } else if blend {
+            None
+        } else if hit_random(self.seed, ray, hit_record) < amount {
+            Some(self.second.as_ref())
+        } else {
+            Some(self.first.as_ref())
+        }
+    }
+}
+
+impl Material for MixMaterial {
+    fn emitted(&self, u: f64, v: f64, p: DVec3) -> DVec3 {
+        self.first
+            .emitted(u, v, p)
+            .lerp(self.second.emitted(u, v, p), self.amount(u, v, p))
+    }
+
+    fn emitted_spectral(&self, u: f64, v: f64, p: DVec3, wavelengths: DVec3) -> DVec3 {
+        self.first.emitted_spectral(u, v, p, wavelengths).lerp(
+            self.second.emitted_spectral(u, v, p, wavelengths),
+            self.amount(u, v, p),
+        )
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.first.is_emissive() || self.second.is_emissive()
+    }
+
+    fn brdf(&self, ray_in: &Ray, hit_record: &HitRecord, ray_out: &Ray) -> DVec3 {
+        if let Some(material) = self.choose(ray_in, hit_record) {
+            return material.brdf(ray_in, hit_record, ray_out);
+        }
+        let amount = self.amount(hit_record.u, hit_record.v, hit_record.point);
+        self.first
+            .brdf(ray_in, hit_record, ray_out)
+            .lerp(self.second.brdf(ray_in, hit_record, ray_out), amount)
+    }
+
+    fn scattering_pdf(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<Box<dyn PDF>> {
+        if let Some(material) = self.choose(ray_in, hit_record) {
+            return material.scattering_pdf(ray_in, hit_record);
+        }
+        let amount = self.amount(hit_record.u, hit_record.v, hit_record.point);
+        match (
+            self.first.scattering_pdf(ray_in, hit_record),
+            self.second.scattering_pdf(ray_in, hit_record),
+        ) {
+            (Some(first), Some(second)) => Some(Box::new(MixturePDF::new(
+                vec![first, second],
+                MixtureMethod::Weighted(vec![1.0 - amount, amount]),
+            ))),
+            // The other material scatters nothing, so its brdf is zero
+            (first, second) => first.or(second),
+        }
+    }
+
+    fn is_specular(&self) -> bool {
+        self.first.is_specular() && self.second.is_specular()
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.first.is_dispersive() || self.second.is_dispersive()
+    }
+
+    /// `first`'s shading normal, or `second`'s if it has none
+    fn shading_normal(&self, hit_record: &HitRecord) -> Option<DVec3> {
+        self.first
+            .shading_normal(hit_record)
+            .or_else(|| self.second.shading_normal(hit_record))
+    }
+
+    /// `first`'s mask, or `second`'s if it has none
+    fn alpha_mask(&self) -> Option<&AlphaMask> {
+        self.first.alpha_mask().or_else(|| self.second.alpha_mask())
+    }
+}
+
+/// A clear dielectric coat over another material, e.g. varnished wood or lacquered metal. The
+/// base only receives the light the coat lets through, and what it scatters has to leave through
+/// the coat again, so the layers never reflect more than they receive.
+#[derive(Debug)]
+pub struct CoatedMaterial {
+    pub base: Arc<dyn Material>,
+    pub ior: f64,
+    /// Perceptual roughness of the coat in [0, 1], from the red channel
+    pub roughness: Arc<dyn Texture>,
+    /// Seeds the choice between the coat and a specular base, from `next_id` unless set by hand
+    pub seed: u64,
+}
+
+impl CoatedMaterial {
+    pub fn new(base: Arc<dyn Material>, ior: f64, roughness: f64) -> CoatedMaterial {
+        CoatedMaterial {
+            base,
+            ior,
+            roughness: constant(roughness),
+            seed: next_id(),
+        }
+    }
+
+    fn distribution(&self, hit_record: &HitRecord) -> TrowbridgeReitz {
+        let roughness = self
+            .roughness
+            .sample(hit_record.u, hit_record.v, hit_record.point)
+            .x
+            .clamp(0.0, 1.0);
+        let alpha = (roughness * roughness).max(MIN_LOBE_ALPHA);
+        TrowbridgeReitz {
+            alpha_x: alpha,
+            alpha_y: alpha,
+        }
+    }
+
+    /// Chance of sampling the coat rather than the base
+    fn coat_probability(&self, wo: DVec3) -> f64 {
+        fresnel_dielectric(wo.z, self.ior).clamp(0.1, 0.9)
+    }
+
+    /// Whether the coat and a specular base are sampled one at a time, and the coat's turn
+    fn coat_chosen(&self, ray_in: &Ray, hit_record: &HitRecord, wo: DVec3) -> Option<bool> {
+        if self.base.is_specular() {
+            Some(hit_random(self.seed, ray_in, hit_record) < self.coat_probability(wo))
+        } else {
+            None
+        }
+    }
+}
+
+impl Material for CoatedMaterial {
+    fn brdf(&self, ray_in: &Ray, hit_record: &HitRecord, ray_out: &Ray) -> DVec3 {
+        let basis = OrthoNormalBasis::from_w(&hit_record.normal);
+        let wo = basis.to_local(&-ray_in.dir.normalize());
+        let wi = basis.to_local(&ray_out.dir.normalize());
+        if wo.z <= 0.0 || wi.z == 0.0 {
+            return DVec3::ZERO;
+        }
+
+        let coat = if wi.z > 0.0 {
+            let distribution = self.distribution(hit_record);
+            let wm = (wo + wi).normalize();
+            DVec3::splat(
+                fresnel_dielectric(wo.dot(wm), self.ior) * distribution.d(wm) / (4.0 * wo.z * wi.z)
+                    * distribution.g(wo, wi),
+            )
+        } else {
+            DVec3::ZERO
+        };
+        let base = self.base.brdf(ray_in, hit_record, ray_out)
+            * (1.0 - fresnel_dielectric(wo.z, self.ior))
+            * (1.0 - fresnel_dielectric(wi.z.abs(), self.ior));
+
+        match self.coat_chosen(ray_in, hit_record, wo) {
+            None => coat + base,
+            Some(true) => coat / self.coat_probability(wo),
+            Some(false) => base / (1.0 - self.coat_probability(wo)),
+        }
+    }
+
+    fn scattering_pdf(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<Box<dyn PDF>> {
+        let basis = OrthoNormalBasis::from_w(&hit_record.normal);
+        let wo = basis.to_local(&-ray_in.dir.normalize());
+        let chosen = self.coat_chosen(ray_in, hit_record, wo);
+        let coat_pdf = Box::new(GGXReflectionPDF::new(
+            basis,
+            wo,
+            self.distribution(hit_record),
+        ));
+        if chosen == Some(true) {
+            return Some(coat_pdf);
+        }
+
+        let base_pdf = match self.base.scattering_pdf(ray_in, hit_record) {
+            Some(base_pdf) => base_pdf,
+            None => return Some(coat_pdf),
+        };
+        if chosen == Some(false) {
+            return Some(base_pdf);
+        }
+        let coat_probability = self.coat_probability(wo);
+        Some(Box::new(MixturePDF::new(
+            vec![coat_pdf, base_pdf],
+            MixtureMethod::Weighted(vec![coat_probability, 1.0 - coat_probability]),
+        )))
+    }
+
+    fn emitted(&self, u: f64, v: f64, p: DVec3) -> DVec3 {
+        self.base.emitted(u, v, p)
+    }
+
+    fn emitted_spectral(&self, u: f64, v: f64, p: DVec3, wavelengths: DVec3) -> DVec3 {
+        self.base.emitted_spectral(u, v, p, wavelengths)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.base.is_emissive()
+    }
+
+    fn is_specular(&self) -> bool {
+        false
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.base.is_dispersive()
+    }
+
+    fn shading_normal(&self, hit_record: &HitRecord) -> Option<DVec3> {
+        self.base.shading_normal(hit_record)
+    }
+
+    fn alpha_mask(&self) -> Option<&AlphaMask> {
+        self.base.alpha_mask()
+    }
 }
 
 #[derive(Debug)]
@@ -126,6 +976,10 @@ impl Material for DiffuseLight {
         self.emit_colour.sample(u, v, p)
     }
 
+    fn is_emissive(&self) -> bool {
+        true
+    }
+
     fn brdf(&self, ray_in: &Ray, hit_record: &HitRecord, ray_out: &Ray) -> DVec3 {
         DVec3::ZERO
     }
@@ -138,3 +992,567 @@ impl Material for DiffuseLight {
         true // Not actually, but it's helpful to tell the integrator not to look at the PDF
     }
 }
+
+/// Emits light with a measured or physical spectrum, e.g. a blackbody, which spectral rendering
+/// traces as is and RGB rendering sees as its colour
+#[derive(Debug)]
+pub struct SpectralLight {
+    spectrum: Arc<dyn Spectrum>,
+    intensity: f64,
+    colour: DVec3,
+}
+
+impl SpectralLight {
+    pub fn new(spectrum: Arc<dyn Spectrum>, intensity: f64) -> SpectralLight {
+        SpectralLight {
+            colour: spectrum.to_rgb() * intensity,
+            spectrum,
+            intensity,
+        }
+    }
+}
+
+impl Material for SpectralLight {
+    fn emitted(&self, _: f64, _: f64, _: DVec3) -> DVec3 {
+        self.colour
+    }
+
+    fn emitted_spectral(&self, _: f64, _: f64, _: DVec3, wavelengths: DVec3) -> DVec3 {
+        self.spectrum.sample(wavelengths) * self.intensity
+    }
+
+    fn is_emissive(&self) -> bool {
+        true
+    }
+
+    fn brdf(&self, _: &Ray, _: &HitRecord, _: &Ray) -> DVec3 {
+        DVec3::ZERO
+    }
+
+    fn scattering_pdf(&self, _: &Ray, _: &HitRecord) -> Option<Box<dyn PDF>> {
+        None
+    }
+
+    fn is_specular(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        rand_unit_vector, AARect, AlphaMasked, BlackbodySpectrum, Hittable, NormalMapped,
+        SolidColour, SurfaceDetail, UniformHemispherePDF,
+    };
+
+    struct UniformSphere;
+
+    impl PDF for UniformSphere {
+        fn value(&self, _: DVec3) -> f64 {
+            1.0 / (4.0 * std::f64::consts::PI)
+        }
+
+        fn generate(&self, rng: &mut dyn rand::RngCore) -> DVec3 {
+            rand_unit_vector(rng)
+        }
+    }
+
+    /// Reflected fraction of light arriving along `ray`, estimated by sampling `pdf`
+    fn albedo(
+        material: &dyn Material,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        pdf: &dyn PDF,
+        rng: &mut StdRng,
+    ) -> DVec3 {
+        let samples = 100_000;
+        let mut total = DVec3::ZERO;
+        for _ in 0..samples {
+            let dir = pdf.generate(rng).normalize();
+            let pdf_value = pdf.value(dir);
+            if pdf_value == 0.0 {
+                continue;
+            }
+            let ray_out = Ray {
+                origin: hit_record.point,
+                dir,
+                time: 0.0,
+                wavelengths: None,
+            };
+            total += material.brdf(ray, hit_record, &ray_out) * dir.dot(hit_record.normal).abs()
+                / pdf_value;
+        }
+        total / samples as f64
+    }
+
+    #[test]
+    fn oren_nayar_scatters_back_towards_light() {
+        let mut rng = StdRng::seed_from_u64(12);
+        let colour = DVec3::new(0.8, 0.6, 0.4);
+        let ray = Ray {
+            origin: DVec3::new(1.0, 1.0, 0.0),
+            dir: DVec3::new(-1.0, -1.0, 0.0),
+            time: 0.0,
+            wavelengths: None,
+        };
+        let out = |dir: DVec3| Ray {
+            origin: DVec3::ZERO,
+            dir,
+            time: 0.0,
+            wavelengths: None,
+        };
+
+        // Smooth facets are Lambertian
+        let smooth = OrenNayar::new(colour, 0.0);
+        let hit_record = HitRecord::new(&ray, &DVec3::ZERO, DVec3::Y, &smooth, 1.0, 0.0, 0.0);
+        let lambertian = Lambertian::new(colour);
+        for &dir in &[DVec3::new(1.0, 0.5, 0.0), DVec3::new(-0.2, 1.0, 0.7)] {
+            assert!(
+                (smooth.brdf(&ray, &hit_record, &out(dir))
+                    - lambertian.brdf(&ray, &hit_record, &out(dir)))
+                .abs()
+                .max_element()
+                    < 1e-12
+            );
+        }
+
+        let clay = OrenNayar::new(colour, 30.0);
+        let back = clay.brdf(&ray, &hit_record, &out(DVec3::new(1.0, 0.8, 0.0)));
+        let forward = clay.brdf(&ray, &hit_record, &out(DVec3::new(-1.0, 0.8, 0.0)));
+        assert!(back.x > 1.2 * forward.x);
+
+        let pdf = clay.scattering_pdf(&ray, &hit_record).unwrap();
+        let reflected = albedo(&clay, &ray, &hit_record, pdf.as_ref(), &mut rng);
+        assert!(reflected.x < colour.x && reflected.x > 0.8 * colour.x);
+    }
+
+    #[test]
+    fn rough_metal_sampling_matches_brdf() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let ray = Ray {
+            origin: DVec3::new(1.0, 2.0, 0.5),
+            dir: DVec3::new(-1.0, -2.0, -0.5),
+            time: 0.0,
+            wavelengths: None,
+        };
+
+        for metal in &[
+            Metal::new(DVec3::ONE, 0.6),
+            Metal::conductor(ComplexIor::gold(), 0.5).with_anisotropic_roughness(0.3, 0.8),
+        ] {
+            let hit_record = HitRecord::new(&ray, &DVec3::ZERO, DVec3::Y, metal, 1.0, 0.0, 0.0);
+            let pdf = metal.scattering_pdf(&ray, &hit_record).unwrap();
+            assert!(!pdf.is_delta_distribution());
+
+            let sampled = albedo(metal, &ray, &hit_record, pdf.as_ref(), &mut rng);
+            let uniform = albedo(
+                metal,
+                &ray,
+                &hit_record,
+                &UniformHemispherePDF::new(DVec3::Y),
+                &mut rng,
+            );
+            assert!(sampled.max_element() <= 1.0);
+            assert!(
+                (sampled - uniform).abs().max_element() < 0.03,
+                "{} vs {}",
+                sampled,
+                uniform
+            );
+        }
+
+        let mirror = Metal::new(DVec3::ONE, 0.0);
+        let hit_record = HitRecord::new(&ray, &DVec3::ZERO, DVec3::Y, &mirror, 1.0, 0.0, 0.0);
+        assert!(mirror
+            .scattering_pdf(&ray, &hit_record)
+            .unwrap()
+            .is_delta_distribution());
+    }
+
+    #[test]
+    fn rough_glass_sampling_matches_brdf() {
+        let mut rng = StdRng::seed_from_u64(8);
+        let glass = RoughDielectric::new(1.5, 0.5);
+
+        // Entering and leaving the glass
+        for &dir in &[DVec3::new(0.4, -1.0, 0.2), DVec3::new(0.3, 1.0, -0.1)] {
+            let ray = Ray {
+                origin: -dir,
+                dir,
+                time: 0.0,
+                wavelengths: None,
+            };
+            let hit_record = HitRecord::new(&ray, &DVec3::ZERO, DVec3::Y, &glass, 1.0, 0.0, 0.0);
+            let pdf = glass.scattering_pdf(&ray, &hit_record).unwrap();
+
+            let sampled = albedo(&glass, &ray, &hit_record, pdf.as_ref(), &mut rng);
+            let uniform = albedo(&glass, &ray, &hit_record, &UniformSphere, &mut rng);
+            assert!(
+                (sampled - uniform).abs().max_element() < 0.05 * uniform.max_element(),
+                "{} vs {}",
+                sampled,
+                uniform
+            );
+        }
+    }
+
+    #[test]
+    fn principled_sampling_matches_brdf() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let materials = [
+            PrincipledBSDF::new(DVec3::new(0.8, 0.5, 0.2)).with_sheen(0.7),
+            PrincipledBSDF::new(DVec3::new(0.9, 0.6, 0.3))
+                .with_metallic(0.6)
+                .with_roughness(0.4)
+                .with_anisotropic(0.8)
+                .with_clearcoat(1.0),
+            PrincipledBSDF::new(DVec3::new(0.7, 0.9, 0.8))
+                .with_roughness(0.5)
+                .with_transmission(0.8, 1.5),
+        ];
+
+        // From outside and, for the glass, from inside
+        for &dir in &[DVec3::new(0.4, -1.0, 0.2), DVec3::new(0.3, 1.0, -0.1)] {
+            let ray = Ray {
+                origin: -dir,
+                dir,
+                time: 0.0,
+                wavelengths: None,
+            };
+            for material in &materials {
+                let hit_record =
+                    HitRecord::new(&ray, &DVec3::ZERO, DVec3::Y, material, 1.0, 0.0, 0.0);
+                let pdf = material.scattering_pdf(&ray, &hit_record).unwrap();
+
+                let sampled = albedo(material, &ray, &hit_record, pdf.as_ref(), &mut rng);
+                let uniform = albedo(material, &ray, &hit_record, &UniformSphere, &mut rng);
+                // Light leaving glass is concentrated into a wider solid angle
+                if hit_record.front_face {
+                    assert!(sampled.max_element() <= 1.0, "{}", sampled);
+                }
+                assert!(
+                    (sampled - uniform).abs().max_element() < 0.05 * uniform.max_element(),
+                    "{} vs {}",
+                    sampled,
+                    uniform
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn fully_metallic_principled_is_metal() {
+        let colour = DVec3::new(0.9, 0.6, 0.3);
+        let principled = PrincipledBSDF::new(colour)
+            .with_metallic(1.0)
+            .with_roughness(0.5);
+        let metal = Metal::new(colour, 0.5);
+
+        let ray = Ray {
+            origin: DVec3::new(1.0, 2.0, 0.5),
+            dir: DVec3::new(-1.0, -2.0, -0.5),
+            time: 0.0,
+            wavelengths: None,
+        };
+        let hit_record = HitRecord::new(&ray, &DVec3::ZERO, DVec3::Y, &metal, 1.0, 0.0, 0.0);
+        for &dir in &[DVec3::new(-0.5, 1.0, 0.2), DVec3::new(0.1, 0.3, -1.0)] {
+            let ray_out = Ray {
+                origin: DVec3::ZERO,
+                dir,
+                time: 0.0,
+                wavelengths: None,
+            };
+            let expected = metal.brdf(&ray, &hit_record, &ray_out);
+            assert!(
+                (principled.brdf(&ray, &hit_record, &ray_out) - expected)
+                    .abs()
+                    .max_element()
+                    < 1e-12 * expected.max_element()
+            );
+        }
+    }
+
+    #[test]
+    fn mix_material_blends_or_picks() {
+        let mut rng = StdRng::seed_from_u64(13);
+        let red: Arc<dyn Material> = Arc::new(Lambertian::new(DVec3::new(0.8, 0.1, 0.1)));
+        let steel: Arc<dyn Material> = Arc::new(Metal::new(DVec3::splat(0.9), 0.4));
+        let ray = Ray {
+            origin: DVec3::new(1.0, 2.0, 0.5),
+            dir: DVec3::new(-1.0, -2.0, -0.5),
+            time: 0.0,
+            wavelengths: None,
+        };
+        let ray_out = Ray {
+            origin: DVec3::ZERO,
+            dir: DVec3::new(0.8, 1.5, 0.5),
+            time: 0.0,
+            wavelengths: None,
+        };
+
+        let mix = MixMaterial::new(red.clone(), steel.clone(), 0.3);
+        let hit_record = HitRecord::new(&ray, &DVec3::ZERO, DVec3::Y, &mix, 1.0, 0.0, 0.0);
+        let blended = red
+            .brdf(&ray, &hit_record, &ray_out)
+            .lerp(steel.brdf(&ray, &hit_record, &ray_out), 0.3);
+        assert!(
+            (mix.brdf(&ray, &hit_record, &ray_out) - blended)
+                .abs()
+                .max_element()
+                < 1e-12
+        );
+
+        let pdf = mix.scattering_pdf(&ray, &hit_record).unwrap();
+        let sampled = albedo(&mix, &ray, &hit_record, pdf.as_ref(), &mut rng);
+        let uniform = albedo(
+            &mix,
+            &ray,
+            &hit_record,
+            &UniformHemispherePDF::new(DVec3::Y),
+            &mut rng,
+        );
+        assert!((sampled - uniform).abs().max_element() < 0.03);
+
+        // Picking one material per hit averages out to the blend
+        let stochastic = MixMaterial::new(red, steel, 0.3).stochastic();
+        let hits = 20_000;
+        let average = (0..hits).fold(DVec3::ZERO, |sum, i| {
+            let point = DVec3::new(i as f64 * 1e-3, 0.0, 0.0);
+            let hit_record = HitRecord::new(&ray, &point, DVec3::Y, &stochastic, 1.0, 0.0, 0.0);
+            // The same choice for the brdf and sampling
+            let chosen_pdf = stochastic.scattering_pdf(&ray, &hit_record).unwrap();
+            let brdf = stochastic.brdf(&ray, &hit_record, &ray_out);
+            assert_eq!(
+                chosen_pdf.value(ray_out.dir)
+                    == CosineWeightedHemispherePDF::new(DVec3::Y).value(ray_out.dir),
+                brdf == DVec3::new(0.8, 0.1, 0.1) / std::f64::consts::PI
+            );
+            sum + brdf
+        }) / hits as f64;
+        assert!((average - blended).abs().max_element() < 0.01 * blended.max_element());
+
+        // Specular materials can't be blended
+        let glassy = MixMaterial::new(
+            Arc::new(Lambertian::new(DVec3::ONE)),
+            Arc::new(Dielectric::new(1.5)),
+            0.5,
+        );
+        let delta = (0..1000)
+            .filter(|&i| {
+                let point = DVec3::new(i as f64 * 1e-3, 0.0, 0.0);
+                let hit_record = HitRecord::new(&ray, &point, DVec3::Y, &glassy, 1.0, 0.0, 0.0);
+                glassy
+                    .scattering_pdf(&ray, &hit_record)
+                    .unwrap()
+                    .is_delta_distribution()
+            })
+            .count();
+        assert!((400..600).contains(&delta));
+    }
+
+    #[test]
+    fn coating_never_adds_energy() {
+        let mut rng = StdRng::seed_from_u64(14);
+        let ray = Ray {
+            origin: DVec3::new(1.0, 1.0, 0.0),
+            dir: DVec3::new(-1.0, -1.0, 0.0),
+            time: 0.0,
+            wavelengths: None,
+        };
+
+        let white = CoatedMaterial::new(Arc::new(Lambertian::new(DVec3::ONE)), 1.5, 0.2);
+        let black = CoatedMaterial::new(Arc::new(Lambertian::new(DVec3::ZERO)), 1.5, 0.2);
+        let reflected = |material: &CoatedMaterial, rng: &mut StdRng| {
+            let hit_record = HitRecord::new(&ray, &DVec3::ZERO, DVec3::Y, material, 1.0, 0.0, 0.0);
+            let pdf = material.scattering_pdf(&ray, &hit_record).unwrap();
+            let sampled = albedo(material, &ray, &hit_record, pdf.as_ref(), rng);
+            let uniform = albedo(
+                material,
+                &ray,
+                &hit_record,
+                &UniformHemispherePDF::new(DVec3::Y),
+                rng,
+            );
+            assert!(
+                (sampled - uniform).abs().max_element() < 0.03,
+                "{} vs {}",
+                sampled,
+                uniform
+            );
+            sampled.x
+        };
+
+        // The coat alone reflects about its Fresnel reflectance at 45 degrees
+        let coat = reflected(&black, &mut rng);
+        assert!(
+            (coat - fresnel_dielectric(0.5f64.sqrt(), 1.5)).abs() < 0.01,
+            "{}",
+            coat
+        );
+
+        let total = reflected(&white, &mut rng);
+        assert!(total <= 1.0 && total > 0.85, "{}", total);
+    }
+
+    #[test]
+    fn wrappers_forward_surface_detail() {
+        // Tilted towards +x, with an opacity mask
+        let detailed: Arc<dyn Material> = Arc::new(AlphaMasked::new(
+            Arc::new(NormalMapped {
+                material: Arc::new(Lambertian::new(DVec3::splat(0.5))),
+                detail: SurfaceDetail::NormalMap(Arc::new(SolidColour {
+                    colour: DVec3::new(1.0, 0.5, 1.0),
+                })),
+            }),
+            AlphaMask::new(constant(0.25)),
+        ));
+        let plain: Arc<dyn Material> = Arc::new(Lambertian::new(DVec3::splat(0.5)));
+        let rect = AARect {
+            x_range: (0.0, 1.0),
+            y_range: (0.0, 1.0),
+            z: 0.0,
+            material: plain.clone(),
+        };
+        let ray = Ray {
+            origin: DVec3::new(0.5, 0.5, 1.0),
+            dir: DVec3::new(0.0, 0.0, -1.0),
+            time: 0.0,
+            wavelengths: None,
+        };
+        let hit_record = rect.hit(&ray, 0.001, 10.0).unwrap();
+        let tilted = DVec3::new(1.0, 0.0, 1.0).normalize();
+
+        let wrappers: Vec<Box<dyn Material>> = vec![
+            Box::new(CoatedMaterial::new(detailed.clone(), 1.5, 0.2)),
+            Box::new(MixMaterial::new(detailed.clone(), plain.clone(), 0.5)),
+            Box::new(MixMaterial::new(plain.clone(), detailed, 0.5)),
+        ];
+        for wrapper in &wrappers {
+            let normal = wrapper.shading_normal(&hit_record).unwrap();
+            assert!(normal.distance(tilted) < 1e-12, "{:?}", wrapper);
+            assert!(wrapper.alpha_mask().is_some(), "{:?}", wrapper);
+        }
+        assert!(MixMaterial::new(plain.clone(), plain.clone(), 0.5)
+            .shading_normal(&hit_record)
+            .is_none());
+
+        let prism: Arc<dyn Material> =
+            Arc::new(Dielectric::new(1.5).with_dispersion(Dispersion::bk7()));
+        assert!(CoatedMaterial::new(prism, 1.5, 0.2).is_dispersive());
+        let glow: Arc<dyn Material> = Arc::new(SpectralLight::new(
+            Arc::new(BlackbodySpectrum::new(3000.0)),
+            1.0,
+        ));
+        let coated_glow = CoatedMaterial::new(glow.clone(), 1.5, 0.2);
+        let wavelengths = DVec3::new(450.0, 550.0, 650.0);
+        assert_eq!(
+            coated_glow.emitted_spectral(0.0, 0.0, DVec3::ZERO, wavelengths),
+            glow.emitted_spectral(0.0, 0.0, DVec3::ZERO, wavelengths)
+        );
+    }
+
+    #[test]
+    fn glass_absorbs_along_its_path() {
+        let glass = Dielectric::new(1.5).with_absorption(DVec3::new(1.0, 0.5, 0.25), 1.0);
+
+        // Leaving the glass after travelling 2 units inside it
+        let ray = Ray {
+            origin: DVec3::new(0.0, -2.0, 0.0),
+            dir: DVec3::new(0.0, 0.5, 0.0),
+            time: 0.0,
+            wavelengths: None,
+        };
+        let hit_record = HitRecord::new(&ray, &DVec3::ZERO, DVec3::Y, &glass, 4.0, 0.0, 0.0);
+        assert!(!hit_record.front_face);
+        let ray_out = Ray {
+            origin: DVec3::ZERO,
+            dir: DVec3::Y,
+            time: 0.0,
+            wavelengths: None,
+        };
+        let throughput =
+            glass.brdf(&ray, &hit_record, &ray_out) * ray_out.dir.dot(hit_record.normal);
+        assert!(
+            (throughput - DVec3::new(1.0, 0.25, 0.0625))
+                .abs()
+                .max_element()
+                < 1e-12
+        );
+
+        // Entering it absorbs nothing yet
+        let entering = HitRecord::new(&ray, &DVec3::ZERO, -DVec3::Y, &glass, 4.0, 0.0, 0.0);
+        let ray_out = Ray {
+            origin: DVec3::ZERO,
+            dir: DVec3::Y,
+            time: 0.0,
+            wavelengths: None,
+        };
+        let throughput = glass.brdf(&ray, &entering, &ray_out) * ray_out.dir.dot(entering.normal);
+        assert!((throughput - DVec3::ONE).abs().max_element() < 1e-12);
+    }
+
+    #[test]
+    fn thin_walled_glass_passes_light_straight_through() {
+        let mut rng = StdRng::seed_from_u64(9);
+        let window = Dielectric::new(1.5).thin_walled();
+        let ray = Ray {
+            origin: DVec3::Y,
+            dir: DVec3::new(0.0, -1.0, 0.0),
+            time: 0.0,
+            wavelengths: None,
+        };
+        let hit_record = HitRecord::new(&ray, &DVec3::ZERO, DVec3::Y, &window, 1.0, 0.0, 0.0);
+        let pdf = window.scattering_pdf(&ray, &hit_record).unwrap();
+
+        let samples = 100_000;
+        let mut reflected = 0;
+        for _ in 0..samples {
+            let dir = pdf.generate(&mut rng);
+            if dir.y > 0.0 {
+                reflected += 1;
+            } else {
+                assert!((dir - ray.dir).length() < 1e-12);
+            }
+        }
+
+        // Both faces reflect 4% at normal incidence, plus the light bouncing between them
+        let expected = 2.0 * 0.04 / 1.04;
+        assert!((reflected as f64 / samples as f64 - expected).abs() < 0.005);
+    }
+
+    #[test]
+    fn dispersive_glass_bends_blue_more_than_red() {
+        let mut rng = StdRng::seed_from_u64(10);
+        let prism = Dielectric::new(1.5).with_dispersion(Dispersion::flint());
+        assert!(prism.is_dispersive());
+        assert!(!Dielectric::new(1.5).is_dispersive());
+
+        // Sine of the angle from the normal a ray at 45 degrees refracts to
+        let refracted_sin = |wavelength: f64, rng: &mut StdRng| {
+            let ray = Ray {
+                origin: DVec3::new(-1.0, 1.0, 0.0),
+                dir: DVec3::new(1.0, -1.0, 0.0).normalize(),
+                time: 0.0,
+                wavelengths: Some(DVec3::splat(wavelength)),
+            };
+            let hit_record = HitRecord::new(&ray, &DVec3::ZERO, DVec3::Y, &prism, 1.0, 0.0, 0.0);
+            let pdf = prism.scattering_pdf(&ray, &hit_record).unwrap();
+            loop {
+                let dir = pdf.generate(rng);
+                if dir.y < 0.0 {
+                    return dir.normalize().x;
+                }
+            }
+        };
+
+        let sin_45 = std::f64::consts::FRAC_1_SQRT_2;
+        for &wavelength in &[450.0, 650.0] {
+            let expected = sin_45 / Dispersion::flint().ior(wavelength);
+            assert!((refracted_sin(wavelength, &mut rng) - expected).abs() < 1e-9);
+        }
+        assert!(refracted_sin(450.0, &mut rng) < refracted_sin(650.0, &mut rng) - 0.005);
+    }
+}
@@ -1,6 +1,26 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
 use glam::DVec3;
 use rand::{Rng, RngCore};
 
+/// Relative luminance of linear sRGB
+pub fn luminance(colour: DVec3) -> f64 {
+    colour.dot(DVec3::new(0.2126, 0.7152, 0.0722))
+}
+
+/// CIE XYZ to linear sRGB, both with a D65 white point
+pub fn xyz_to_srgb(xyz: DVec3) -> DVec3 {
+    DVec3::new(
+        3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+        -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+        0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z,
+    )
+}
+
 pub fn rand_in_range(rng: &mut dyn rand::RngCore, min: f64, max: f64) -> DVec3 {
     DVec3::new(
         rng.gen_range(min..max),
@@ -70,3 +90,22 @@ pub fn refract(v: DVec3, n: DVec3, etai_over_etat: f64) -> DVec3 {
 pub fn random(rng: &mut dyn rand::RngCore) -> DVec3 {
     DVec3::new(rng.gen(), rng.gen(), rng.gen())
 }
+
+/// Ids handed out in creation order, the same between runs that build a scene the same way
+pub(crate) fn next_id() -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Number in [0, 1) fixed by `seed` and the exact bits of `vectors`, for random choices that
+/// have to be repeated
+pub(crate) fn hash_to_unit(seed: impl Hash, vectors: &[DVec3]) -> f64 {
+    let mut hasher = DefaultHasher::new();
+    seed.hash(&mut hasher);
+    for value in vectors {
+        value.x.to_bits().hash(&mut hasher);
+        value.y.to_bits().hash(&mut hasher);
+        value.z.to_bits().hash(&mut hasher);
+    }
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -1,116 +1,439 @@
-use std::sync::Arc;
+use std::{mem::size_of, sync::Arc};
 
-use crate::{bounding_box::AABB, hit::HitRecord, hittable::Hittable, material::Material, ray::Ray};
+use crate::{
+    bounding_box::AABB, hit::HitRecord, hittable::Hittable, light_sampler::surface_power,
+    material::Material, mesh_light::emissive_mesh_lights, next_id, ray::Ray, AreaSampledPDF,
+    DirectionCone, LightBounds, OrthoNormalBasis, SampleableLight, SphericalTrianglePDF,
+    MAX_SPHERICAL_SAMPLE_AREA, MIN_SPHERICAL_SAMPLE_AREA, PDF,
+};
 
-use glam::{DVec2, DVec3};
+use glam::{DVec2, DVec3, Vec2, Vec3, Vec4};
+use rand::Rng;
+
+/// Per-vertex positions or normals, optionally stored in single precision to halve their size
+#[derive(Clone, Debug, PartialEq)]
+pub enum VertexBuffer {
+    F64(Vec<DVec3>),
+    F32(Vec<Vec3>),
+}
+
+impl VertexBuffer {
+    pub fn get(&self, index: u32) -> DVec3 {
+        match self {
+            VertexBuffer::F64(data) => data[index as usize],
+            VertexBuffer::F32(data) => data[index as usize].as_f64(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            VertexBuffer::F64(data) => data.len(),
+            VertexBuffer::F32(data) => data.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        match self {
+            VertexBuffer::F64(data) => data.len() * size_of::<DVec3>(),
+            VertexBuffer::F32(data) => data.len() * size_of::<Vec3>(),
+        }
+    }
+}
+
+impl From<Vec<DVec3>> for VertexBuffer {
+    fn from(data: Vec<DVec3>) -> Self {
+        VertexBuffer::F64(data)
+    }
+}
+
+impl From<Vec<Vec3>> for VertexBuffer {
+    fn from(data: Vec<Vec3>) -> Self {
+        VertexBuffer::F32(data)
+    }
+}
+
+/// Per-vertex texture coordinates, see `VertexBuffer`
+#[derive(Clone, Debug, PartialEq)]
+pub enum UVBuffer {
+    F64(Vec<DVec2>),
+    F32(Vec<Vec2>),
+}
+
+impl UVBuffer {
+    pub fn get(&self, index: u32) -> DVec2 {
+        match self {
+            UVBuffer::F64(data) => data[index as usize],
+            UVBuffer::F32(data) => data[index as usize].as_f64(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            UVBuffer::F64(data) => data.len(),
+            UVBuffer::F32(data) => data.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        match self {
+            UVBuffer::F64(data) => data.len() * size_of::<DVec2>(),
+            UVBuffer::F32(data) => data.len() * size_of::<Vec2>(),
+        }
+    }
+}
+
+impl From<Vec<DVec2>> for UVBuffer {
+    fn from(data: Vec<DVec2>) -> Self {
+        UVBuffer::F64(data)
+    }
+}
+
+impl From<Vec<Vec2>> for UVBuffer {
+    fn from(data: Vec<Vec2>) -> Self {
+        UVBuffer::F32(data)
+    }
+}
 
 #[derive(Debug)]
 pub struct Mesh {
-    pub vertices: Vec<DVec3>,
-    pub normals: Vec<DVec3>,
-    pub uv: Vec<DVec2>,
+    pub vertices: VertexBuffer,
+    pub normals: VertexBuffer,
+    pub uv: UVBuffer,
+    /// Per-vertex tangent in `xyz` and bitangent sign in `w`, following the MikkTSpace convention
+    /// that the bitangent is `w * normal.cross(tangent)`. Empty without normals and uvs.
+    pub tangents: Vec<Vec4>,
+    /// Vertex indices of every triangle, shared by all the `Triangle`s of the mesh
+    pub indices: Vec<[u32; 3]>,
     pub material: Arc<dyn Material>,
+    /// Seeds the alpha test of its triangles, from `next_id` unless set by hand
+    pub id: u64,
+}
+
+impl Mesh {
+    pub fn new(
+        vertices: VertexBuffer,
+        normals: VertexBuffer,
+        uv: UVBuffer,
+        indices: Vec<[u32; 3]>,
+        material: Arc<dyn Material>,
+    ) -> Mesh {
+        let tangents = compute_tangents(&vertices, &normals, &uv, &indices);
+        Mesh {
+            vertices,
+            normals,
+            uv,
+            tangents,
+            indices,
+            material,
+            id: next_id(),
+        }
+    }
+
+    /// Bytes used by the vertex and index buffers
+    pub fn memory_usage(&self) -> usize {
+        size_of::<Mesh>()
+            + self.vertices.memory_usage()
+            + self.normals.memory_usage()
+            + self.uv.memory_usage()
+            + self.tangents.len() * size_of::<Vec4>()
+            + self.indices.len() * size_of::<[u32; 3]>()
+    }
+}
+
+/// Tangents from the uv parameterization of each face, weighted by the angle of the face at the
+/// vertex and orthogonalized against the vertex normal, as MikkTSpace does
+fn compute_tangents(
+    vertices: &VertexBuffer,
+    normals: &VertexBuffer,
+    uv: &UVBuffer,
+    indices: &[[u32; 3]],
+) -> Vec<Vec4> {
+    if normals.len() != vertices.len() || uv.len() != vertices.len() {
+        return Vec::new();
+    }
+
+    let mut tangents = vec![DVec3::ZERO; vertices.len()];
+    let mut bitangents = vec![DVec3::ZERO; vertices.len()];
+
+    for triangle in indices {
+        let p = triangle.map(|index| vertices.get(index));
+        let st = triangle.map(|index| uv.get(index));
+
+        let (edge_1, edge_2) = (p[1] - p[0], p[2] - p[0]);
+        let (duv_1, duv_2) = (st[1] - st[0], st[2] - st[0]);
+        let det = duv_1.x * duv_2.y - duv_2.x * duv_1.y;
+        if det == 0.0 {
+            continue;
+        }
+
+        let tangent = ((edge_1 * duv_2.y - edge_2 * duv_1.y) / det).normalize_or_zero();
+        let bitangent = ((edge_2 * duv_1.x - edge_1 * duv_2.x) / det).normalize_or_zero();
+
+        for corner in 0..3 {
+            let to_next = (p[(corner + 1) % 3] - p[corner]).normalize_or_zero();
+            let to_prev = (p[(corner + 2) % 3] - p[corner]).normalize_or_zero();
+            let angle = to_next.dot(to_prev).clamp(-1.0, 1.0).acos();
+
+            let index = triangle[corner] as usize;
+            tangents[index] += tangent * angle;
+            bitangents[index] += bitangent * angle;
+        }
+    }
+
+    tangents
+        .iter()
+        .zip(bitangents.iter())
+        .enumerate()
+        .map(|(index, (tangent, bitangent))| {
+            let normal = normals.get(index as u32);
+            let mut tangent = (*tangent - normal * normal.dot(*tangent)).normalize_or_zero();
+            if tangent == DVec3::ZERO {
+                // No usable uvs around this vertex, any tangent will do
+                tangent = OrthoNormalBasis::from_w(&normal.normalize()).v;
+            }
+            let sign = if normal.cross(tangent).dot(*bitangent) < 0.0 {
+                -1.0
+            } else {
+                1.0
+            };
+            Vec4::from((tangent.as_f32(), sign))
+        })
+        .collect()
 }
 
 pub fn create_mesh(
-    vertices: Vec<DVec3>,
-    normals: Vec<DVec3>,
-    uv: Vec<DVec2>,
+    vertices: impl Into<VertexBuffer>,
+    normals: impl Into<VertexBuffer>,
+    uv: impl Into<UVBuffer>,
     indices: Vec<[u32; 3]>,
     material: Arc<dyn Material>,
 ) -> Vec<Triangle> {
-    let mesh = Arc::new(Mesh {
-        vertices,
-        normals,
-        uv,
+    let triangle_count = indices.len() as u32;
+    let mesh = Arc::new(Mesh::new(
+        vertices.into(),
+        normals.into(),
+        uv.into(),
+        indices,
         material,
-    });
+    ));
 
-    let mut triangles = Vec::new();
-
-    for indices in indices {
-        triangles.push(Triangle {
-            indices,
+    (0..triangle_count)
+        .map(|index| Triangle {
+            index,
             data: mesh.clone(),
-        });
-    }
-
-    triangles
+        })
+        .collect()
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Triangle {
-    pub indices: [u32; 3],
+    /// Index into the mesh's index buffer
+    pub index: u32,
     pub data: Arc<Mesh>,
 }
 
-impl Hittable for Triangle {
-    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        let (v0, v1, v2) = (
-            self.data.vertices[self.indices[0] as usize],
-            self.data.vertices[self.indices[1] as usize],
-            self.data.vertices[self.indices[2] as usize],
-        );
+impl Triangle {
+    pub fn indices(&self) -> [u32; 3] {
+        self.data.indices[self.index as usize]
+    }
+
+    pub fn positions(&self) -> [DVec3; 3] {
+        self.indices().map(|index| self.data.vertices.get(index))
+    }
+
+    pub fn area(&self) -> f64 {
+        let [p0, p1, p2] = self.positions();
+        0.5 * (p1 - p0).cross(p2 - p0).length()
+    }
+
+    /// Returns `(t, u, v)` for the intersection with `ray`, where `u` and `v` are the barycentric
+    /// weights of the second and third vertices. Alpha masks are ignored, so pdfs over the
+    /// triangle give the same value for every direction.
+    ///
+    /// Watertight test from Woop, Benthin and Wald 2013: the vertices are moved into a space where
+    /// the ray runs along +z from the origin, so the edge functions of triangles sharing an edge
+    /// are computed from identical values and a ray can't slip between them.
+    pub(crate) fn intersect(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<(f64, f64, f64)> {
+        let [i0, i1, i2] = self.indices();
+
+        // Permute the axes so the largest component of the direction becomes z
+        let abs_dir = ray.dir.abs();
+        let kz = if abs_dir.x > abs_dir.y {
+            if abs_dir.x > abs_dir.z {
+                0
+            } else {
+                2
+            }
+        } else if abs_dir.y > abs_dir.z {
+            1
+        } else {
+            2
+        };
+        let kx = (kz + 1) % 3;
+        let ky = (kx + 1) % 3;
+        let permute = |v: DVec3| DVec3::new(v[kx], v[ky], v[kz]);
 
-        let edge1 = v1 - v0;
-        let edge2 = v2 - v0;
-        let h = ray.dir.cross(edge2);
-        let det = edge1.dot(h);
-        if det > -f64::EPSILON && det < f64::EPSILON {
+        let dir = permute(ray.dir);
+        if dir.z == 0.0 {
             return None;
         }
+        let shear_x = -dir.x / dir.z;
+        let shear_y = -dir.y / dir.z;
+        let shear_z = 1.0 / dir.z;
 
-        let det_inv = 1.0 / det;
-        let s = ray.origin - v0;
+        let transform = |v: DVec3| {
+            let p = permute(v - ray.origin);
+            DVec3::new(p.x + shear_x * p.z, p.y + shear_y * p.z, p.z)
+        };
+        let p0 = transform(self.data.vertices.get(i0));
+        let p1 = transform(self.data.vertices.get(i1));
+        let p2 = transform(self.data.vertices.get(i2));
 
-        let u = det_inv * s.dot(h);
+        // Edge functions, each the (scaled) barycentric weight of the opposite vertex
+        let e0 = p1.x * p2.y - p1.y * p2.x;
+        let e1 = p2.x * p0.y - p2.y * p0.x;
+        let e2 = p0.x * p1.y - p0.y * p1.x;
 
-        if !(0.0..=1.0).contains(&u) {
+        if (e0 < 0.0 || e1 < 0.0 || e2 < 0.0) && (e0 > 0.0 || e1 > 0.0 || e2 > 0.0) {
+            return None;
+        }
+        let det = e0 + e1 + e2;
+        if det == 0.0 {
             return None;
         }
 
-        let q = s.cross(edge1);
-        let v = det_inv * ray.dir.dot(q);
-        if v < 0.0 || (u + v) > 1.0 {
+        let t_scaled = (e0 * p0.z + e1 * p1.z + e2 * p2.z) * shear_z;
+        let t = t_scaled / det;
+        if t <= t_min || t > t_max {
             return None;
         }
 
-        let t = det_inv * edge2.dot(q);
-        if t <= f64::EPSILON || t > t_max || t <= t_min {
+        Some((t, e1 / det, e2 / det))
+    }
+
+    /// Whether `ray` passes through a hole cut by the material's alpha mask at the barycentric
+    /// weights `u` and `v`
+    fn is_cut_out(&self, ray: &Ray, t: f64, u: f64, v: f64) -> bool {
+        let mask = match self.data.material.alpha_mask() {
+            Some(mask) => mask,
+            None => return false,
+        };
+
+        let uv = if self.data.uv.is_empty() {
+            DVec2::new(u, v)
+        } else {
+            let [i0, i1, i2] = self.indices();
+            (1.0 - u - v) * self.data.uv.get(i0)
+                + u * self.data.uv.get(i1)
+                + v * self.data.uv.get(i2)
+        };
+        let surface = (self.data.id, self.index);
+        mask.passes(ray, surface, uv.x, uv.y, ray.at(t))
+    }
+}
+
+impl Hittable for Triangle {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        let (t, u, v) = self.intersect(ray, t_min, t_max)?;
+        if self.is_cut_out(ray, t, u, v) {
             return None;
         }
 
-        let (n0, n1, n2) = (
-            self.data.normals[self.indices[0] as usize],
-            self.data.normals[self.indices[1] as usize],
-            self.data.normals[self.indices[2] as usize],
+        let [i0, i1, i2] = self.indices();
+        let w = 1.0 - u - v;
+
+        let (v0, v1, v2) = (
+            self.data.vertices.get(i0),
+            self.data.vertices.get(i1),
+            self.data.vertices.get(i2),
         );
+        // Counter-clockwise winding faces outwards
+        let face_normal = (v1 - v0).cross(v2 - v0).normalize();
 
-        let (uv0, uv1, uv2) = (
-            self.data.uv[self.indices[0] as usize],
-            self.data.uv[self.indices[1] as usize],
-            self.data.uv[self.indices[2] as usize],
+        let mut hr = HitRecord::new(
+            ray,
+            &ray.at(t),
+            face_normal,
+            self.data.material.as_ref(),
+            t,
+            u,
+            v,
         );
 
-        let n = (u * n0) + (v * n1) + ((1.0 - (u + v)) * n2);
-        let uv = (u * uv0) + (v * uv1) + ((1.0 - (u + v)) * uv2);
+        if !self.data.uv.is_empty() {
+            let (uv0, uv1, uv2) = (
+                self.data.uv.get(i0),
+                self.data.uv.get(i1),
+                self.data.uv.get(i2),
+            );
+            let uv = w * uv0 + u * uv1 + v * uv2;
+            hr.u = uv.x;
+            hr.v = uv.y;
 
-        Some(HitRecord {
-            point: ray.at(t),
-            material: self.data.material.as_ref(),
-            normal: n,
-            u: uv.x,
-            v: uv.y,
-            t,
-            front_face: ray.dir.dot(n) > 0.0,
-        })
+            let (duv_1, duv_2) = (uv1 - uv0, uv2 - uv0);
+            let det = duv_1.x * duv_2.y - duv_2.x * duv_1.y;
+            if det != 0.0 {
+                let (edge_1, edge_2) = (v1 - v0, v2 - v0);
+                let dpdu = (edge_1 * duv_2.y - edge_2 * duv_1.y) / det;
+                let dpdv = (edge_2 * duv_1.x - edge_1 * duv_2.x) / det;
+                hr = hr.with_uv_derivatives(dpdu, dpdv);
+            }
+        }
+
+        if self.data.normals.is_empty() {
+            return Some(hr);
+        }
+
+        let shading_normal = w * self.data.normals.get(i0)
+            + u * self.data.normals.get(i1)
+            + v * self.data.normals.get(i2);
+        if shading_normal.length_squared() == 0.0 {
+            return Some(hr);
+        }
+        let shading_normal = shading_normal.normalize();
+
+        if !self.data.tangents.is_empty() {
+            // Align the derivatives with the interpolated vertex tangent frame so normal maps
+            // baked against MikkTSpace tangents decode correctly
+            let tangent = w * self.data.tangents[i0 as usize].as_f64()
+                + u * self.data.tangents[i1 as usize].as_f64()
+                + v * self.data.tangents[i2 as usize].as_f64();
+            let tangent_dir = tangent.truncate();
+            let tangent_dir = (tangent_dir - shading_normal * shading_normal.dot(tangent_dir))
+                .normalize_or_zero();
+            if tangent_dir != DVec3::ZERO {
+                let bitangent_dir = shading_normal.cross(tangent_dir) * tangent.w.signum();
+                let (dpdu, dpdv) = (
+                    tangent_dir * hr.dpdu.length(),
+                    bitangent_dir * hr.dpdv.length(),
+                );
+                hr = hr.with_uv_derivatives(dpdu, dpdv);
+            }
+        }
+
+        Some(hr.with_shading_normal(shading_normal))
+    }
+
+    fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+        match self.intersect(ray, t_min, t_max) {
+            Some((t, u, v)) => !self.is_cut_out(ray, t, u, v),
+            None => false,
+        }
     }
 
     fn bounding_box(&self, _: f64, _: f64) -> Option<AABB> {
         let (min, max) = self
-            .indices
+            .indices()
             .iter()
-            .map(|index| self.data.vertices[*index as usize])
+            .map(|index| self.data.vertices.get(*index))
             .fold(
                 (DVec3::splat(f64::MAX), DVec3::splat(f64::MIN)),
                 |(min, max), vertex| (DVec3::min(min, vertex), DVec3::max(max, vertex)),
@@ -119,12 +442,72 @@ impl Hittable for Triangle {
         Some(AABB { min, max })
     }
 
-    fn sample_uniform(&self, _: &mut dyn rand::RngCore) -> DVec3 {
-        todo!()
+    fn sample_uniform(&self, rng: &mut dyn rand::RngCore) -> DVec3 {
+        let [p0, p1, p2] = self.positions();
+
+        // Square root warp keeps the barycentrics uniform over the triangle's area
+        let sqrt_r1 = rng.gen::<f64>().sqrt();
+        let b0 = 1.0 - sqrt_r1;
+        let b1 = rng.gen::<f64>() * sqrt_r1;
+
+        b0 * p0 + b1 * p1 + (1.0 - b0 - b1) * p2
+    }
+
+    fn pdf_uniform(&self, _: DVec3) -> f64 {
+        1.0 / self.area()
+    }
+
+    fn emissive_lights(self: Arc<Self>) -> Vec<Arc<dyn SampleableLight>> {
+        if self.data.material.is_emissive() {
+            vec![self]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+impl SampleableLight for Triangle {
+    fn pdf_for_point(&self, point: DVec3) -> Box<dyn PDF> {
+        // Spherical sampling is only worth it, and only numerically stable, for triangles that
+        // are neither tiny nor huge from the point's perspective
+        match SphericalTrianglePDF::new(point, self.clone()) {
+            Some(pdf)
+                if (MIN_SPHERICAL_SAMPLE_AREA..=MAX_SPHERICAL_SAMPLE_AREA)
+                    .contains(&pdf.solid_angle()) =>
+            {
+                Box::new(pdf)
+            }
+            _ => Box::new(AreaSampledPDF::new(point, self.clone())),
+        }
+    }
+
+    fn power(&self) -> f64 {
+        let [p0, p1, p2] = self.positions();
+        let [i0, i1, i2] = self.indices();
+        let uv = if self.data.uv.is_empty() {
+            DVec2::splat(1.0 / 3.0)
+        } else {
+            (self.data.uv.get(i0) + self.data.uv.get(i1) + self.data.uv.get(i2)) / 3.0
+        };
+        surface_power(
+            self.data.material.as_ref(),
+            uv.x,
+            uv.y,
+            (p0 + p1 + p2) / 3.0,
+            self.area(),
+        )
     }
 
-    fn pdf_uniform(&self, point: DVec3) -> f64 {
-        todo!()
+    fn light_bounds(&self) -> Option<LightBounds> {
+        let [p0, p1, p2] = self.positions();
+        Some(LightBounds {
+            normals: DirectionCone {
+                w: (p1 - p0).cross(p2 - p0).normalize_or_zero(),
+                cos_theta: 1.0,
+            },
+            two_sided: true,
+            ..LightBounds::omnidirectional(self.bounding_box(0.0, 0.0)?, self.power())
+        })
     }
 }
 
@@ -142,6 +525,11 @@ impl Hittable for Vec<Triangle> {
         })
     }
 
+    fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+        self.iter()
+            .any(|triangle| triangle.occluded(ray, t_min, t_max))
+    }
+
     fn bounding_box(&self, time_0: f64, time_1: f64) -> Option<AABB> {
         if self.is_empty() {
             return None;
@@ -158,19 +546,42 @@ impl Hittable for Vec<Triangle> {
         })
     }
 
-    fn sample_uniform(&self, _: &mut dyn rand::RngCore) -> DVec3 {
-        todo!()
+    /// Uniform over the total area of the triangles
+    fn sample_uniform(&self, rng: &mut dyn rand::RngCore) -> DVec3 {
+        let total_area: f64 = self.iter().map(Triangle::area).sum();
+
+        let mut target = rng.gen::<f64>() * total_area;
+        for triangle in self {
+            let area = triangle.area();
+            if target < area {
+                return triangle.sample_uniform(rng);
+            }
+            target -= area;
+        }
+
+        // Only reachable through rounding error
+        self.last()
+            .expect("Cannot sample an empty mesh")
+            .sample_uniform(rng)
+    }
+
+    fn pdf_uniform(&self, _: DVec3) -> f64 {
+        1.0 / self.iter().map(Triangle::area).sum::<f64>()
     }
 
-    fn pdf_uniform(&self, point: DVec3) -> f64 {
-        todo!()
+    fn emissive_lights(self: Arc<Self>) -> Vec<Arc<dyn SampleableLight>> {
+        emissive_mesh_lights(&self)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{Lambertian, Ray};
+    use crate::{
+        rand_in_unit_sphere, AlphaMask, AlphaMasked, BVHBuildParams, DiffuseLight, Lambertian,
+        MeshBVH, MeshLight, Ray, SolidColour,
+    };
+    use rand::{rngs::StdRng, SeedableRng};
 
     #[test]
     fn bbox() {
@@ -179,14 +590,18 @@ mod tests {
                 DVec3::new(-1.0, -1.0, -1.0),
                 DVec3::new(-1.0, 1.0, -1.0),
                 DVec3::new(1.0, 1.1, -1.0),
-            ],
-            uv: Vec::new(),
-            normals: Vec::new(),
+            ]
+            .into(),
+            uv: Vec::<DVec2>::new().into(),
+            normals: Vec::<DVec3>::new().into(),
+            tangents: Vec::new(),
+            indices: vec![[0, 1, 2]],
             material: Arc::new(Lambertian::new(DVec3::splat(0.0))),
+            id: 0,
         });
 
         let triangle = Triangle {
-            indices: [0, 1, 2],
+            index: 0,
             data: meshdata,
         };
 
@@ -207,22 +622,28 @@ mod tests {
                 DVec3::new(1.0, 0.0, -1.0),
                 DVec3::new(0.0, 1.0, -1.0),
                 DVec3::new(0.0, 0.0, -1.0),
-            ],
+            ]
+            .into(),
             uv: vec![
                 DVec2::new(1.0, 0.0),
                 DVec2::new(0.0, 1.0),
                 DVec2::new(0.0, 0.0),
-            ],
+            ]
+            .into(),
             normals: vec![
                 DVec3::new(0.0, 0.0, 1.0),
                 DVec3::new(0.0, 0.0, 1.0),
                 DVec3::new(0.0, 0.0, 1.0),
-            ],
+            ]
+            .into(),
+            tangents: Vec::new(),
+            indices: vec![[0, 1, 2]],
             material: Arc::new(Lambertian::new(DVec3::splat(0.0))),
+            id: 0,
         });
 
         let triangle = Triangle {
-            indices: [0, 1, 2],
+            index: 0,
             data: meshdata,
         };
 
@@ -230,6 +651,7 @@ mod tests {
             origin: DVec3::ZERO,
             dir: DVec3::new(0.5, 0.5, -1.0).normalize(),
             time: 0.0,
+            wavelengths: None,
         };
 
         let hr = triangle.hit(&ray, 0.01, 10.0).unwrap();
@@ -237,4 +659,472 @@ mod tests {
 
         println!("{:#?}", ray.at(hr.t));
     }
+
+    #[test]
+    fn single_precision_storage() {
+        let vertices = vec![
+            Vec3::new(1.0, 0.0, -1.0),
+            Vec3::new(0.0, 1.0, -1.0),
+            Vec3::new(0.0, 0.0, -1.0),
+            Vec3::new(1.0, 1.0, -1.0),
+        ];
+        let normals = vec![Vec3::Z; 4];
+        let uv = vec![Vec2::ZERO; 4];
+        let material = Arc::new(Lambertian::new(DVec3::splat(0.5)));
+
+        let single = create_mesh(
+            vertices.clone(),
+            normals.clone(),
+            uv.clone(),
+            vec![[0, 1, 2], [0, 3, 1]],
+            material.clone(),
+        );
+        let double = create_mesh(
+            vertices.iter().map(|v| v.as_f64()).collect::<Vec<_>>(),
+            normals.iter().map(|n| n.as_f64()).collect::<Vec<_>>(),
+            uv.iter().map(|uv| uv.as_f64()).collect::<Vec<_>>(),
+            vec![[0, 1, 2], [0, 3, 1]],
+            material,
+        );
+
+        // Both triangles share one mesh and index buffer
+        assert!(Arc::ptr_eq(&single[0].data, &single[1].data));
+        assert_eq!(single[1].indices(), [0, 3, 1]);
+        assert!(single[0].data.memory_usage() < double[0].data.memory_usage());
+
+        let ray = Ray {
+            origin: DVec3::new(0.6, 0.6, 0.0),
+            dir: DVec3::new(0.0, 0.0, -1.0),
+            time: 0.0,
+            wavelengths: None,
+        };
+        assert_eq!(
+            single.hit(&ray, 0.001, 10.0).map(|hr| hr.t),
+            double.hit(&ray, 0.001, 10.0).map(|hr| hr.t)
+        );
+    }
+
+    fn closed_mesh(vertices: Vec<DVec3>, indices: Vec<[u32; 3]>) -> Vec<Triangle> {
+        let count = vertices.len();
+        create_mesh(
+            vertices,
+            vec![DVec3::Z; count],
+            vec![DVec2::ZERO; count],
+            indices,
+            Arc::new(Lambertian::new(DVec3::splat(0.5))),
+        )
+    }
+
+    #[test]
+    fn watertight_cube_grid() {
+        // Unit cube split along its face diagonals
+        let vertices = (0..8)
+            .map(|i| {
+                DVec3::new(
+                    if i & 1 == 0 { -1.0 } else { 1.0 },
+                    if i & 2 == 0 { -1.0 } else { 1.0 },
+                    if i & 4 == 0 { -1.0 } else { 1.0 },
+                )
+            })
+            .collect::<Vec<_>>();
+        let cube = closed_mesh(
+            vertices,
+            vec![
+                [0, 1, 3],
+                [0, 3, 2],
+                [4, 7, 5],
+                [4, 6, 7],
+                [0, 4, 5],
+                [0, 5, 1],
+                [2, 3, 7],
+                [2, 7, 6],
+                [0, 2, 6],
+                [0, 6, 4],
+                [1, 5, 7],
+                [1, 7, 3],
+            ],
+        );
+
+        // Grid spacing is exactly representable, so many rays pass exactly through edges and vertices
+        let steps = 128;
+        for i in 0..=steps {
+            for j in 0..=steps {
+                let x = -1.0 + 2.0 * i as f64 / steps as f64;
+                let y = -1.0 + 2.0 * j as f64 / steps as f64;
+
+                let from_outside = Ray {
+                    origin: DVec3::new(x, y, 5.0),
+                    dir: DVec3::new(0.0, 0.0, -1.0),
+                    time: 0.0,
+                    wavelengths: None,
+                };
+                let hr = cube.hit(&from_outside, 0.001, 100.0);
+                assert_eq!(hr.map(|hr| hr.t), Some(4.0), "leak at ({}, {})", x, y);
+
+                let from_inside = Ray {
+                    origin: DVec3::ZERO,
+                    dir: DVec3::new(x, y, 1.0),
+                    time: 0.0,
+                    wavelengths: None,
+                };
+                assert!(
+                    cube.occluded(&from_inside, 0.001, 100.0),
+                    "leak at ({}, {})",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn watertight_sphere_from_inside() {
+        // Subdivided octahedron projected onto an off-centre sphere, so no edge is axis aligned
+        let center = DVec3::new(0.3, -0.2, 0.1);
+        let mut vertices = vec![
+            DVec3::X,
+            -DVec3::X,
+            DVec3::Y,
+            -DVec3::Y,
+            DVec3::Z,
+            -DVec3::Z,
+        ];
+        let mut indices = vec![
+            [0, 2, 4],
+            [2, 1, 4],
+            [1, 3, 4],
+            [3, 0, 4],
+            [2, 0, 5],
+            [1, 2, 5],
+            [3, 1, 5],
+            [0, 3, 5],
+        ];
+        for _ in 0..3 {
+            let mut midpoints = std::collections::HashMap::new();
+            let mut midpoint = |a: u32, b: u32, vertices: &mut Vec<DVec3>| {
+                *midpoints.entry((a.min(b), a.max(b))).or_insert_with(|| {
+                    let v = (vertices[a as usize] + vertices[b as usize]).normalize();
+                    vertices.push(v);
+                    vertices.len() as u32 - 1
+                })
+            };
+            indices = indices
+                .iter()
+                .flat_map(|&[a, b, c]| {
+                    let ab = midpoint(a, b, &mut vertices);
+                    let bc = midpoint(b, c, &mut vertices);
+                    let ca = midpoint(c, a, &mut vertices);
+                    vec![[a, ab, ca], [ab, b, bc], [ca, bc, c], [ab, bc, ca]]
+                })
+                .collect();
+        }
+        let vertices = vertices
+            .iter()
+            .map(|v| *v * 1.7 + center)
+            .collect::<Vec<_>>();
+        let sphere = MeshBVH::new(
+            closed_mesh(vertices.clone(), indices.clone()),
+            BVHBuildParams::default(),
+        );
+
+        // Aim at the vertices and at points along every edge
+        let mut targets = vertices.clone();
+        for [a, b, c] in indices {
+            for (from, to) in [(a, b), (b, c), (c, a)] {
+                for k in 1..8 {
+                    targets
+                        .push(vertices[from as usize].lerp(vertices[to as usize], k as f64 / 8.0));
+                }
+            }
+        }
+
+        for origin in [center, center + DVec3::new(0.5, 0.25, -0.125)] {
+            for target in &targets {
+                let ray = Ray {
+                    origin,
+                    dir: *target - origin,
+                    time: 0.0,
+                    wavelengths: None,
+                };
+                assert!(
+                    sphere.hit(&ray, 1e-9, 100.0).is_some(),
+                    "leak towards {}",
+                    target
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn tiny_triangle_hit() {
+        let scale = 1e-9;
+        let triangle = closed_mesh(
+            vec![
+                DVec3::new(0.0, 0.0, -1.0),
+                DVec3::new(scale, 0.0, -1.0),
+                DVec3::new(0.0, scale, -1.0),
+            ],
+            vec![[0, 1, 2]],
+        );
+
+        let ray = Ray {
+            origin: DVec3::new(0.25 * scale, 0.25 * scale, 0.0),
+            dir: DVec3::new(0.0, 0.0, -1.0),
+            time: 0.0,
+            wavelengths: None,
+        };
+        let hr = triangle.hit(&ray, 0.001, 10.0).unwrap();
+        assert!((hr.t - 1.0).abs() < 1e-12);
+    }
+
+    fn shading_triangle(normals: Vec<DVec3>) -> Vec<Triangle> {
+        create_mesh(
+            vec![
+                DVec3::new(0.0, 0.0, -1.0),
+                DVec3::new(1.0, 0.0, -1.0),
+                DVec3::new(0.0, 1.0, -1.0),
+            ],
+            normals,
+            vec![
+                DVec2::new(0.0, 0.0),
+                DVec2::new(1.0, 0.0),
+                DVec2::new(0.0, 1.0),
+            ],
+            vec![[0, 1, 2]],
+            Arc::new(Lambertian::new(DVec3::splat(0.5))),
+        )
+    }
+
+    #[test]
+    fn barycentric_interpolation_order() {
+        let n0 = DVec3::new(-1.0, -1.0, 1.0).normalize();
+        let n1 = DVec3::new(1.0, 0.0, 1.0).normalize();
+        let n2 = DVec3::new(0.0, 1.0, 1.0).normalize();
+        let triangle = shading_triangle(vec![n0, n1, n2]);
+
+        // Just inside each corner, the shading normal and uv should match that vertex
+        for (corner, normal) in [
+            (DVec2::new(0.0, 0.0), n0),
+            (DVec2::new(1.0, 0.0), n1),
+            (DVec2::new(0.0, 1.0), n2),
+        ] {
+            let target = corner.lerp(DVec2::splat(1.0 / 3.0), 1e-6);
+            let ray = Ray {
+                origin: DVec3::new(target.x, target.y, 0.0),
+                dir: DVec3::new(0.0, 0.0, -1.0),
+                time: 0.0,
+                wavelengths: None,
+            };
+            let hr = triangle.hit(&ray, 0.001, 10.0).unwrap();
+            assert!(hr.normal.distance(normal) < 1e-5);
+            assert!(DVec2::new(hr.u, hr.v).distance(corner) < 1e-5);
+            assert_eq!(hr.geometric_normal, DVec3::Z);
+        }
+    }
+
+    #[test]
+    fn back_face_flips_normals() {
+        let triangle = shading_triangle(vec![DVec3::Z; 3]);
+
+        let front = Ray {
+            origin: DVec3::new(0.25, 0.25, 0.0),
+            dir: DVec3::new(0.0, 0.0, -1.0),
+            time: 0.0,
+            wavelengths: None,
+        };
+        let hr = triangle.hit(&front, 0.001, 10.0).unwrap();
+        assert!(hr.front_face);
+        assert_eq!(hr.geometric_normal, DVec3::Z);
+        assert_eq!(hr.normal, DVec3::Z);
+
+        let back = Ray {
+            origin: DVec3::new(0.25, 0.25, -2.0),
+            dir: DVec3::new(0.0, 0.0, 1.0),
+            time: 0.0,
+            wavelengths: None,
+        };
+        let hr = triangle.hit(&back, 0.001, 10.0).unwrap();
+        assert!(!hr.front_face);
+        assert_eq!(hr.geometric_normal, -DVec3::Z);
+        assert_eq!(hr.normal, -DVec3::Z);
+    }
+
+    #[test]
+    fn face_normal_without_vertex_normals() {
+        let triangle = shading_triangle(Vec::new());
+
+        let ray = Ray {
+            origin: DVec3::new(0.25, 0.25, 0.0),
+            dir: DVec3::new(0.0, 0.0, -1.0),
+            time: 0.0,
+            wavelengths: None,
+        };
+        let hr = triangle.hit(&ray, 0.001, 10.0).unwrap();
+        assert_eq!(hr.normal, DVec3::Z);
+        assert_eq!(hr.normal, hr.geometric_normal);
+    }
+
+    #[test]
+    fn tangents_follow_uv_layout() {
+        let material = Arc::new(Lambertian::new(DVec3::splat(0.5)));
+        let quad = |uv: Vec<DVec2>| {
+            create_mesh(
+                vec![
+                    DVec3::new(0.0, 0.0, 0.0),
+                    DVec3::new(1.0, 0.0, 0.0),
+                    DVec3::new(1.0, 1.0, 0.0),
+                    DVec3::new(0.0, 1.0, 0.0),
+                ],
+                vec![DVec3::Z; 4],
+                uv,
+                vec![[0, 1, 2], [0, 2, 3]],
+                material.clone(),
+            )
+        };
+
+        let regular = quad(vec![
+            DVec2::new(0.0, 0.0),
+            DVec2::new(1.0, 0.0),
+            DVec2::new(1.0, 1.0),
+            DVec2::new(0.0, 1.0),
+        ]);
+        for tangent in &regular[0].data.tangents {
+            assert_eq!(*tangent, Vec4::new(1.0, 0.0, 0.0, 1.0));
+        }
+
+        // Mirroring v flips the bitangent
+        let mirrored = quad(vec![
+            DVec2::new(0.0, 1.0),
+            DVec2::new(1.0, 1.0),
+            DVec2::new(1.0, 0.0),
+            DVec2::new(0.0, 0.0),
+        ]);
+        for tangent in &mirrored[0].data.tangents {
+            assert_eq!(*tangent, Vec4::new(1.0, 0.0, 0.0, -1.0));
+        }
+
+        let ray = Ray {
+            origin: DVec3::new(0.7, 0.2, 1.0),
+            dir: DVec3::new(0.0, 0.0, -1.0),
+            time: 0.0,
+            wavelengths: None,
+        };
+        let hr = mirrored.hit(&ray, 0.001, 10.0).unwrap();
+        assert!(hr.dpdu.distance(DVec3::X) < 1e-12);
+        assert!(hr.dpdv.distance(-DVec3::Y) < 1e-12);
+    }
+
+    #[test]
+    fn triangle_light_pdfs() {
+        let triangle = shading_triangle(vec![DVec3::Z; 3]).remove(0);
+        let mut rng = StdRng::seed_from_u64(0);
+
+        // Close enough to sample the subtended solid angle, the pdf should integrate to one
+        let near = triangle.pdf_for_point(DVec3::new(0.3, 0.3, 0.0));
+        let samples = 200_000;
+        let sum: f64 = (0..samples)
+            .map(|_| near.value(rand_in_unit_sphere(&mut rng).normalize()))
+            .sum();
+        let integral = sum * 4.0 * std::f64::consts::PI / samples as f64;
+        assert!((integral - 1.0).abs() < 0.05, "integral was {}", integral);
+
+        // Far away it is area sampled, E[1 / pdf] over its samples is the solid angle
+        let origin = DVec3::new(0.3, 0.3, 50.0);
+        let far = triangle.pdf_for_point(origin);
+        let solid_angle = SphericalTrianglePDF::new(origin, triangle.clone())
+            .unwrap()
+            .solid_angle();
+        assert!(solid_angle < MIN_SPHERICAL_SAMPLE_AREA);
+
+        let samples = 10_000;
+        let estimate = (0..samples)
+            .map(|_| 1.0 / far.value(far.generate(&mut rng)))
+            .sum::<f64>()
+            / samples as f64;
+        assert!((estimate / solid_angle - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn light_pdfs_ignore_cut_outs() {
+        let glowing = Arc::new(DiffuseLight {
+            emit_colour: Arc::new(SolidColour { colour: DVec3::ONE }),
+        });
+        let half_transparent = AlphaMask::new(Arc::new(SolidColour {
+            colour: DVec3::splat(0.5),
+        }))
+        .stochastic();
+        let triangles = create_mesh(
+            vec![
+                DVec3::new(0.0, 0.0, -1.0),
+                DVec3::new(1.0, 0.0, -1.0),
+                DVec3::new(0.0, 1.0, -1.0),
+            ],
+            vec![DVec3::Z; 3],
+            vec![DVec2::ZERO; 3],
+            vec![[0, 1, 2]],
+            Arc::new(AlphaMasked::new(glowing, half_transparent)),
+        );
+        let point = DVec3::new(0.3, 0.3, 0.0);
+        let mut rng = StdRng::seed_from_u64(1);
+
+        // Every direction the pdfs generate has their density, wherever the mask lets rays through
+        let spherical = triangles[0].pdf_for_point(point);
+        let mesh = MeshLight::new(triangles).pdf_for_point(point);
+        for pdf in &[spherical, mesh] {
+            for _ in 0..1000 {
+                assert!(pdf.value(pdf.generate(&mut rng)) > 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn cut_outs_follow_mesh_id() {
+        let half_transparent = || {
+            Arc::new(Mesh::new(
+                vec![
+                    DVec3::new(-1.0, -1.0, -1.0),
+                    DVec3::new(1.0, -1.0, -1.0),
+                    DVec3::new(0.0, 1.0, -1.0),
+                ]
+                .into(),
+                vec![DVec3::Z; 3].into(),
+                vec![DVec2::ZERO; 3].into(),
+                vec![[0, 1, 2]],
+                Arc::new(AlphaMasked::new(
+                    Arc::new(Lambertian::new(DVec3::ONE)),
+                    AlphaMask::new(Arc::new(SolidColour {
+                        colour: DVec3::splat(0.5),
+                    }))
+                    .stochastic(),
+                )),
+            ))
+        };
+        let first = half_transparent();
+        let mut second = half_transparent();
+        Arc::get_mut(&mut second).unwrap().id = first.id;
+        let first = Triangle {
+            index: 0,
+            data: first,
+        };
+        let second = Triangle {
+            index: 0,
+            data: second,
+        };
+
+        // Equal meshes in different allocations cut out the same holes
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut hits = 0;
+        for _ in 0..1000 {
+            let ray = Ray {
+                origin: DVec3::ZERO,
+                dir: DVec3::new(rng.gen_range(-0.2..0.2), rng.gen_range(-0.2..0.2), -1.0),
+                time: 0.0,
+                wavelengths: None,
+            };
+            let hit = first.hit(&ray, 0.001, 10.0).is_some();
+            assert_eq!(hit, second.hit(&ray, 0.001, 10.0).is_some());
+            hits += hit as i32;
+        }
+        assert!(hits > 0 && hits < 1000);
+    }
 }
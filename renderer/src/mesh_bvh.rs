@@ -0,0 +1,734 @@
+use std::{
+    cmp::Ordering,
+    convert::TryInto,
+    io::{self, Write},
+    mem::size_of,
+    sync::Arc,
+};
+
+use glam::{DVec2, DVec3, Vec2, Vec3};
+
+use rand::Rng;
+
+use crate::{
+    mesh_light::emissive_mesh_lights, AliasTable, HitRecord, Hittable, Material, Mesh, Ray,
+    SampleableLight, Triangle, UVBuffer, VertexBuffer, AABB,
+};
+
+// Deep enough for any tree built from u32 triangle counts
+const TRAVERSAL_STACK_SIZE: usize = 64;
+
+// Tags for the precision of each serialized vertex buffer
+const DOUBLE_PRECISION: u8 = 0;
+const SINGLE_PRECISION: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BVHBuildParams {
+    pub max_leaf_triangles: u32,
+}
+
+impl Default for BVHBuildParams {
+    fn default() -> Self {
+        Self {
+            max_leaf_triangles: 4,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LinearBVHNode {
+    pub bbox: AABB,
+    /// Leaves: first triangle. Interior nodes: second child, the first child directly follows this node
+    pub offset: u32,
+    /// Number of triangles in a leaf, 0 for interior nodes
+    pub count: u32,
+    /// Axis interior nodes were split on, used to visit the nearer child first
+    pub axis: u8,
+}
+
+/// BVH over the triangles of a single mesh, flattened into an array so it can be cached on disk
+#[derive(Debug)]
+pub struct MeshBVH {
+    mesh: Arc<Mesh>,
+    triangles: Vec<Triangle>,
+    nodes: Vec<LinearBVHNode>,
+    /// Picks triangles by area, for `sample_uniform`
+    area: AliasTable,
+    total_area: f64,
+}
+
+struct BuildTriangle {
+    index: usize,
+    bbox: AABB,
+    centroid: DVec3,
+}
+
+fn build_recursive(
+    nodes: &mut Vec<LinearBVHNode>,
+    triangles: &mut [BuildTriangle],
+    first: usize,
+    params: &BVHBuildParams,
+) {
+    let bbox = triangles
+        .iter()
+        .skip(1)
+        .fold(triangles[0].bbox.clone(), |bbox, triangle| {
+            AABB::surrounding_box(&bbox, &triangle.bbox)
+        });
+
+    let node_index = nodes.len();
+    nodes.push(LinearBVHNode {
+        bbox,
+        offset: first as u32,
+        count: triangles.len() as u32,
+        axis: 0,
+    });
+
+    if triangles.len() <= params.max_leaf_triangles as usize {
+        return;
+    }
+
+    let (centroid_min, centroid_max) = triangles.iter().fold(
+        (DVec3::splat(f64::MAX), DVec3::splat(f64::MIN)),
+        |(min, max), triangle| (min.min(triangle.centroid), max.max(triangle.centroid)),
+    );
+    let extent = centroid_max - centroid_min;
+    let axis = if extent.x > extent.y && extent.x > extent.z {
+        0
+    } else if extent.y > extent.z {
+        1
+    } else {
+        2
+    };
+
+    // All centroids coincide, splitting can't separate anything
+    if extent[axis] <= 0.0 {
+        return;
+    }
+
+    let mid = triangles.len() / 2;
+    triangles.select_nth_unstable_by(mid, |a, b| {
+        a.centroid[axis]
+            .partial_cmp(&b.centroid[axis])
+            .unwrap_or(Ordering::Equal)
+    });
+
+    let (left, right) = triangles.split_at_mut(mid);
+    build_recursive(nodes, left, first, params);
+    let right_index = nodes.len();
+    build_recursive(nodes, right, first + mid, params);
+
+    nodes[node_index].offset = right_index as u32;
+    nodes[node_index].count = 0;
+    nodes[node_index].axis = axis as u8;
+}
+
+impl MeshBVH {
+    /// All triangles must share the same mesh data, as produced by `create_mesh`
+    pub fn new(triangles: Vec<Triangle>, params: BVHBuildParams) -> MeshBVH {
+        assert!(
+            !triangles.is_empty(),
+            "Cannot build a BVH over an empty mesh"
+        );
+        let mesh = triangles[0].data.clone();
+
+        let mut build_triangles = triangles
+            .iter()
+            .enumerate()
+            .map(|(index, triangle)| {
+                let bbox = triangle.bounding_box(0.0, 0.0).unwrap();
+                let centroid = (bbox.min + bbox.max) * 0.5;
+                BuildTriangle {
+                    index,
+                    bbox,
+                    centroid,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut nodes = Vec::with_capacity(2 * triangles.len());
+        build_recursive(&mut nodes, &mut build_triangles, 0, &params);
+
+        let triangles = build_triangles
+            .iter()
+            .map(|build_triangle| triangles[build_triangle.index].clone())
+            .collect();
+
+        MeshBVH::from_parts(mesh, triangles, nodes)
+    }
+
+    fn from_parts(mesh: Arc<Mesh>, triangles: Vec<Triangle>, nodes: Vec<LinearBVHNode>) -> MeshBVH {
+        let areas = triangles.iter().map(Triangle::area).collect::<Vec<_>>();
+        MeshBVH {
+            mesh,
+            area: AliasTable::new(&areas),
+            total_area: areas.iter().sum(),
+            triangles,
+            nodes,
+        }
+    }
+
+    pub fn mesh(&self) -> &Arc<Mesh> {
+        &self.mesh
+    }
+
+    pub fn nodes(&self) -> &[LinearBVHNode] {
+        &self.nodes
+    }
+
+    pub fn triangles(&self) -> &[Triangle] {
+        &self.triangles
+    }
+
+    /// Visits the leaves whose boxes the ray overlaps, nearest child first, passing the index of
+    /// the leaf's first triangle and its triangles.
+    /// `visit_leaf` returns the new `t_max` for the ray, or `None` to stop traversal.
+    fn traverse<'a>(
+        &'a self,
+        ray: &Ray,
+        t_min: f64,
+        mut t_max: f64,
+        mut visit_leaf: impl FnMut(usize, &'a [Triangle], f64) -> Option<f64>,
+    ) {
+        let dir_is_negative = [ray.dir.x < 0.0, ray.dir.y < 0.0, ray.dir.z < 0.0];
+
+        let mut stack = [0usize; TRAVERSAL_STACK_SIZE];
+        let mut stack_size = 0;
+        let mut node_index = 0;
+
+        loop {
+            let node = &self.nodes[node_index];
+
+            if node.bbox.hit(ray, t_min, t_max) {
+                if node.count > 0 {
+                    let first = node.offset as usize;
+                    let last = first + node.count as usize;
+                    t_max = match visit_leaf(first, &self.triangles[first..last], t_max) {
+                        Some(t_max) => t_max,
+                        None => return,
+                    };
+                } else {
+                    let (near, far) = if dir_is_negative[node.axis as usize] {
+                        (node.offset as usize, node_index + 1)
+                    } else {
+                        (node_index + 1, node.offset as usize)
+                    };
+                    stack[stack_size] = far;
+                    stack_size += 1;
+                    node_index = near;
+                    continue;
+                }
+            }
+
+            if stack_size == 0 {
+                return;
+            }
+            stack_size -= 1;
+            node_index = stack[stack_size];
+        }
+    }
+
+    /// Closest hit along with the index of the triangle hit in `triangles()`
+    pub fn hit_triangle(
+        &self,
+        ray: &Ray,
+        t_min: f64,
+        t_max: f64,
+    ) -> Option<(usize, HitRecord<'_>)> {
+        let mut closest = None;
+
+        self.traverse(ray, t_min, t_max, |first, triangles, mut t_max| {
+            for (offset, triangle) in triangles.iter().enumerate() {
+                if let Some(hr) = triangle.hit(ray, t_min, t_max) {
+                    t_max = hr.t;
+                    closest = Some((first + offset, hr));
+                }
+            }
+            Some(t_max)
+        });
+
+        closest
+    }
+
+    /// Closest intersection, ignoring alpha masks, as the index of the triangle and `t`
+    pub(crate) fn intersect_triangle(
+        &self,
+        ray: &Ray,
+        t_min: f64,
+        t_max: f64,
+    ) -> Option<(usize, f64)> {
+        let mut closest = None;
+
+        self.traverse(ray, t_min, t_max, |first, triangles, mut t_max| {
+            for (offset, triangle) in triangles.iter().enumerate() {
+                if let Some((t, _, _)) = triangle.intersect(ray, t_min, t_max) {
+                    t_max = t;
+                    closest = Some((first + offset, t));
+                }
+            }
+            Some(t_max)
+        });
+
+        closest
+    }
+
+    /// Bytes used by the mesh buffers, triangles and nodes
+    pub fn memory_usage(&self) -> usize {
+        self.mesh.memory_usage()
+            + self.triangles.len() * size_of::<Triangle>()
+            + self.nodes.len() * size_of::<LinearBVHNode>()
+    }
+
+    /// Serializes the mesh buffers and flattened nodes, little endian
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        let write_u32 = |writer: &mut dyn Write, value: u32| writer.write_all(&value.to_le_bytes());
+        let write_f32 = |writer: &mut dyn Write, value: f32| writer.write_all(&value.to_le_bytes());
+        let write_f64 = |writer: &mut dyn Write, value: f64| writer.write_all(&value.to_le_bytes());
+
+        for buffer in [&self.mesh.vertices, &self.mesh.normals] {
+            match buffer {
+                VertexBuffer::F64(data) => {
+                    writer.write_all(&[DOUBLE_PRECISION])?;
+                    write_u32(writer, data.len() as u32)?;
+                    for value in data.iter().flat_map(|v| v.to_array()) {
+                        write_f64(writer, value)?;
+                    }
+                }
+                VertexBuffer::F32(data) => {
+                    writer.write_all(&[SINGLE_PRECISION])?;
+                    write_u32(writer, data.len() as u32)?;
+                    for value in data.iter().flat_map(|v| v.to_array()) {
+                        write_f32(writer, value)?;
+                    }
+                }
+            }
+        }
+
+        match &self.mesh.uv {
+            UVBuffer::F64(data) => {
+                writer.write_all(&[DOUBLE_PRECISION])?;
+                write_u32(writer, data.len() as u32)?;
+                for value in data.iter().flat_map(|uv| uv.to_array()) {
+                    write_f64(writer, value)?;
+                }
+            }
+            UVBuffer::F32(data) => {
+                writer.write_all(&[SINGLE_PRECISION])?;
+                write_u32(writer, data.len() as u32)?;
+                for value in data.iter().flat_map(|uv| uv.to_array()) {
+                    write_f32(writer, value)?;
+                }
+            }
+        }
+
+        write_u32(writer, self.mesh.indices.len() as u32)?;
+        for index in self.mesh.indices.iter().flatten() {
+            write_u32(writer, *index)?;
+        }
+
+        write_u32(writer, self.triangles.len() as u32)?;
+        for triangle in &self.triangles {
+            write_u32(writer, triangle.index)?;
+        }
+
+        write_u32(writer, self.nodes.len() as u32)?;
+        for node in &self.nodes {
+            for i in 0..3 {
+                write_f64(writer, node.bbox.min[i])?;
+            }
+            for i in 0..3 {
+                write_f64(writer, node.bbox.max[i])?;
+            }
+            write_u32(writer, node.offset)?;
+            write_u32(writer, node.count)?;
+            writer.write_all(&[node.axis])?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads a mesh written by `write` from the start of `bytes`.
+    /// Returns the mesh and the number of bytes consumed, or `None` if the data is malformed.
+    pub fn read(bytes: &[u8], material: Arc<dyn Material>) -> Option<(MeshBVH, usize)> {
+        let mut reader = ByteReader { bytes, position: 0 };
+
+        let vertices = reader.vertex_buffer()?;
+        let normals = reader.vertex_buffer()?;
+        let uv = match reader.u8()? {
+            DOUBLE_PRECISION => UVBuffer::F64(
+                (0..reader.u32()?)
+                    .map(|_| Some(DVec2::new(reader.f64()?, reader.f64()?)))
+                    .collect::<Option<Vec<_>>>()?,
+            ),
+            SINGLE_PRECISION => UVBuffer::F32(
+                (0..reader.u32()?)
+                    .map(|_| Some(Vec2::new(reader.f32()?, reader.f32()?)))
+                    .collect::<Option<Vec<_>>>()?,
+            ),
+            _ => return None,
+        };
+        let indices = (0..reader.u32()?)
+            .map(|_| Some([reader.u32()?, reader.u32()?, reader.u32()?]))
+            .collect::<Option<Vec<_>>>()?;
+        let triangle_indices = (0..reader.u32()?)
+            .map(|_| reader.u32())
+            .collect::<Option<Vec<_>>>()?;
+        let nodes = (0..reader.u32()?)
+            .map(|_| {
+                Some(LinearBVHNode {
+                    bbox: AABB {
+                        min: reader.dvec3()?,
+                        max: reader.dvec3()?,
+                    },
+                    offset: reader.u32()?,
+                    count: reader.u32()?,
+                    axis: reader.u8()?,
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        let vertex_count = vertices.len() as u32;
+        if indices.iter().flatten().any(|&index| index >= vertex_count)
+            || triangle_indices
+                .iter()
+                .any(|&index| index as usize >= indices.len())
+        {
+            return None;
+        }
+
+        // Traversal would panic on nodes pointing outside the buffers, and loop forever on
+        // children pointing back up the tree
+        let in_bounds = |(index, node): (usize, &LinearBVHNode)| {
+            if node.count > 0 {
+                node.offset as usize + node.count as usize <= triangle_indices.len()
+            } else {
+                index + 1 < nodes.len()
+                    && (node.offset as usize) < nodes.len()
+                    && node.offset as usize > index
+                    && node.axis < 3
+            }
+        };
+        if nodes.is_empty() || !nodes.iter().enumerate().all(in_bounds) {
+            return None;
+        }
+
+        // Interior nodes push one entry onto the traversal stack for every interior ancestor.
+        // Children always come after their parents, so depths are final when a node is reached.
+        let mut depths = vec![0; nodes.len()];
+        for (index, node) in nodes.iter().enumerate() {
+            if node.count == 0 {
+                if depths[index] >= TRAVERSAL_STACK_SIZE {
+                    return None;
+                }
+                for child in [index + 1, node.offset as usize] {
+                    depths[child] = depths[child].max(depths[index] + 1);
+                }
+            }
+        }
+
+        let mesh = Arc::new(Mesh::new(vertices, normals, uv, indices, material));
+        let triangles = triangle_indices
+            .into_iter()
+            .map(|index| Triangle {
+                index,
+                data: mesh.clone(),
+            })
+            .collect();
+
+        Some((MeshBVH::from_parts(mesh, triangles, nodes), reader.position))
+    }
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self.bytes.get(self.position..self.position + N)?;
+        self.position += N;
+        bytes.try_into().ok()
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take()?))
+    }
+
+    fn f32(&mut self) -> Option<f32> {
+        Some(f32::from_le_bytes(self.take()?))
+    }
+
+    fn f64(&mut self) -> Option<f64> {
+        Some(f64::from_le_bytes(self.take()?))
+    }
+
+    fn dvec3(&mut self) -> Option<DVec3> {
+        Some(DVec3::new(self.f64()?, self.f64()?, self.f64()?))
+    }
+
+    fn vec3(&mut self) -> Option<Vec3> {
+        Some(Vec3::new(self.f32()?, self.f32()?, self.f32()?))
+    }
+
+    fn vertex_buffer(&mut self) -> Option<VertexBuffer> {
+        match self.u8()? {
+            DOUBLE_PRECISION => Some(VertexBuffer::F64(
+                (0..self.u32()?)
+                    .map(|_| self.dvec3())
+                    .collect::<Option<Vec<_>>>()?,
+            )),
+            SINGLE_PRECISION => Some(VertexBuffer::F32(
+                (0..self.u32()?)
+                    .map(|_| self.vec3())
+                    .collect::<Option<Vec<_>>>()?,
+            )),
+            _ => None,
+        }
+    }
+}
+
+impl Hittable for MeshBVH {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        self.hit_triangle(ray, t_min, t_max).map(|(_, hr)| hr)
+    }
+
+    fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+        let mut occluded = false;
+
+        self.traverse(ray, t_min, t_max, |_, triangles, t_max| {
+            occluded = triangles
+                .iter()
+                .any(|triangle| triangle.occluded(ray, t_min, t_max));
+            if occluded {
+                None
+            } else {
+                Some(t_max)
+            }
+        });
+
+        occluded
+    }
+
+    fn bounding_box(&self, _: f64, _: f64) -> Option<AABB> {
+        self.nodes.first().map(|root| root.bbox.clone())
+    }
+
+    /// Uniform over the total area of the triangles
+    fn sample_uniform(&self, rng: &mut dyn rand::RngCore) -> DVec3 {
+        let (triangle, _) = self.area.sample(rng.gen());
+        self.triangles[triangle].sample_uniform(rng)
+    }
+
+    fn pdf_uniform(&self, _: DVec3) -> f64 {
+        1.0 / self.total_area
+    }
+
+    fn emissive_lights(self: Arc<Self>) -> Vec<Arc<dyn SampleableLight>> {
+        emissive_mesh_lights(self.triangles())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::{create_mesh, Lambertian};
+
+    use super::*;
+
+    fn random_mesh(count: u32) -> Vec<Triangle> {
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+
+        for i in 0..count {
+            let center = DVec3::new(
+                rng.gen_range(-5.0..5.0),
+                rng.gen_range(-5.0..5.0),
+                rng.gen_range(-5.0..5.0),
+            );
+            for _ in 0..3 {
+                vertices.push(
+                    center
+                        + DVec3::new(
+                            rng.gen_range(-0.5..0.5),
+                            rng.gen_range(-0.5..0.5),
+                            rng.gen_range(-0.5..0.5),
+                        ),
+                );
+            }
+            indices.push([3 * i, 3 * i + 1, 3 * i + 2]);
+        }
+
+        let normals = vec![DVec3::Z; vertices.len()];
+        let uv = vec![DVec2::ZERO; vertices.len()];
+        create_mesh(
+            vertices,
+            normals,
+            uv,
+            indices,
+            Arc::new(Lambertian::new(DVec3::splat(0.5))),
+        )
+    }
+
+    fn random_rays(count: usize) -> Vec<Ray> {
+        let mut rng = StdRng::seed_from_u64(7);
+        (0..count)
+            .map(|_| Ray {
+                origin: DVec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), 10.0),
+                dir: DVec3::new(rng.gen_range(-0.5..0.5), rng.gen_range(-0.5..0.5), -1.0),
+                time: 0.0,
+                wavelengths: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        let triangles = random_mesh(200);
+        let bvh = MeshBVH::new(random_mesh(200), BVHBuildParams::default());
+
+        for ray in random_rays(500) {
+            let expected = triangles.hit(&ray, 0.001, 100.0).map(|hr| hr.t);
+            let actual = bvh.hit(&ray, 0.001, 100.0).map(|hr| hr.t);
+            assert_eq!(expected, actual);
+            assert_eq!(expected.is_some(), bvh.occluded(&ray, 0.001, 100.0));
+        }
+    }
+
+    #[test]
+    fn serialization_round_trip() {
+        let bvh = MeshBVH::new(random_mesh(50), BVHBuildParams::default());
+
+        let mut bytes = Vec::new();
+        bvh.write(&mut bytes).unwrap();
+
+        let (read, consumed) =
+            MeshBVH::read(&bytes, Arc::new(Lambertian::new(DVec3::splat(0.5)))).unwrap();
+        assert_eq!(consumed, bytes.len());
+        assert_eq!(read.nodes(), bvh.nodes());
+
+        for ray in random_rays(100) {
+            assert_eq!(
+                bvh.hit(&ray, 0.001, 100.0).map(|hr| hr.t),
+                read.hit(&ray, 0.001, 100.0).map(|hr| hr.t)
+            );
+        }
+    }
+
+    #[test]
+    fn uniform_sampling_follows_area() {
+        // A right triangle of area 0.5 at x < 0 and one of area 1.5 at x > 10
+        let triangles = create_mesh(
+            vec![
+                DVec3::new(-1.0, 0.0, 0.0),
+                DVec3::new(0.0, 0.0, 0.0),
+                DVec3::new(0.0, 1.0, 0.0),
+                DVec3::new(10.0, 0.0, 0.0),
+                DVec3::new(13.0, 0.0, 0.0),
+                DVec3::new(13.0, 1.0, 0.0),
+            ],
+            vec![DVec3::Z; 6],
+            vec![DVec2::ZERO; 6],
+            vec![[0, 1, 2], [3, 4, 5]],
+            Arc::new(Lambertian::new(DVec3::splat(0.5))),
+        );
+        let bvh = MeshBVH::new(triangles.clone(), BVHBuildParams::default());
+        assert!((bvh.pdf_uniform(DVec3::ZERO) - 0.5).abs() < 1e-12);
+        assert_eq!(
+            bvh.pdf_uniform(DVec3::ZERO),
+            triangles.pdf_uniform(DVec3::ZERO)
+        );
+
+        let mut rng = StdRng::seed_from_u64(6);
+        let samples = 20_000;
+        let large = (0..samples)
+            .filter(|_| bvh.sample_uniform(&mut rng).x > 5.0)
+            .count();
+        let fraction = large as f64 / samples as f64;
+        assert!((fraction - 0.75).abs() < 0.02, "fraction was {}", fraction);
+    }
+
+    #[test]
+    fn rejects_cyclic_and_deep_trees() {
+        let bvh = MeshBVH::new(random_mesh(50), BVHBuildParams::default());
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(DVec3::splat(0.5)));
+        let mut bytes = Vec::new();
+        bvh.write(&mut bytes).unwrap();
+
+        // Everything before the nodes, then nodes written as bbox, offset, count and axis
+        const NODE_SIZE: usize = 6 * 8 + 4 + 4 + 1;
+        let header = bytes.len() - 4 - bvh.nodes().len() * NODE_SIZE;
+        let with_nodes = |nodes: &[(u32, u32)]| {
+            let mut bytes = bytes[..header].to_vec();
+            bytes.extend((nodes.len() as u32).to_le_bytes());
+            for &(offset, count) in nodes {
+                for value in [-1.0f64, -1.0, -1.0, 1.0, 1.0, 1.0] {
+                    bytes.extend(value.to_le_bytes());
+                }
+                bytes.extend(offset.to_le_bytes());
+                bytes.extend(count.to_le_bytes());
+                bytes.push(0);
+            }
+            bytes
+        };
+
+        // Root whose second child is itself
+        let cyclic = with_nodes(&[(0, 0), (0, 1)]);
+        assert!(MeshBVH::read(&cyclic, material.clone()).is_none());
+
+        // Chains of interior nodes, both children being the next node
+        let chain = |interior: u32| {
+            let mut nodes = (1..=interior).map(|next| (next, 0)).collect::<Vec<_>>();
+            nodes.push((0, 1));
+            with_nodes(&nodes)
+        };
+        assert!(MeshBVH::read(&chain(TRAVERSAL_STACK_SIZE as u32), material.clone()).is_some());
+        assert!(MeshBVH::read(&chain(TRAVERSAL_STACK_SIZE as u32 + 1), material).is_none());
+    }
+
+    #[test]
+    fn single_precision_round_trip() {
+        let triangles = random_mesh(50);
+        let mesh = &triangles[0].data;
+        let to_f32 = |buffer: &VertexBuffer| match buffer {
+            VertexBuffer::F64(data) => data.iter().map(|v| v.as_f32()).collect::<Vec<_>>(),
+            VertexBuffer::F32(data) => data.clone(),
+        };
+        let single = create_mesh(
+            to_f32(&mesh.vertices),
+            to_f32(&mesh.normals),
+            vec![Vec2::ZERO; mesh.vertices.len()],
+            mesh.indices.clone(),
+            mesh.material.clone(),
+        );
+        let bvh = MeshBVH::new(single, BVHBuildParams::default());
+
+        let mut bytes = Vec::new();
+        bvh.write(&mut bytes).unwrap();
+
+        let (read, consumed) = MeshBVH::read(&bytes, mesh.material.clone()).unwrap();
+        assert_eq!(consumed, bytes.len());
+        assert_eq!(read.mesh().vertices, bvh.mesh().vertices);
+        assert!(matches!(read.mesh().uv, UVBuffer::F32(_)));
+        assert!(
+            bvh.memory_usage() < MeshBVH::new(triangles, BVHBuildParams::default()).memory_usage()
+        );
+    }
+
+    #[test]
+    fn truncated_data_is_rejected() {
+        let bvh = MeshBVH::new(random_mesh(10), BVHBuildParams::default());
+
+        let mut bytes = Vec::new();
+        bvh.write(&mut bytes).unwrap();
+        bytes.truncate(bytes.len() - 1);
+
+        assert!(MeshBVH::read(&bytes, Arc::new(Lambertian::new(DVec3::ZERO))).is_none());
+    }
+}
@@ -0,0 +1,266 @@
+use std::{collections::HashMap, sync::Arc};
+
+use glam::DVec3;
+use rand::Rng;
+
+use crate::{
+    AliasTable, BVHBuildParams, HitRecord, Hittable, MeshBVH, Ray, SampleableLight, Triangle, AABB,
+    PDF,
+};
+
+struct MeshLightData {
+    bvh: MeshBVH,
+    /// Picks triangles by emitted power, for sampling the light
+    power: AliasTable,
+    total_power: f64,
+}
+
+/// An emissive mesh that can be added to `Scene::lights`.
+/// Triangles are sampled in proportion to their power, using the emission at their centroids.
+#[derive(Clone)]
+pub struct MeshLight {
+    data: Arc<MeshLightData>,
+}
+
+impl MeshLight {
+    pub fn new(triangles: Vec<Triangle>) -> MeshLight {
+        MeshLight::from_bvh(MeshBVH::new(triangles, BVHBuildParams::default()))
+    }
+
+    pub fn from_bvh(bvh: MeshBVH) -> MeshLight {
+        let powers = bvh
+            .triangles()
+            .iter()
+            .map(|triangle| triangle.power())
+            .collect::<Vec<_>>();
+
+        MeshLight {
+            data: Arc::new(MeshLightData {
+                power: AliasTable::new(&powers),
+                total_power: powers.iter().sum(),
+                bvh,
+            }),
+        }
+    }
+
+    /// Probability density per unit area of sampling `triangle` at any point on it
+    fn pdf_area(&self, triangle: usize) -> f64 {
+        self.data.power.pmf(triangle) / self.data.bvh.triangles()[triangle].area()
+    }
+}
+
+impl Hittable for MeshLight {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        self.data.bvh.hit(ray, t_min, t_max)
+    }
+
+    fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+        self.data.bvh.occluded(ray, t_min, t_max)
+    }
+
+    fn bounding_box(&self, time_0: f64, time_1: f64) -> Option<AABB> {
+        self.data.bvh.bounding_box(time_0, time_1)
+    }
+
+    fn sample_uniform(&self, rng: &mut dyn rand::RngCore) -> DVec3 {
+        self.data.bvh.sample_uniform(rng)
+    }
+
+    fn pdf_uniform(&self, point: DVec3) -> f64 {
+        self.data.bvh.pdf_uniform(point)
+    }
+
+    fn emissive_lights(self: Arc<Self>) -> Vec<Arc<dyn SampleableLight>> {
+        vec![self]
+    }
+}
+
+impl SampleableLight for MeshLight {
+    fn pdf_for_point(&self, point: DVec3) -> Box<dyn PDF> {
+        Box::new(MeshLightPDF {
+            origin: point,
+            light: self.clone(),
+        })
+    }
+
+    fn power(&self) -> f64 {
+        self.data.total_power
+    }
+}
+
+/// A `MeshLight` for the emissive triangles of each mesh among `triangles`
+pub(crate) fn emissive_mesh_lights(triangles: &[Triangle]) -> Vec<Arc<dyn SampleableLight>> {
+    // `MeshBVH` indexes every triangle into one mesh's buffers, so meshes are kept apart
+    let mut groups: Vec<Vec<Triangle>> = Vec::new();
+    let mut group_of_mesh = HashMap::new();
+    for triangle in triangles
+        .iter()
+        .filter(|triangle| triangle.data.material.is_emissive())
+    {
+        let group = *group_of_mesh
+            .entry(Arc::as_ptr(&triangle.data))
+            .or_insert_with(|| {
+                groups.push(Vec::new());
+                groups.len() - 1
+            });
+        groups[group].push(triangle.clone());
+    }
+
+    groups
+        .into_iter()
+        .map(|group| Arc::new(MeshLight::new(group)) as Arc<dyn SampleableLight>)
+        .collect()
+}
+
+/// Directions towards a power-weighted triangle of the light, then a uniform point on it
+pub struct MeshLightPDF {
+    origin: DVec3,
+    light: MeshLight,
+}
+
+impl PDF for MeshLightPDF {
+    fn value(&self, direction: DVec3) -> f64 {
+        let ray = Ray {
+            origin: self.origin,
+            dir: direction.normalize(),
+            time: 0.0,
+            wavelengths: None,
+        };
+
+        // Cut-outs are ignored so every direction through the light has one density
+        let bvh = &self.light.data.bvh;
+        match bvh.intersect_triangle(&ray, 0.0001, f64::INFINITY) {
+            Some((triangle, t)) => {
+                let [p0, p1, p2] = bvh.triangles()[triangle].positions();
+                let normal = (p1 - p0).cross(p2 - p0).normalize();
+                let cosine = normal.dot(ray.dir).abs();
+                if cosine == 0.0 {
+                    return 0.0;
+                }
+                self.light.pdf_area(triangle) * t * t / cosine
+            }
+            None => 0.0,
+        }
+    }
+
+    fn generate(&self, rng: &mut dyn rand::RngCore) -> DVec3 {
+        let (triangle, _) = self.light.data.power.sample(rng.gen());
+        let point = self.light.data.bvh.triangles()[triangle].sample_uniform(rng);
+        (point - self.origin).normalize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::DVec2;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{create_mesh, DiffuseLight, Material, SolidColour, Texture};
+
+    use super::*;
+
+    /// Brighter on the right half of the texture
+    #[derive(Debug)]
+    struct Split;
+
+    impl Texture for Split {
+        fn sample(&self, u: f64, _: f64, _: DVec3) -> DVec3 {
+            if u > 0.5 {
+                DVec3::splat(3.0)
+            } else {
+                DVec3::splat(1.0)
+            }
+        }
+    }
+
+    fn quad(material: Arc<dyn Material>) -> Vec<Triangle> {
+        // Two unit squares side by side at z = -1, facing +z
+        create_mesh(
+            vec![
+                DVec3::new(-1.0, 0.0, -1.0),
+                DVec3::new(0.0, 0.0, -1.0),
+                DVec3::new(1.0, 0.0, -1.0),
+                DVec3::new(-1.0, 1.0, -1.0),
+                DVec3::new(0.0, 1.0, -1.0),
+                DVec3::new(1.0, 1.0, -1.0),
+            ],
+            vec![DVec3::Z; 6],
+            vec![
+                DVec2::new(0.0, 0.0),
+                DVec2::new(0.5, 0.0),
+                DVec2::new(1.0, 0.0),
+                DVec2::new(0.0, 1.0),
+                DVec2::new(0.5, 1.0),
+                DVec2::new(1.0, 1.0),
+            ],
+            vec![[0, 1, 4], [0, 4, 3], [1, 2, 5], [1, 5, 4]],
+            material,
+        )
+    }
+
+    #[test]
+    fn power_weighted_sampling() {
+        let light = MeshLight::new(quad(Arc::new(DiffuseLight {
+            emit_colour: Arc::new(Split),
+        })));
+        let pdf = light.pdf_for_point(DVec3::new(0.0, 0.5, 0.0));
+
+        let mut rng = StdRng::seed_from_u64(3);
+        let samples = 20_000;
+        let bright = (0..samples)
+            .filter(|_| pdf.generate(&mut rng).x > 0.0)
+            .count();
+
+        // The right half emits three times as much
+        let fraction = bright as f64 / samples as f64;
+        assert!((fraction - 0.75).abs() < 0.02);
+    }
+
+    #[test]
+    fn pdf_integrates_to_one() {
+        // Estimate the integral of the pdf over the sphere of directions by sampling uniformly
+        let light = MeshLight::new(quad(Arc::new(DiffuseLight {
+            emit_colour: Arc::new(Split),
+        })));
+        let pdf = light.pdf_for_point(DVec3::new(0.2, 0.4, 0.0));
+
+        let mut rng = StdRng::seed_from_u64(4);
+        let samples = 200_000;
+        let sum: f64 = (0..samples)
+            .map(|_| pdf.value(crate::rand_in_unit_sphere(&mut rng).normalize()))
+            .sum();
+        let integral = sum * 4.0 * std::f64::consts::PI / samples as f64;
+
+        assert!((integral - 1.0).abs() < 0.05, "integral was {}", integral);
+    }
+
+    #[test]
+    fn uniform_pdf_is_over_total_area() {
+        let light = MeshLight::new(quad(Arc::new(DiffuseLight {
+            emit_colour: Arc::new(SolidColour { colour: DVec3::ONE }),
+        })));
+        assert!((light.pdf_uniform(DVec3::ZERO) - 0.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn one_light_per_emissive_mesh() {
+        let white: Arc<dyn Material> = Arc::new(DiffuseLight {
+            emit_colour: Arc::new(SolidColour { colour: DVec3::ONE }),
+        });
+        let split: Arc<dyn Material> = Arc::new(DiffuseLight {
+            emit_colour: Arc::new(Split),
+        });
+        let grey: Arc<dyn Material> = Arc::new(crate::Lambertian::new(DVec3::splat(0.5)));
+
+        let mut triangles = quad(white.clone());
+        triangles.extend(quad(grey));
+        triangles.extend(quad(split.clone()));
+        let lights = emissive_mesh_lights(&triangles);
+        assert_eq!(lights.len(), 2);
+
+        for (light, material) in lights.iter().zip(&[white, split]) {
+            let alone = MeshLight::new(quad(material.clone()));
+            assert!((light.power() - alone.power()).abs() < 1e-12);
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use std::f64::consts::PI;
+
+use glam::{DVec2, DVec3};
+
+/// Below this roughness surfaces are treated as perfectly smooth
+const SMOOTH_ALPHA: f64 = 1e-3;
+
+/// Trowbridge-Reitz (GGX) distribution of microfacet normals, in a local frame with the
+/// surface normal along z and `alpha_x` along the tangent
+#[derive(Clone, Copy, Debug)]
+pub struct TrowbridgeReitz {
+    pub alpha_x: f64,
+    pub alpha_y: f64,
+}
+
+impl TrowbridgeReitz {
+    /// Perceptual roughness in [0, 1] along each tangent direction, squared into alphas
+    pub fn from_roughness(roughness_x: f64, roughness_y: f64) -> TrowbridgeReitz {
+        TrowbridgeReitz {
+            alpha_x: roughness_x.clamp(0.0, 1.0).powi(2),
+            alpha_y: roughness_y.clamp(0.0, 1.0).powi(2),
+        }
+    }
+
+    /// Too smooth to sample as anything but a perfect mirror
+    pub fn is_smooth(&self) -> bool {
+        self.alpha_x.max(self.alpha_y) < SMOOTH_ALPHA
+    }
+
+    /// Density of microfacet normals `wm`
+    pub fn d(&self, wm: DVec3) -> f64 {
+        if wm.z <= 0.0 {
+            return 0.0;
+        }
+        let e = (wm.x / self.alpha_x).powi(2) + (wm.y / self.alpha_y).powi(2) + wm.z * wm.z;
+        1.0 / (PI * self.alpha_x * self.alpha_y * e * e)
+    }
+
+    /// Smith's auxiliary function, the microfacet area hidden from `w` per visible area
+    pub fn lambda(&self, w: DVec3) -> f64 {
+        if w.z == 0.0 {
+            return f64::INFINITY;
+        }
+        let alpha_2_tan_2 =
+            ((self.alpha_x * w.x).powi(2) + (self.alpha_y * w.y).powi(2)) / (w.z * w.z);
+        ((1.0 + alpha_2_tan_2).sqrt() - 1.0) / 2.0
+    }
+
+    /// Fraction of microfacets visible from `w`
+    pub fn g1(&self, w: DVec3) -> f64 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    /// Fraction of microfacets visible from both `wo` and `wi`, height-correlated
+    pub fn g(&self, wo: DVec3, wi: DVec3) -> f64 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    /// Density of normals `wm` visible from `w`
+    pub fn d_visible(&self, w: DVec3, wm: DVec3) -> f64 {
+        if w.z == 0.0 {
+            return 0.0;
+        }
+        self.g1(w) / w.z.abs() * self.d(wm) * w.dot(wm).abs()
+    }
+
+    /// Samples a normal visible from `w`, Heitz 2018
+    pub fn sample_wm(&self, w: DVec3, u: DVec2) -> DVec3 {
+        // Stretch into the hemisphere configuration where the distribution is isotropic
+        let mut wh = DVec3::new(self.alpha_x * w.x, self.alpha_y * w.y, w.z).normalize();
+        if wh.z < 0.0 {
+            wh = -wh;
+        }
+
+        let t1 = if wh.z < 0.99999 {
+            DVec3::Z.cross(wh).normalize()
+        } else {
+            DVec3::X
+        };
+        let t2 = wh.cross(t1);
+
+        // Uniform disc sample, warped onto the visible half
+        let r = u.x.sqrt();
+        let phi = 2.0 * PI * u.y;
+        let px = r * phi.cos();
+        let h = (1.0 - px * px).sqrt();
+        let s = (1.0 + wh.z) / 2.0;
+        let py = (1.0 - s) * h + s * r * phi.sin();
+        let pz = (1.0 - px * px - py * py).max(0.0).sqrt();
+
+        let nh = px * t1 + py * t2 + pz * wh;
+        DVec3::new(self.alpha_x * nh.x, self.alpha_y * nh.y, nh.z.max(1e-6)).normalize()
+    }
+}
+
+/// Complex index of refraction of a conductor per RGB channel
+#[derive(Clone, Copy, Debug)]
+pub struct ComplexIor {
+    pub eta: DVec3,
+    pub k: DVec3,
+}
+
+impl ComplexIor {
+    pub fn gold() -> ComplexIor {
+        ComplexIor {
+            eta: DVec3::new(0.143, 0.374, 1.442),
+            k: DVec3::new(3.983, 2.385, 1.603),
+        }
+    }
+
+    pub fn silver() -> ComplexIor {
+        ComplexIor {
+            eta: DVec3::new(0.155, 0.117, 0.138),
+            k: DVec3::new(4.828, 3.122, 2.147),
+        }
+    }
+
+    pub fn copper() -> ComplexIor {
+        ComplexIor {
+            eta: DVec3::new(0.200, 0.924, 1.102),
+            k: DVec3::new(3.912, 2.452, 2.142),
+        }
+    }
+
+    pub fn aluminium() -> ComplexIor {
+        ComplexIor {
+            eta: DVec3::new(1.657, 0.880, 0.521),
+            k: DVec3::new(9.224, 6.270, 4.837),
+        }
+    }
+
+    pub fn iron() -> ComplexIor {
+        ComplexIor {
+            eta: DVec3::new(2.911, 2.950, 2.585),
+            k: DVec3::new(3.089, 2.932, 2.767),
+        }
+    }
+
+    /// Reflectance at `cos_theta` from the surface normal, per channel
+    pub fn fresnel(&self, cos_theta: f64) -> DVec3 {
+        DVec3::new(
+            fresnel_conductor(cos_theta, self.eta.x, self.k.x),
+            fresnel_conductor(cos_theta, self.eta.y, self.k.y),
+            fresnel_conductor(cos_theta, self.eta.z, self.k.z),
+        )
+    }
+}
+
+/// Exact Fresnel reflectance of unpolarised light on a conductor with complex IOR `eta + ik`
+pub fn fresnel_conductor(cos_theta: f64, eta: f64, k: f64) -> f64 {
+    let cos_2 = cos_theta.clamp(0.0, 1.0).powi(2);
+    let sin_2 = 1.0 - cos_2;
+
+    let t0 = eta * eta - k * k - sin_2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+    let t1 = a2_plus_b2 + cos_2;
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t2 = 2.0 * cos_2.sqrt() * a;
+    let rs = (t1 - t2) / (t1 + t2);
+
+    let t3 = cos_2 * a2_plus_b2 + sin_2 * sin_2;
+    let t4 = t2 * sin_2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+
+    0.5 * (rp + rs)
+}
+
+/// Exact Fresnel reflectance of unpolarised light at a dielectric interface, where `eta` is the
+/// IOR on the far side of the normal over the IOR on its side. Light arriving from below the
+/// normal has a negative `cos_theta_i`.
+pub fn fresnel_dielectric(cos_theta_i: f64, eta: f64) -> f64 {
+    let (cos_theta_i, eta) = if cos_theta_i < 0.0 {
+        (-cos_theta_i.max(-1.0), 1.0 / eta)
+    } else {
+        (cos_theta_i.min(1.0), eta)
+    };
+
+    let sin_2_theta_t = (1.0 - cos_theta_i * cos_theta_i) / (eta * eta);
+    if sin_2_theta_t >= 1.0 {
+        // Total internal reflection
+        return 1.0;
+    }
+    let cos_theta_t = (1.0 - sin_2_theta_t).sqrt();
+
+    let r_parallel = (eta * cos_theta_i - cos_theta_t) / (eta * cos_theta_i + cos_theta_t);
+    let r_perpendicular = (cos_theta_i - eta * cos_theta_t) / (cos_theta_i + eta * cos_theta_t);
+    (r_parallel * r_parallel + r_perpendicular * r_perpendicular) / 2.0
+}
+
+/// Refracts `wi`, pointing away from the surface on the side of `normal`, through an interface
+/// with relative IOR `eta`. `None` on total internal reflection.
+pub fn refract_through(wi: DVec3, normal: DVec3, eta: f64) -> Option<DVec3> {
+    let cos_theta_i = normal.dot(wi);
+    let sin_2_theta_t = (1.0 - cos_theta_i * cos_theta_i).max(0.0) / (eta * eta);
+    if sin_2_theta_t >= 1.0 {
+        return None;
+    }
+    let cos_theta_t = (1.0 - sin_2_theta_t).sqrt();
+    Some(-wi / eta + (cos_theta_i / eta - cos_theta_t) * normal)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::{rand_in_unit_sphere, UniformConePDF, PDF};
+
+    #[test]
+    fn distribution_projects_to_unit_area() {
+        let distribution = TrowbridgeReitz::from_roughness(0.5, 0.8);
+        let mut rng = StdRng::seed_from_u64(4);
+
+        // Integrate D(wm) cos(theta_m) over the hemisphere with uniform samples
+        let samples = 400_000;
+        let mut total = 0.0;
+        for _ in 0..samples {
+            let mut wm = rand_in_unit_sphere(&mut rng).normalize();
+            wm.z = wm.z.abs();
+            total += distribution.d(wm) * wm.z * 2.0 * PI;
+        }
+        let projected_area = total / samples as f64;
+        assert!((projected_area - 1.0).abs() < 0.02, "{}", projected_area);
+    }
+
+    #[test]
+    fn visible_normals_match_density() {
+        let distribution = TrowbridgeReitz::from_roughness(0.7, 0.4);
+        let w = DVec3::new(0.5, -0.3, 0.6).normalize();
+        let mut rng = StdRng::seed_from_u64(5);
+
+        // Fraction of sampled normals in a cone against the integral of their density over it
+        let axis = DVec3::new(0.2, 0.1, 1.0).normalize();
+        let cos_cone = 0.95;
+
+        let samples = 200_000;
+        let in_cone = (0..samples)
+            .filter(|_| {
+                distribution
+                    .sample_wm(w, DVec2::new(rng.gen(), rng.gen()))
+                    .dot(axis)
+                    > cos_cone
+            })
+            .count() as f64
+            / samples as f64;
+
+        let cone = UniformConePDF::new(axis, cos_cone);
+        let solid_angle = 2.0 * PI * (1.0 - cos_cone);
+        let integral = (0..samples)
+            .map(|_| distribution.d_visible(w, cone.generate(&mut rng)))
+            .sum::<f64>()
+            * solid_angle
+            / samples as f64;
+
+        assert!(
+            (in_cone - integral).abs() < 0.01,
+            "{} vs {}",
+            in_cone,
+            integral
+        );
+    }
+
+    #[test]
+    fn conductor_fresnel() {
+        // No absorption reduces to a dielectric at normal incidence
+        assert!((fresnel_conductor(1.0, 1.5, 0.0) - 0.04).abs() < 1e-9);
+        assert!((fresnel_conductor(0.0, 0.2, 3.9) - 1.0).abs() < 1e-9);
+
+        assert!((fresnel_dielectric(1.0, 1.5) - 0.04).abs() < 1e-9);
+        assert!((fresnel_dielectric(-1.0, 1.0 / 1.5) - 0.04).abs() < 1e-9);
+        // Past the critical angle inside glass
+        assert_eq!(fresnel_dielectric(0.5, 1.0 / 1.5), 1.0);
+        // Brewster's angle reflects only the perpendicular polarisation
+        let brewster = 1.5f64.atan().cos();
+        let r_perpendicular = ((brewster - 1.5 * (1.0 - (1.0 - brewster.powi(2)) / 2.25).sqrt())
+            / (brewster + 1.5 * (1.0 - (1.0 - brewster.powi(2)) / 2.25).sqrt()))
+        .powi(2);
+        assert!((fresnel_dielectric(brewster, 1.5) - r_perpendicular / 2.0).abs() < 1e-9);
+
+        let gold = ComplexIor::gold().fresnel(1.0);
+        assert!(gold.x > gold.y && gold.y > gold.z);
+        assert!(gold.x > 0.9);
+    }
+}
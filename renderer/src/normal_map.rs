@@ -0,0 +1,193 @@
+use std::sync::Arc;
+
+use glam::DVec3;
+
+use crate::{AlphaMask, HitRecord, Material, Ray, Texture, PDF};
+
+// Step in texture space used to difference bump maps
+const BUMP_DELTA: f64 = 0.0005;
+
+#[derive(Debug)]
+pub enum SurfaceDetail {
+    /// Tangent space normals encoded as colours, with +z away from the surface
+    NormalMap(Arc<dyn Texture>),
+    /// Height field, `scale` converts texture values to distances along the normal
+    BumpMap {
+        height: Arc<dyn Texture>,
+        scale: f64,
+    },
+}
+
+/// Wraps a material, perturbing the shading normal of surfaces using it
+#[derive(Debug)]
+pub struct NormalMapped {
+    pub material: Arc<dyn Material>,
+    pub detail: SurfaceDetail,
+}
+
+fn height(texture: &dyn Texture, u: f64, v: f64, p: DVec3) -> f64 {
+    let sample = texture.sample(u, v, p);
+    (sample.x + sample.y + sample.z) / 3.0
+}
+
+fn normal_mapped(texture: &dyn Texture, hit_record: &HitRecord) -> Option<DVec3> {
+    let normal = hit_record.normal;
+    let tangent = (hit_record.dpdu - normal * normal.dot(hit_record.dpdu)).normalize_or_zero();
+    if tangent == DVec3::ZERO {
+        return None;
+    }
+    let mut bitangent = normal.cross(tangent);
+    if bitangent.dot(hit_record.dpdv) < 0.0 {
+        bitangent = -bitangent;
+    }
+
+    let local = texture.sample(hit_record.u, hit_record.v, hit_record.point) * 2.0 - DVec3::ONE;
+    let mapped = (local.x * tangent + local.y * bitangent + local.z * normal).normalize_or_zero();
+    if mapped == DVec3::ZERO {
+        None
+    } else {
+        Some(mapped)
+    }
+}
+
+fn bump_mapped(texture: &dyn Texture, scale: f64, hit_record: &HitRecord) -> Option<DVec3> {
+    let (u, v, p) = (hit_record.u, hit_record.v, hit_record.point);
+    let (dpdu, dpdv) = (hit_record.dpdu, hit_record.dpdv);
+
+    let base = height(texture, u, v, p);
+    let shifted_u = height(texture, u + BUMP_DELTA, v, p + BUMP_DELTA * dpdu);
+    let shifted_v = height(texture, u, v + BUMP_DELTA, p + BUMP_DELTA * dpdv);
+
+    // Derivatives of the displaced surface p + h(u, v) * n, ignoring the change in n
+    let normal = hit_record.normal;
+    let displaced_dpdu = dpdu + (shifted_u - base) / BUMP_DELTA * scale * normal;
+    let displaced_dpdv = dpdv + (shifted_v - base) / BUMP_DELTA * scale * normal;
+
+    let bumped = displaced_dpdu.cross(displaced_dpdv).normalize_or_zero();
+    if bumped == DVec3::ZERO {
+        return None;
+    }
+
+    // dpdu x dpdv points out of the surface, keep the side the shading normal is on
+    if dpdu.cross(dpdv).dot(normal) < 0.0 {
+        Some(-bumped)
+    } else {
+        Some(bumped)
+    }
+}
+
+impl Material for NormalMapped {
+    fn emitted(&self, u: f64, v: f64, p: DVec3) -> DVec3 {
+        self.material.emitted(u, v, p)
+    }
+
+    fn scattering_pdf(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<Box<dyn PDF>> {
+        self.material.scattering_pdf(ray_in, hit_record)
+    }
+
+    fn brdf(&self, ray_in: &Ray, hit_record: &HitRecord, ray_out: &Ray) -> DVec3 {
+        self.material.brdf(ray_in, hit_record, ray_out)
+    }
+
+    fn is_specular(&self) -> bool {
+        self.material.is_specular()
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn emitted_spectral(&self, u: f64, v: f64, p: DVec3, wavelengths: DVec3) -> DVec3 {
+        self.material.emitted_spectral(u, v, p, wavelengths)
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.material.is_dispersive()
+    }
+
+    fn alpha_mask(&self) -> Option<&AlphaMask> {
+        self.material.alpha_mask()
+    }
+
+    fn shading_normal(&self, hit_record: &HitRecord) -> Option<DVec3> {
+        match &self.detail {
+            SurfaceDetail::NormalMap(texture) => normal_mapped(texture.as_ref(), hit_record),
+            SurfaceDetail::BumpMap { height, scale } => {
+                bump_mapped(height.as_ref(), *scale, hit_record)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AARect, Hittable, Lambertian, SolidColour};
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct Ramp;
+
+    impl Texture for Ramp {
+        fn sample(&self, u: f64, _: f64, _: DVec3) -> DVec3 {
+            DVec3::splat(u)
+        }
+    }
+
+    fn shading_normal(detail: SurfaceDetail) -> DVec3 {
+        let material = NormalMapped {
+            material: Arc::new(Lambertian::new(DVec3::splat(0.5))),
+            detail,
+        };
+        let rect = AARect {
+            x_range: (0.0, 1.0),
+            y_range: (0.0, 1.0),
+            z: 0.0,
+            material: Arc::new(Lambertian::new(DVec3::splat(0.5))),
+        };
+        let ray = Ray {
+            origin: DVec3::new(0.5, 0.5, 1.0),
+            dir: DVec3::new(0.0, 0.0, -1.0),
+            time: 0.0,
+            wavelengths: None,
+        };
+
+        let hr = rect.hit(&ray, 0.001, 10.0).unwrap();
+        material.shading_normal(&hr).unwrap()
+    }
+
+    #[test]
+    fn flat_normal_map() {
+        let flat = SurfaceDetail::NormalMap(Arc::new(SolidColour {
+            colour: DVec3::new(0.5, 0.5, 1.0),
+        }));
+        assert!(shading_normal(flat).distance(DVec3::Z) < 1e-12);
+    }
+
+    #[test]
+    fn normal_map_follows_tangent_frame() {
+        // Tilted towards +u, which runs along +x on the rect
+        let tilted = SurfaceDetail::NormalMap(Arc::new(SolidColour {
+            colour: DVec3::new(1.0, 0.5, 1.0),
+        }));
+        let normal = shading_normal(tilted);
+        assert!(normal.distance(DVec3::new(1.0, 0.0, 1.0).normalize()) < 1e-12);
+    }
+
+    #[test]
+    fn bump_map_tilts_away_from_slope() {
+        let constant = SurfaceDetail::BumpMap {
+            height: Arc::new(SolidColour { colour: DVec3::ONE }),
+            scale: 1.0,
+        };
+        assert!(shading_normal(constant).distance(DVec3::Z) < 1e-12);
+
+        // Height rising along +x by 1 over the unit rect makes a 45 degree slope
+        let ramp = SurfaceDetail::BumpMap {
+            height: Arc::new(Ramp),
+            scale: 1.0,
+        };
+        let normal = shading_normal(ramp);
+        assert!(normal.distance(DVec3::new(-1.0, 0.0, 1.0).normalize()) < 1e-9);
+    }
+}
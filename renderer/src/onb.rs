@@ -1,5 +1,6 @@
 use glam::DVec3;
 
+#[derive(Clone, Copy, Debug)]
 pub struct OrthoNormalBasis {
     pub u: DVec3,
     pub v: DVec3,
@@ -20,6 +21,27 @@ impl OrthoNormalBasis {
         Self { u, v, w: *w }
     }
 
+    /// Frame around `w` with `u` along `tangent` projected onto the plane, for anisotropic
+    /// materials. Falls back to `from_w` when `tangent` is parallel to `w`.
+    pub fn from_w_and_tangent(w: &DVec3, tangent: &DVec3) -> Self {
+        let u = *tangent - *w * w.dot(*tangent);
+        if u.length_squared() < 1e-12 {
+            return Self::from_w(w);
+        }
+
+        let u = u.normalize();
+        Self {
+            u,
+            v: w.cross(u),
+            w: *w,
+        }
+    }
+
+    /// Inverse of `local`
+    pub fn to_local(&self, vec: &DVec3) -> DVec3 {
+        DVec3::new(vec.dot(self.u), vec.dot(self.v), vec.dot(self.w))
+    }
+
     pub fn local(&self, vec: &DVec3) -> DVec3 {
         (vec.x * self.u) + (vec.y * self.v) + (vec.z * self.w)
     }
@@ -0,0 +1,151 @@
+use glam::DVec3;
+
+use crate::{HitRecord, Ray};
+
+pub const PACKET_SIZE: usize = 8;
+
+/// Bit `i` set means lane `i` of the packet is active
+pub type PacketMask = u32;
+
+pub fn packet_lanes(mask: PacketMask) -> impl Iterator<Item = usize> {
+    (0..PACKET_SIZE).filter(move |lane| mask & (1 << lane) != 0)
+}
+
+/// A group of (ideally coherent) rays traced together through the scene.
+/// Lanes past `len` repeat the first ray and are never active.
+#[derive(Clone, Debug)]
+pub struct RayPacket {
+    pub rays: [Ray; PACKET_SIZE],
+    pub inv_dir: [DVec3; PACKET_SIZE],
+    len: usize,
+}
+
+impl RayPacket {
+    pub fn new(rays: &[Ray]) -> RayPacket {
+        assert!(
+            !rays.is_empty() && rays.len() <= PACKET_SIZE,
+            "Packet must contain between 1 and {} rays",
+            PACKET_SIZE
+        );
+
+        let mut packet_rays = [rays[0]; PACKET_SIZE];
+        packet_rays[..rays.len()].copy_from_slice(rays);
+
+        let mut inv_dir = [DVec3::ZERO; PACKET_SIZE];
+        for (inv, ray) in inv_dir.iter_mut().zip(packet_rays.iter()) {
+            *inv = DVec3::ONE / ray.dir;
+        }
+
+        RayPacket {
+            rays: packet_rays,
+            inv_dir,
+            len: rays.len(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn full_mask(&self) -> PacketMask {
+        (1 << self.len) - 1
+    }
+}
+
+/// Closest hit found so far for every lane of a packet
+pub struct PacketHits<'material> {
+    pub hits: [Option<HitRecord<'material>>; PACKET_SIZE],
+    pub t_max: [f64; PACKET_SIZE],
+}
+
+impl<'material> PacketHits<'material> {
+    pub fn new(t_max: f64) -> PacketHits<'material> {
+        PacketHits {
+            hits: Default::default(),
+            t_max: [t_max; PACKET_SIZE],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{BVHNode, Hittable, Lambertian, Sphere};
+
+    use super::*;
+
+    fn spheres() -> Vec<Arc<dyn Hittable>> {
+        let material = Arc::new(Lambertian::new(DVec3::splat(0.5)));
+        (0..10)
+            .map(|i| {
+                Arc::new(Sphere {
+                    center: DVec3::new(i as f64 - 5.0, 0.0, -5.0 - i as f64),
+                    radius: 0.6,
+                    material: material.clone(),
+                }) as Arc<dyn Hittable>
+            })
+            .collect()
+    }
+
+    fn rays() -> Vec<Ray> {
+        (0..PACKET_SIZE)
+            .map(|i| Ray {
+                origin: DVec3::ZERO,
+                dir: DVec3::new(i as f64 * 0.1 - 0.4, 0.0, -1.0),
+                time: 0.0,
+                wavelengths: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn packet_matches_single_rays() {
+        let objects = spheres();
+        let bvh = BVHNode::new(objects.as_slice(), 0.0, 0.0);
+        let rays = rays();
+        let packet = RayPacket::new(&rays);
+
+        let mut hits = PacketHits::new(f64::INFINITY);
+        bvh.hit_packet(&packet, packet.full_mask(), 0.001, &mut hits);
+
+        for (lane, ray) in rays.iter().enumerate() {
+            let single = bvh.hit(ray, 0.001, f64::INFINITY).map(|hr| hr.t);
+            let packed = hits.hits[lane].as_ref().map(|hr| hr.t);
+            assert_eq!(single, packed);
+        }
+    }
+
+    #[test]
+    fn occluded_packet_matches_single_rays() {
+        let objects = spheres();
+        let bvh = BVHNode::new(objects.as_slice(), 0.0, 0.0);
+        let rays = rays();
+        let packet = RayPacket::new(&rays);
+
+        let occluded =
+            bvh.occluded_packet(&packet, packet.full_mask(), 0.001, &[100.0; PACKET_SIZE]);
+
+        for (lane, ray) in rays.iter().enumerate() {
+            let single = bvh.hit(ray, 0.001, 100.0).is_some();
+            assert_eq!(single, occluded & (1 << lane) != 0);
+        }
+    }
+
+    #[test]
+    fn partial_packet_mask() {
+        let rays = rays();
+        let packet = RayPacket::new(&rays[0..3]);
+
+        assert_eq!(packet.len(), 3);
+        assert_eq!(packet.full_mask(), 0b111);
+        assert_eq!(
+            packet_lanes(packet.full_mask()).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+    }
+}
@@ -1,9 +1,9 @@
-use glam::DVec3;
+use glam::{DVec2, DVec3};
 use rand::{prelude::SliceRandom, Rng};
 
 use crate::{
-    material::reflectance, rand_cosine_hemisphere, rand_hemisphere, rand_in_unit_sphere, reflect,
-    refract, OrthoNormalBasis, Ray,
+    fresnel_dielectric, rand_cosine_hemisphere, rand_hemisphere, rand_in_unit_sphere, reflect,
+    refract, refract_through, Hittable, OrthoNormalBasis, Ray, Triangle, TrowbridgeReitz,
 };
 
 pub trait PDF {
@@ -132,6 +132,124 @@ impl PDF for FuzzyDiracDeltaPDF {
     }
 }
 
+/// Reflections off visible GGX microfacet normals, in the frame `basis`
+pub struct GGXReflectionPDF {
+    basis: OrthoNormalBasis,
+    /// Local direction back along the incoming ray
+    wo: DVec3,
+    distribution: TrowbridgeReitz,
+}
+
+impl GGXReflectionPDF {
+    pub fn new(basis: OrthoNormalBasis, wo: DVec3, distribution: TrowbridgeReitz) -> Self {
+        Self {
+            basis,
+            wo,
+            distribution,
+        }
+    }
+}
+
+impl PDF for GGXReflectionPDF {
+    fn value(&self, direction: DVec3) -> f64 {
+        let wi = self.basis.to_local(&direction.normalize());
+        if wi.z <= 0.0 || self.wo.z <= 0.0 {
+            return 0.0;
+        }
+
+        let wm = (self.wo + wi).normalize();
+        self.distribution.d_visible(self.wo, wm) / (4.0 * self.wo.dot(wm).abs())
+    }
+
+    fn generate(&self, rng: &mut dyn rand::RngCore) -> DVec3 {
+        let wm = self
+            .distribution
+            .sample_wm(self.wo, DVec2::new(rng.gen(), rng.gen()));
+        let wi = -self.wo + 2.0 * self.wo.dot(wm) * wm;
+        self.basis.local(&wi)
+    }
+}
+
+/// Reflections and refractions through visible GGX microfacet normals of a dielectric, chosen
+/// by their Fresnel reflectance, in the frame `basis`
+pub struct GGXDielectricPDF {
+    basis: OrthoNormalBasis,
+    /// Local direction back along the incoming ray
+    wo: DVec3,
+    distribution: TrowbridgeReitz,
+    /// IOR below the surface over the IOR above it
+    eta: f64,
+}
+
+impl GGXDielectricPDF {
+    pub fn new(
+        basis: OrthoNormalBasis,
+        wo: DVec3,
+        distribution: TrowbridgeReitz,
+        eta: f64,
+    ) -> Self {
+        Self {
+            basis,
+            wo,
+            distribution,
+            eta,
+        }
+    }
+}
+
+/// Microfacet normal that scatters `wo` into `wi` across an interface with relative IOR `eta`,
+/// facing up, or `None` if it would be back-facing to either direction
+pub(crate) fn dielectric_half_vector(wo: DVec3, wi: DVec3, eta: f64) -> Option<DVec3> {
+    let eta_p = if wi.z > 0.0 { 1.0 } else { eta };
+    let wm = wi * eta_p + wo;
+    if wo.z <= 0.0 || wi.z == 0.0 || wm.length_squared() == 0.0 {
+        return None;
+    }
+
+    let wm = wm.normalize();
+    let wm = if wm.z < 0.0 { -wm } else { wm };
+    if wm.dot(wi) * wi.z < 0.0 || wm.dot(wo) * wo.z < 0.0 {
+        return None;
+    }
+    Some(wm)
+}
+
+impl PDF for GGXDielectricPDF {
+    fn value(&self, direction: DVec3) -> f64 {
+        let wi = self.basis.to_local(&direction.normalize());
+        let wm = match dielectric_half_vector(self.wo, wi, self.eta) {
+            Some(wm) => wm,
+            None => return 0.0,
+        };
+
+        let reflectance = fresnel_dielectric(self.wo.dot(wm), self.eta);
+        let visible = self.distribution.d_visible(self.wo, wm);
+        if wi.z > 0.0 {
+            visible / (4.0 * self.wo.dot(wm).abs()) * reflectance
+        } else {
+            // Change of variables from the microfacet normal to the refracted direction
+            let denominator = wi.dot(wm) + self.wo.dot(wm) / self.eta;
+            let dwm_dwi = wi.dot(wm).abs() / (denominator * denominator);
+            visible * dwm_dwi * (1.0 - reflectance)
+        }
+    }
+
+    fn generate(&self, rng: &mut dyn rand::RngCore) -> DVec3 {
+        let wm = self
+            .distribution
+            .sample_wm(self.wo, DVec2::new(rng.gen(), rng.gen()));
+        let reflectance = fresnel_dielectric(self.wo.dot(wm), self.eta);
+
+        let wi = if rng.gen::<f64>() < reflectance {
+            None
+        } else {
+            refract_through(self.wo, wm, self.eta)
+        }
+        .unwrap_or_else(|| -self.wo + 2.0 * self.wo.dot(wm) * wm);
+        self.basis.local(&wi)
+    }
+}
+
 pub struct DielectricFresnelPDF {
     reflect_dir: DVec3,
     refract_dir: DVec3,
@@ -148,7 +266,7 @@ impl DielectricFresnelPDF {
         let cannot_refract = (ior * sin_theta) > 1.0;
         let reflect_dir = reflect(unit_direction, normal).normalize();
         let refract_dir = refract(unit_direction, normal, ior).normalize();
-        let reflectance_ratio = reflectance(cos_theta, ior);
+        let reflectance_ratio = fresnel_dielectric(cos_theta, 1.0 / ior);
 
         Self {
             reflect_dir,
@@ -157,6 +275,28 @@ impl DielectricFresnelPDF {
             cannot_refract,
         }
     }
+
+    /// A thin slab, which light passes straight through after bouncing around inside it
+    pub fn thin_walled(ray_in: &Ray, normal: DVec3, ior: f64) -> Self {
+        let unit_direction = ray_in.dir.normalize();
+        let cos_theta = (-unit_direction).dot(normal).min(1.0);
+
+        // Sum of the light reflected off the slab after each internal bounce
+        let reflectance = fresnel_dielectric(cos_theta, 1.0 / ior);
+        let reflectance = if reflectance < 1.0 {
+            reflectance
+                + (1.0 - reflectance).powi(2) * reflectance / (1.0 - reflectance * reflectance)
+        } else {
+            reflectance
+        };
+
+        Self {
+            reflect_dir: reflect(unit_direction, normal).normalize(),
+            refract_dir: unit_direction,
+            reflectance,
+            cannot_refract: false,
+        }
+    }
 }
 
 impl PDF for DielectricFresnelPDF {
@@ -180,6 +320,8 @@ impl PDF for DielectricFresnelPDF {
 pub enum MixtureMethod {
     Uniform,
     PowerHeuristic,
+    /// Chooses each PDF in proportion to its weight
+    Weighted(Vec<f64>),
 }
 
 pub struct MixturePDF {
@@ -192,6 +334,15 @@ impl MixturePDF {
         if method == MixtureMethod::PowerHeuristic && pdfs.len() > 2 {
             panic!("Power heuristic only implemented for 2 PDFS");
         }
+        if let MixtureMethod::Weighted(weights) = &method {
+            assert_eq!(weights.len(), pdfs.len(), "Need a weight per PDF");
+            let total: f64 = weights.iter().sum();
+            let weights = weights.iter().map(|weight| weight / total).collect();
+            return Self {
+                pdfs,
+                method: MixtureMethod::Weighted(weights),
+            };
+        }
         Self { pdfs, method }
     }
 }
@@ -207,6 +358,13 @@ impl PDF for MixturePDF {
                     .sum()
             }
             MixtureMethod::PowerHeuristic => todo!(),
+            MixtureMethod::Weighted(ref weights) => self
+                .pdfs
+                .iter()
+                .zip(weights)
+                .filter(|(_, weight)| **weight > 0.0)
+                .map(|(pdf, weight)| pdf.value(direction) * weight)
+                .sum(),
         }
     }
 
@@ -219,6 +377,18 @@ impl PDF for MixturePDF {
                 .generate(rng)
                 .normalize(),
             MixtureMethod::PowerHeuristic => todo!(),
+            MixtureMethod::Weighted(ref weights) => {
+                let mut u = rng.gen::<f64>();
+                let chosen = weights
+                    .iter()
+                    .position(|&weight| {
+                        u -= weight;
+                        u < 0.0
+                    })
+                    // Rounding can leave a sliver past the last weight
+                    .unwrap_or_else(|| weights.iter().rposition(|&w| w > 0.0).unwrap_or(0));
+                self.pdfs[chosen].generate(rng).normalize()
+            }
         }
     }
 }
@@ -261,3 +431,155 @@ impl PDF for UniformConePDF {
         ))
     }
 }
+
+/// Directions towards points sampled uniformly over the area of `shape`,
+/// converted to a density over solid angle as seen from `origin`
+pub struct AreaSampledPDF<S: Hittable> {
+    origin: DVec3,
+    shape: S,
+}
+
+impl<S: Hittable> AreaSampledPDF<S> {
+    pub fn new(origin: DVec3, shape: S) -> Self {
+        Self { origin, shape }
+    }
+}
+
+impl<S: Hittable> PDF for AreaSampledPDF<S> {
+    fn value(&self, direction: DVec3) -> f64 {
+        let ray = Ray {
+            origin: self.origin,
+            dir: direction.normalize(),
+            time: 0.0,
+            wavelengths: None,
+        };
+
+        match self.shape.hit(&ray, 0.0001, f64::INFINITY) {
+            Some(hr) => {
+                let cosine = hr.geometric_normal.dot(ray.dir).abs();
+                if cosine == 0.0 {
+                    return 0.0;
+                }
+                self.shape.pdf_uniform(hr.point) * hr.t * hr.t / cosine
+            }
+            None => 0.0,
+        }
+    }
+
+    fn generate(&self, rng: &mut dyn rand::RngCore) -> DVec3 {
+        (self.shape.sample_uniform(rng) - self.origin).normalize()
+    }
+}
+
+/// Solid angle bounds, in steradians, outside of which triangles are area sampled instead
+pub const MIN_SPHERICAL_SAMPLE_AREA: f64 = 3e-4;
+pub const MAX_SPHERICAL_SAMPLE_AREA: f64 = 6.22;
+
+/// Uniform over the solid angle a triangle subtends (Arvo 1995)
+pub struct SphericalTrianglePDF {
+    triangle: Triangle,
+    origin: DVec3,
+    // Unit directions from the origin to the vertices
+    a: DVec3,
+    b: DVec3,
+    c: DVec3,
+    alpha: f64,
+    area: f64,
+}
+
+/// Angle between two unit vectors, accurate for nearly parallel vectors unlike acos
+fn angle_between(v1: DVec3, v2: DVec3) -> f64 {
+    if v1.dot(v2) < 0.0 {
+        std::f64::consts::PI - 2.0 * ((v1 + v2).length() / 2.0).min(1.0).asin()
+    } else {
+        2.0 * ((v2 - v1).length() / 2.0).min(1.0).asin()
+    }
+}
+
+/// Component of `v` perpendicular to the unit vector `w`
+fn gram_schmidt(v: DVec3, w: DVec3) -> DVec3 {
+    v - v.dot(w) * w
+}
+
+impl SphericalTrianglePDF {
+    /// `None` if the triangle is degenerate as seen from `origin`
+    pub fn new(origin: DVec3, triangle: Triangle) -> Option<Self> {
+        let [p0, p1, p2] = triangle.positions();
+        let (a, b, c) = (
+            (p0 - origin).normalize_or_zero(),
+            (p1 - origin).normalize_or_zero(),
+            (p2 - origin).normalize_or_zero(),
+        );
+
+        let n_ab = a.cross(b).normalize_or_zero();
+        let n_bc = b.cross(c).normalize_or_zero();
+        let n_ca = c.cross(a).normalize_or_zero();
+        if n_ab == DVec3::ZERO || n_bc == DVec3::ZERO || n_ca == DVec3::ZERO {
+            return None;
+        }
+
+        // Interior angles of the spherical triangle, their excess over pi is its area
+        let alpha = angle_between(n_ab, -n_ca);
+        let beta = angle_between(n_bc, -n_ab);
+        let gamma = angle_between(n_ca, -n_bc);
+        let area = alpha + beta + gamma - std::f64::consts::PI;
+        if area <= 0.0 {
+            return None;
+        }
+
+        Some(Self {
+            triangle,
+            origin,
+            a,
+            b,
+            c,
+            alpha,
+            area,
+        })
+    }
+
+    pub fn solid_angle(&self) -> f64 {
+        self.area
+    }
+}
+
+impl PDF for SphericalTrianglePDF {
+    fn value(&self, direction: DVec3) -> f64 {
+        let ray = Ray {
+            origin: self.origin,
+            dir: direction,
+            time: 0.0,
+            wavelengths: None,
+        };
+        if self.triangle.intersect(&ray, 0.0, f64::INFINITY).is_some() {
+            1.0 / self.area
+        } else {
+            0.0
+        }
+    }
+
+    fn generate(&self, rng: &mut dyn rand::RngCore) -> DVec3 {
+        let (a, b, c) = (self.a, self.b, self.c);
+
+        // Pick the sub-triangle a, b, c' with the sampled fraction of the area
+        let sampled_area = rng.gen::<f64>() * self.area;
+        let (sin_area, cos_area) = (sampled_area - self.alpha).sin_cos();
+        let (sin_alpha, cos_alpha) = self.alpha.sin_cos();
+
+        let s = sin_area;
+        let t = cos_area;
+        let u = t - cos_alpha;
+        let v = s + sin_alpha * a.dot(b);
+        let q = ((v * t - u * s) * cos_alpha - v) / ((v * s + u * t) * sin_alpha);
+
+        let q = q.clamp(-1.0, 1.0);
+        let c_prime = q * a + (1.0 - q * q).max(0.0).sqrt() * gram_schmidt(c, a).normalize();
+
+        // Then a point along the arc from b to c'
+        let z = 1.0 - rng.gen::<f64>() * (1.0 - c_prime.dot(b));
+        let direction =
+            z * b + (1.0 - z * z).max(0.0).sqrt() * gram_schmidt(c_prime, b).normalize();
+
+        direction.normalize()
+    }
+}
@@ -1,9 +1,12 @@
 use glam::DVec3;
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
 pub struct Ray {
     pub origin: DVec3,
     pub dir: DVec3,
     pub time: f64,
+    /// Wavelengths in nm the path carries radiance at, one per channel, `None` while it carries
+    /// RGB. All three are the same once a dispersive material has split the path.
+    pub wavelengths: Option<DVec3>,
 }
 
 impl Ray {
@@ -2,18 +2,101 @@ use std::sync::Arc;
 
 use glam::DVec3;
 
-use crate::{BVHNode, Camera, HitRecord, Hittable, Ray, AABB, PDF};
+use crate::{
+    packet_lanes, rgb_at, BVHNode, Background, Camera, ConstantBackground, HitRecord, Hittable,
+    LightBounds, LightSample, LightSampler, LightSampling, PacketHits, PacketMask, Ray, RayPacket,
+    UniformLightSampler, AABB, PACKET_SIZE, PDF,
+};
 
 pub trait SampleableLight: Hittable {
     fn pdf_for_point(&self, point: DVec3) -> Box<dyn PDF>;
+
+    /// Total emitted flux for choosing between lights, or irradiance for lights at infinity
+    fn power(&self) -> f64;
+
+    /// Bounds for many-light sampling, `None` for lights at infinity
+    fn light_bounds(&self) -> Option<LightBounds> {
+        Some(LightBounds::omnidirectional(
+            self.bounding_box(0.0, 0.0)?,
+            self.power(),
+        ))
+    }
+
+    /// Samples a direction towards the light from `point` and the light arriving along it, at
+    /// `time` and at `wavelengths` for paths carrying a spectrum
+    fn sample_li(
+        &self,
+        point: DVec3,
+        time: f64,
+        wavelengths: Option<DVec3>,
+        rng: &mut dyn rand::RngCore,
+    ) -> Option<LightSample> {
+        traced_light_sample(self, point, time, wavelengths, rng)
+    }
+
+    /// Solid angle density of `sample_li` choosing `direction`, 0 for delta lights
+    fn pdf_li(&self, point: DVec3, direction: DVec3) -> f64 {
+        self.pdf_for_point(point).value(direction)
+    }
+
+    /// Emits from a single point or in a single direction, so only sampling it can find it
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    /// Radiance seen looking in `direction` by rays that escape the scene, for lights at
+    /// infinity that rays can find
+    fn escaped_radiance(&self, _: DVec3) -> Option<DVec3> {
+        None
+    }
+}
+
+/// Samples a direction from the light's `pdf_for_point` and finds the light along it at `time`
+pub(crate) fn traced_light_sample<L: SampleableLight + ?Sized>(
+    light: &L,
+    point: DVec3,
+    time: f64,
+    wavelengths: Option<DVec3>,
+    rng: &mut dyn rand::RngCore,
+) -> Option<LightSample> {
+    let pdf = light.pdf_for_point(point);
+    let direction = pdf.generate(rng).normalize();
+    let ray = Ray {
+        origin: point,
+        dir: direction,
+        time,
+        wavelengths: None,
+    };
+    let hit = light.hit(&ray, 0.001, f64::INFINITY)?;
+
+    let pdf = pdf.value(direction);
+    if pdf == 0.0 {
+        return None;
+    }
+    Some(LightSample {
+        direction,
+        distance: hit.t,
+        radiance: match wavelengths {
+            Some(wavelengths) => {
+                hit.material
+                    .emitted_spectral(hit.u, hit.v, hit.point, wavelengths)
+            }
+            None => hit.material.emitted(hit.u, hit.v, hit.point),
+        },
+        pdf,
+    })
 }
+
 #[derive(Clone)]
 pub struct Scene {
     objects: Vec<Arc<dyn Hittable>>,
     pub lights: Vec<Arc<dyn SampleableLight>>,
     pub camera: Camera,
-    pub background: fn(Ray) -> DVec3,
+    pub background: Arc<dyn Background>,
+    /// Lights at infinity, seen instead of `background` by rays that find them
+    infinite_lights: Vec<Arc<dyn SampleableLight>>,
     bvh: Option<BVHNode>,
+    light_sampler: Arc<dyn LightSampler>,
 }
 
 impl Default for Scene {
@@ -22,18 +105,72 @@ impl Default for Scene {
             objects: Default::default(),
             lights: Default::default(),
             camera: Default::default(),
-            background: |_| DVec3::ZERO,
+            background: Arc::new(ConstantBackground {
+                colour: DVec3::ZERO,
+            }),
+            infinite_lights: Vec::new(),
             bvh: None,
+            light_sampler: Arc::new(UniformLightSampler { count: 0 }),
         }
     }
 }
 
+/// Lets the material of the hit surface perturb its shading normal
+fn apply_shading_normal(hr: HitRecord) -> HitRecord {
+    match hr.material.shading_normal(&hr) {
+        Some(normal) => hr.with_shading_normal(normal),
+        None => hr,
+    }
+}
+
 impl Hittable for Scene {
     fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        if let Some(bvh) = &self.bvh {
+        let hr = if let Some(bvh) = &self.bvh {
             bvh.hit(ray, t_min, t_max)
         } else {
             self.objects.hit(ray, t_min, t_max)
+        };
+
+        hr.map(apply_shading_normal)
+    }
+
+    fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+        if let Some(bvh) = &self.bvh {
+            bvh.occluded(ray, t_min, t_max)
+        } else {
+            self.objects.occluded(ray, t_min, t_max)
+        }
+    }
+
+    fn hit_packet<'a>(
+        &'a self,
+        packet: &RayPacket,
+        mask: PacketMask,
+        t_min: f64,
+        hits: &mut PacketHits<'a>,
+    ) {
+        if let Some(bvh) = &self.bvh {
+            bvh.hit_packet(packet, mask, t_min, hits)
+        } else {
+            self.objects.hit_packet(packet, mask, t_min, hits)
+        }
+
+        for lane in packet_lanes(mask) {
+            hits.hits[lane] = hits.hits[lane].take().map(apply_shading_normal);
+        }
+    }
+
+    fn occluded_packet(
+        &self,
+        packet: &RayPacket,
+        mask: PacketMask,
+        t_min: f64,
+        t_max: &[f64; PACKET_SIZE],
+    ) -> PacketMask {
+        if let Some(bvh) = &self.bvh {
+            bvh.occluded_packet(packet, mask, t_min, t_max)
+        } else {
+            self.objects.occluded_packet(packet, mask, t_min, t_max)
         }
     }
 
@@ -58,12 +195,41 @@ impl Scene {
     pub fn build() -> SceneBuilder {
         SceneBuilder::default()
     }
+
+    /// Radiance reaching `ray` after it escapes the scene
+    pub fn background_radiance(&self, ray: Ray) -> DVec3 {
+        let radiance = self
+            .infinite_lights
+            .iter()
+            .filter_map(|light| light.escaped_radiance(ray.dir))
+            .reduce(|a, b| a + b)
+            .unwrap_or_else(|| self.background.radiance(&ray));
+        rgb_at(radiance, ray.wavelengths)
+    }
+
+    /// Chooses a light to sample for a shading point, along with the probability it was chosen
+    pub fn sample_light(
+        &self,
+        point: DVec3,
+        normal: DVec3,
+        u: f64,
+    ) -> Option<(&Arc<dyn SampleableLight>, f64)> {
+        let (light, pmf) = self.light_sampler.sample(point, normal, u)?;
+        Some((&self.lights[light], pmf))
+    }
 }
 
 #[derive(Default)]
 pub struct SceneBuilder {
     scene: Scene,
     build_bvh: bool,
+    light_sampling: LightSampling,
+    /// Objects whose emissive surfaces aren't registered as lights
+    unsampled: Vec<Arc<dyn Hittable>>,
+}
+
+fn address<T: ?Sized>(arc: &Arc<T>) -> *const () {
+    Arc::as_ptr(arc) as *const ()
 }
 
 impl SceneBuilder {
@@ -83,8 +249,24 @@ impl SceneBuilder {
         self.scene.objects.push(obj);
     }
 
-    pub fn background(mut self, background: fn(Ray) -> DVec3) -> Self {
-        self.scene.background = background;
+    pub fn add_light(&mut self, light: Arc<dyn SampleableLight>) {
+        self.scene.lights.push(light);
+    }
+
+    /// Adds an object that is never sampled as a light, it still emits light when rays hit it
+    pub fn add_unsampled_object(&mut self, obj: Arc<dyn Hittable>) {
+        self.unsampled.push(obj.clone());
+        self.scene.objects.push(obj);
+    }
+
+    pub fn background(mut self, background: impl Background + 'static) -> Self {
+        self.scene.background = Arc::new(background);
+        self
+    }
+
+    /// Lights the scene from infinitely far away, replacing `background`
+    pub fn environment(mut self, environment: Arc<dyn SampleableLight>) -> Self {
+        self.scene.lights.push(environment);
         self
     }
 
@@ -98,17 +280,161 @@ impl SceneBuilder {
         self
     }
 
+    pub fn light_sampling(mut self, light_sampling: LightSampling) -> Self {
+        self.light_sampling = light_sampling;
+        self
+    }
+
     pub fn build_bvh(mut self) -> Self {
         self.build_bvh = true;
         self
     }
 
+    /// Registers the emissive surfaces of every object as lights, besides those added with
+    /// `add_unsampled_object` or already passed to `lights`
     pub fn build(mut self) -> Scene {
+        for object in &self.scene.objects {
+            if self
+                .unsampled
+                .iter()
+                .any(|unsampled| address(unsampled) == address(object))
+            {
+                continue;
+            }
+
+            for light in object.clone().emissive_lights() {
+                if !self
+                    .scene
+                    .lights
+                    .iter()
+                    .any(|existing| address(existing) == address(&light))
+                {
+                    self.scene.lights.push(light);
+                }
+            }
+        }
+
+        self.scene.infinite_lights = self
+            .scene
+            .lights
+            .iter()
+            .filter(|light| light.light_bounds().is_none())
+            .cloned()
+            .collect();
+
+        // Bound moving objects over the whole shutter so rays at any time can find them
+        let (time_0, time_1) = self.scene.camera.shutter();
+        let scene_radius = self
+            .scene
+            .objects
+            .bounding_box(time_0, time_1)
+            .map_or(0.0, |bbox| (bbox.max - bbox.min).length() / 2.0);
+        self.scene.light_sampler = self.light_sampling.build(&self.scene.lights, scene_radius);
+
         if self.build_bvh {
-            let bvh = BVHNode::new(self.scene.objects.as_slice(), 0.0, 0.0);
+            let bvh = BVHNode::new(self.scene.objects.as_slice(), time_0, time_1);
             self.scene.bvh = Some(bvh);
         }
 
         self.scene
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{create_mesh, DiffuseLight, Lambertian, MovingSphere, SolidColour, Sphere};
+
+    use super::*;
+
+    #[test]
+    fn bvh_bounds_moving_objects_over_shutter() {
+        let mut builder = Scene::build();
+        builder.add_object(Arc::new(MovingSphere {
+            center_0: DVec3::new(0.0, 0.0, -5.0),
+            center_1: DVec3::new(10.0, 0.0, -5.0),
+            time_0: 0.0,
+            time_1: 1.0,
+            radius: 1.0,
+            material: Arc::new(Lambertian::new(DVec3::splat(0.5))),
+        }));
+
+        let camera = Camera::new(
+            DVec3::ZERO,
+            DVec3::new(0.0, 0.0, -1.0),
+            DVec3::Y,
+            90.0,
+            1.0,
+            0.0,
+            1.0,
+            0.0,
+            1.0,
+        );
+        let scene = builder.camera(camera).build_bvh().build();
+
+        let ray = Ray {
+            origin: DVec3::new(10.0, 0.0, 0.0),
+            dir: DVec3::new(0.0, 0.0, -1.0),
+            time: 1.0,
+            wavelengths: None,
+        };
+
+        assert!(scene.hit(&ray, 0.001, 100.0).is_some());
+        assert!(scene.occluded(&ray, 0.001, 100.0));
+    }
+
+    fn sphere(center: DVec3, material: Arc<dyn crate::Material>) -> Arc<Sphere> {
+        Arc::new(Sphere {
+            center,
+            radius: 1.0,
+            material,
+        })
+    }
+
+    #[test]
+    fn discovers_emissive_objects() {
+        let emissive: Arc<dyn crate::Material> = Arc::new(DiffuseLight {
+            emit_colour: Arc::new(SolidColour { colour: DVec3::ONE }),
+        });
+        let diffuse: Arc<dyn crate::Material> = Arc::new(Lambertian::new(DVec3::splat(0.5)));
+
+        let explicit = sphere(DVec3::new(0.0, 0.0, 0.0), emissive.clone());
+        let mut builder = Scene::build().lights(vec![explicit.clone()]);
+        builder.add_object(explicit);
+        builder.add_object(sphere(DVec3::new(3.0, 0.0, 0.0), emissive.clone()));
+        builder.add_object(sphere(DVec3::new(6.0, 0.0, 0.0), diffuse.clone()));
+        builder.add_unsampled_object(sphere(DVec3::new(9.0, 0.0, 0.0), emissive.clone()));
+
+        // Only the emissive triangles of a mesh become a light
+        let mut triangles = create_mesh(
+            vec![DVec3::ZERO, DVec3::X, DVec3::Y],
+            Vec::<DVec3>::new(),
+            Vec::<glam::DVec2>::new(),
+            vec![[0, 1, 2]],
+            emissive,
+        );
+        triangles.extend(create_mesh(
+            vec![DVec3::ZERO, DVec3::X, DVec3::Z],
+            Vec::<DVec3>::new(),
+            Vec::<glam::DVec2>::new(),
+            vec![[0, 1, 2]],
+            diffuse,
+        ));
+        builder.add_object(Arc::new(triangles));
+
+        let scene = builder.build_bvh().build();
+        let corners = scene
+            .lights
+            .iter()
+            .map(|light| light.bounding_box(0.0, 0.0).unwrap().min)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            corners,
+            vec![
+                DVec3::new(-1.0, -1.0, -1.0),
+                DVec3::new(2.0, -1.0, -1.0),
+                DVec3::ZERO
+            ]
+        );
+        assert_eq!(scene.lights[2].bounding_box(0.0, 0.0).unwrap().max.z, 0.0);
+    }
+}
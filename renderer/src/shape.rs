@@ -1,7 +1,8 @@
 use std::{ops::Mul, sync::Arc};
 
 use crate::{
-    rand_in_unit_sphere, spherical_direction, OrthoNormalBasis, SampleableLight, UniformConePDF,
+    light_sampler::surface_power, rand_in_unit_sphere, rand_unit_vector, spherical_direction,
+    AreaSampledPDF, DirectionCone, LightBounds, OrthoNormalBasis, SampleableLight, UniformConePDF,
     UniformSpherePDF, PDF,
 };
 
@@ -50,6 +51,32 @@ impl SampleableLight for Sphere {
             cos_theta_max,
         ))
     }
+
+    fn power(&self) -> f64 {
+        let area = 4.0 * std::f64::consts::PI * self.radius * self.radius;
+        surface_power(
+            self.material.as_ref(),
+            0.5,
+            0.5,
+            self.center + DVec3::new(self.radius, 0.0, 0.0),
+            area,
+        )
+    }
+}
+
+/// Uniform directions towards a sphere bounding a light, or in every direction from inside it.
+/// Covers a moving light at any time without knowing where it is then.
+pub(crate) fn bounding_sphere_pdf(point: DVec3, center: DVec3, radius: f64) -> Box<dyn PDF> {
+    let distance_squared = point.distance_squared(center);
+    if distance_squared <= radius * radius {
+        return Box::new(UniformConePDF::new(DVec3::Z, -1.0));
+    }
+
+    let cos_theta_max = f64::max(0.0, 1.0 - radius * radius / distance_squared).sqrt();
+    Box::new(UniformConePDF::new(
+        (center - point).normalize(),
+        cos_theta_max,
+    ))
 }
 
 pub trait Spherical {
@@ -90,12 +117,7 @@ fn spherical_uv(p: &DVec3) -> (f64, f64) {
     )
 }
 
-fn spherical_hit<'material, T: Spherical>(
-    sphere: &'material T,
-    ray: &Ray,
-    t_min: f64,
-    t_max: f64,
-) -> Option<HitRecord<'material>> {
+fn spherical_root<T: Spherical>(sphere: &T, ray: &Ray, t_min: f64, t_max: f64) -> Option<f64> {
     let center = sphere.center(ray.time);
     let radius = sphere.radius(ray.time);
 
@@ -118,19 +140,51 @@ fn spherical_hit<'material, T: Spherical>(
         }
     }
 
-    let t = root;
+    Some(root)
+}
+
+fn spherical_hit<'material, T: Spherical>(
+    sphere: &'material T,
+    ray: &Ray,
+    t_min: f64,
+    t_max: f64,
+) -> Option<HitRecord<'material>> {
+    let t = spherical_root(sphere, ray, t_min, t_max)?;
+
+    let center = sphere.center(ray.time);
+    let radius = sphere.radius(ray.time);
+
     let point = ray.at(t);
     let normal = (point - center) / radius;
     let (u, v) = spherical_uv(&normal);
     let hr = HitRecord::new(ray, &point, normal, sphere.material().as_ref(), t, u, v);
 
-    Some(hr)
+    // Derivatives of the mapping in `spherical_uv`, undefined at the poles
+    let sin_theta = (1.0 - normal.y * normal.y).max(0.0).sqrt();
+    if sin_theta == 0.0 {
+        return Some(hr);
+    }
+    let dpdu = 2.0 * std::f64::consts::PI * radius * DVec3::new(normal.z, 0.0, -normal.x);
+    let dpdv = std::f64::consts::PI
+        * radius
+        * DVec3::new(
+            -normal.x * normal.y / sin_theta,
+            sin_theta,
+            -normal.z * normal.y / sin_theta,
+        );
+
+    Some(hr.with_uv_derivatives(dpdu, dpdv))
 }
 
 impl Hittable for Sphere {
     fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
         spherical_hit(self, ray, t_min, t_max)
     }
+
+    fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+        spherical_root(self, ray, t_min, t_max).is_some()
+    }
+
     fn bounding_box(&self, _: f64, _: f64) -> Option<crate::AABB> {
         Some(AABB {
             min: self.center - DVec3::new(self.radius, self.radius, self.radius),
@@ -145,6 +199,14 @@ impl Hittable for Sphere {
     fn pdf_uniform(&self, point: DVec3) -> f64 {
         1.0 / (4.0 * std::f64::consts::PI * (self.radius * self.radius))
     }
+
+    fn emissive_lights(self: Arc<Self>) -> Vec<Arc<dyn SampleableLight>> {
+        if self.material.is_emissive() {
+            vec![self]
+        } else {
+            Vec::new()
+        }
+    }
     fn sample_from_ref(&self, rng: &mut dyn rand::RngCore, reference_point: DVec3) -> DVec3 {
         if (reference_point - self.center).length_squared() <= self.radius * self.radius {
             return self.sample_uniform(rng);
@@ -228,6 +290,11 @@ impl Hittable for MovingSphere {
     fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
         spherical_hit(self, ray, t_min, t_max)
     }
+
+    fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+        spherical_root(self, ray, t_min, t_max).is_some()
+    }
+
     fn bounding_box(&self, time_0: f64, time_1: f64) -> Option<AABB> {
         let center_0 = self.center(time_0);
         let center_1 = self.center(time_1);
@@ -246,15 +313,55 @@ impl Hittable for MovingSphere {
         ))
     }
 
-    fn sample_uniform(&self, _: &mut dyn rand::RngCore) -> DVec3 {
-        todo!()
+    /// Uniform over the surface of the sphere in the middle of its motion
+    fn sample_uniform(&self, rng: &mut dyn rand::RngCore) -> DVec3 {
+        self.center((self.time_0 + self.time_1) / 2.0) + rand_unit_vector(rng) * self.radius
     }
 
-    fn pdf_uniform(&self, point: DVec3) -> f64 {
-        todo!()
+    fn pdf_uniform(&self, _: DVec3) -> f64 {
+        1.0 / (4.0 * std::f64::consts::PI * self.radius * self.radius)
+    }
+
+    fn emissive_lights(self: Arc<Self>) -> Vec<Arc<dyn SampleableLight>> {
+        if self.material.is_emissive() {
+            vec![self]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+impl SampleableLight for MovingSphere {
+    // Samples towards the whole path, the default `sample_li` then finds the sphere at the
+    // ray's time
+    fn pdf_for_point(&self, point: DVec3) -> Box<dyn PDF> {
+        bounding_sphere_pdf(
+            point,
+            (self.center_0 + self.center_1) / 2.0,
+            self.radius + self.center_0.distance(self.center_1) / 2.0,
+        )
+    }
+
+    fn power(&self) -> f64 {
+        let area = 4.0 * std::f64::consts::PI * self.radius * self.radius;
+        surface_power(
+            self.material.as_ref(),
+            0.5,
+            0.5,
+            self.center_0 + DVec3::new(self.radius, 0.0, 0.0),
+            area,
+        )
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        Some(LightBounds::omnidirectional(
+            self.bounding_box(self.time_0, self.time_1)?,
+            self.power(),
+        ))
     }
 }
 
+#[derive(Clone)]
 pub struct AARect {
     pub x_range: (f64, f64),
     pub y_range: (f64, f64),
@@ -262,8 +369,9 @@ pub struct AARect {
     pub material: Arc<dyn Material>,
 }
 
-impl Hittable for AARect {
-    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+impl AARect {
+    /// Returns `t` and the hit point for the intersection with `ray`
+    fn intersect(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<(f64, DVec3)> {
         let t = (self.z - ray.origin.z) / ray.dir.z;
         if t < t_min || t > t_max {
             return None;
@@ -277,14 +385,25 @@ impl Hittable for AARect {
             return None;
         }
 
+        Some((t, ray_hit))
+    }
+}
+
+impl Hittable for AARect {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        let (t, ray_hit) = self.intersect(ray, t_min, t_max)?;
+
+        let (min_x, max_x) = self.x_range;
+        let (min_y, max_y) = self.y_range;
+
         let width = max_x - min_x;
         let height = max_y - min_y;
 
-        let u = (x - min_x) / width;
-        let v = (y - min_y) / height;
+        let u = (ray_hit.x - min_x) / width;
+        let v = (ray_hit.y - min_y) / height;
 
         let outward_normal = DVec3::new(0.0, 0.0, 1.0);
-        Some(HitRecord::new(
+        let hr = HitRecord::new(
             ray,
             &ray_hit,
             outward_normal,
@@ -292,7 +411,13 @@ impl Hittable for AARect {
             t,
             u,
             v,
-        ))
+        );
+
+        Some(hr.with_uv_derivatives(DVec3::new(width, 0.0, 0.0), DVec3::new(0.0, height, 0.0)))
+    }
+
+    fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+        self.intersect(ray, t_min, t_max).is_some()
     }
 
     fn bounding_box(&self, _: f64, _: f64) -> Option<AABB> {
@@ -304,12 +429,53 @@ impl Hittable for AARect {
         })
     }
 
-    fn sample_uniform(&self, _: &mut dyn rand::RngCore) -> DVec3 {
-        todo!()
+    fn sample_uniform(&self, rng: &mut dyn rand::RngCore) -> DVec3 {
+        let (min_x, max_x) = self.x_range;
+        let (min_y, max_y) = self.y_range;
+        DVec3::new(
+            min_x + rng.gen::<f64>() * (max_x - min_x),
+            min_y + rng.gen::<f64>() * (max_y - min_y),
+            self.z,
+        )
     }
 
-    fn pdf_uniform(&self, point: DVec3) -> f64 {
-        todo!()
+    fn pdf_uniform(&self, _: DVec3) -> f64 {
+        let (min_x, max_x) = self.x_range;
+        let (min_y, max_y) = self.y_range;
+        1.0 / ((max_x - min_x) * (max_y - min_y))
+    }
+
+    fn emissive_lights(self: Arc<Self>) -> Vec<Arc<dyn SampleableLight>> {
+        if self.material.is_emissive() {
+            vec![self]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+impl SampleableLight for AARect {
+    fn pdf_for_point(&self, point: DVec3) -> Box<dyn PDF> {
+        Box::new(AreaSampledPDF::new(point, self.clone()))
+    }
+
+    fn power(&self) -> f64 {
+        let (min_x, max_x) = self.x_range;
+        let (min_y, max_y) = self.y_range;
+        let centre = DVec3::new((min_x + max_x) / 2.0, (min_y + max_y) / 2.0, self.z);
+        let area = (max_x - min_x) * (max_y - min_y);
+        surface_power(self.material.as_ref(), 0.5, 0.5, centre, area)
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        Some(LightBounds {
+            normals: DirectionCone {
+                w: DVec3::Z,
+                cos_theta: 1.0,
+            },
+            two_sided: true,
+            ..LightBounds::omnidirectional(self.bounding_box(0.0, 0.0)?, self.power())
+        })
     }
 }
 
@@ -338,4 +504,71 @@ mod tests {
 
         assert!(false)
     }
+
+    #[test]
+    fn sphere_uv_derivatives() {
+        let sphere = Sphere {
+            center: DVec3::new(1.0, 2.0, 3.0),
+            radius: 2.0,
+            material: Arc::new(Lambertian::new(DVec3::ZERO)),
+        };
+        let ray = Ray {
+            origin: DVec3::new(-5.0, 3.0, 4.0),
+            dir: DVec3::new(6.0, -1.0, -1.0),
+            time: 0.0,
+            wavelengths: None,
+        };
+        let hr = sphere.hit(&ray, 0.001, 100.0).unwrap();
+
+        // Step along each derivative and check the uv mapping moves by the same amount
+        let delta = 1e-6;
+        let uv_at = |p: DVec3| spherical_uv(&((p - sphere.center) / sphere.radius));
+        let (u, v) = uv_at(hr.point);
+        let (u_du, v_du) = uv_at(hr.point + delta * hr.dpdu);
+        let (u_dv, v_dv) = uv_at(hr.point + delta * hr.dpdv);
+
+        assert!(((u_du - u) / delta - 1.0).abs() < 1e-4);
+        assert!(((v_du - v) / delta).abs() < 1e-4);
+        assert!(((u_dv - u) / delta).abs() < 1e-4);
+        assert!(((v_dv - v) / delta - 1.0).abs() < 1e-4);
+        assert!(hr.dpdu.cross(hr.dpdv).dot(hr.point - sphere.center) > 0.0);
+    }
+
+    #[test]
+    fn moving_light_is_sampled_where_it_is() {
+        let sphere = Arc::new(MovingSphere {
+            center_0: DVec3::new(-3.0, 0.0, -10.0),
+            center_1: DVec3::new(3.0, 0.0, -10.0),
+            time_0: 0.0,
+            time_1: 1.0,
+            radius: 1.0,
+            material: Arc::new(crate::DiffuseLight {
+                emit_colour: Arc::new(crate::SolidColour { colour: DVec3::ONE }),
+            }),
+        });
+        let lights = sphere.clone().emissive_lights();
+        assert_eq!(lights.len(), 1);
+
+        let mut rng = rand::thread_rng();
+        for time in [0.0, 1.0] {
+            let center = sphere.center(time);
+            let found = (0..1000)
+                .filter_map(|_| lights[0].sample_li(DVec3::ZERO, time, None, &mut rng))
+                .inspect(|sample| {
+                    let hit_point = sample.direction * sample.distance;
+                    assert!((hit_point.distance(center) - 1.0).abs() < 1e-6);
+                })
+                .count();
+            assert!(found > 0);
+        }
+
+        // Uniform samples cover the sphere halfway along its path
+        let middle = DVec3::new(0.0, 0.0, -10.0);
+        for _ in 0..100 {
+            let point = sphere.sample_uniform(&mut rng);
+            assert!((point.distance(middle) - 1.0).abs() < 1e-12);
+        }
+        let area = 4.0 * std::f64::consts::PI;
+        assert!((sphere.pdf_uniform(middle + DVec3::X) - 1.0 / area).abs() < 1e-12);
+    }
 }
@@ -0,0 +1,313 @@
+use std::f64::consts::PI;
+
+use glam::{DQuat, DVec2, DVec3};
+
+use crate::{
+    environment::uv_to_direction, xyz_to_srgb, Background, DistantLight, EnvironmentLight, Image,
+    Ray,
+};
+
+/// Illuminance of the sun above the atmosphere in klx, matching the sky's kcd/m²
+const SOLAR_ILLUMINANCE: f64 = 128.0;
+
+/// Angular radius of the sun's disc in degrees
+const SUN_ANGULAR_RADIUS: f64 = 0.2667;
+
+/// Wavelengths in μm standing in for the red, green and blue channels when attenuating the sun
+const CHANNEL_WAVELENGTHS: [f64; 3] = [0.610, 0.550, 0.465];
+
+/// Coefficients A to E of the Perez distribution, from Preetham et al. 1999
+type PerezCoefficients = [f64; 5];
+
+fn perez(coefficients: &PerezCoefficients, cos_theta: f64, gamma: f64) -> f64 {
+    let [a, b, c, d, e] = *coefficients;
+    (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+}
+
+/// Evaluates `coefficients` at the sun's zenith angle for the T², T and constant terms
+fn zenith_polynomial(coefficients: [[f64; 4]; 3], turbidity: f64, theta_s: f64) -> f64 {
+    let angles = [theta_s.powi(3), theta_s.powi(2), theta_s, 1.0];
+    let terms = coefficients
+        .iter()
+        .map(|row| {
+            row.iter()
+                .zip(angles.iter())
+                .map(|(c, a)| c * a)
+                .sum::<f64>()
+        })
+        .collect::<Vec<_>>();
+    turbidity * turbidity * terms[0] + turbidity * terms[1] + terms[2]
+}
+
+/// Clear daylight sky from the Preetham model, in kcd/m², with a uniformly lit ground below the
+/// horizon. The sun's disc is left to the `DistantLight` from `sun`.
+#[derive(Clone, Debug)]
+pub struct PreethamSky {
+    /// Unit direction towards the sun
+    sun_direction: DVec3,
+    turbidity: f64,
+    ground_albedo: DVec3,
+    intensity: f64,
+    /// Zenith luminance and chromaticity as Y, x and y
+    zenith: DVec3,
+    /// Perez coefficients for Y, x and y
+    coefficients: [PerezCoefficients; 3],
+    /// The Perez function at the zenith, which `zenith` is relative to
+    zenith_perez: DVec3,
+    /// Radiance of the ground, without the intensity applied
+    ground: DVec3,
+}
+
+impl PreethamSky {
+    /// `turbidity` is clamped to the model's range of 2 (very clear) to 10 (hazy)
+    pub fn new(sun_direction: DVec3, turbidity: f64, ground_albedo: DVec3) -> PreethamSky {
+        let sun_direction = sun_direction.normalize();
+        let t = turbidity.clamp(2.0, 10.0);
+        // The model breaks down once the sun sets
+        let theta_s = sun_direction.y.clamp(0.0, 1.0).acos().min(PI / 2.0 - 0.01);
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith = DVec3::new(
+            (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192,
+            zenith_polynomial(
+                [
+                    [0.00166, -0.00375, 0.00209, 0.0],
+                    [-0.02903, 0.06377, -0.03202, 0.00394],
+                    [0.11693, -0.21196, 0.06052, 0.25886],
+                ],
+                t,
+                theta_s,
+            ),
+            zenith_polynomial(
+                [
+                    [0.00275, -0.00610, 0.00317, 0.0],
+                    [-0.04214, 0.08970, -0.04153, 0.00516],
+                    [0.15346, -0.26756, 0.06670, 0.26688],
+                ],
+                t,
+                theta_s,
+            ),
+        );
+
+        let coefficients = [
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+        let zenith_perez = DVec3::new(
+            perez(&coefficients[0], 1.0, theta_s),
+            perez(&coefficients[1], 1.0, theta_s),
+            perez(&coefficients[2], 1.0, theta_s),
+        );
+
+        let mut sky = PreethamSky {
+            sun_direction,
+            turbidity: t,
+            ground_albedo,
+            intensity: 1.0,
+            zenith,
+            coefficients,
+            zenith_perez,
+            ground: DVec3::ZERO,
+        };
+        sky.ground = sky.ground_radiance();
+        sky
+    }
+
+    /// Scales the sky, sun and ground, e.g. to bring daylight into the range of other lights
+    pub fn with_intensity(mut self, intensity: f64) -> PreethamSky {
+        self.intensity = intensity;
+        self
+    }
+
+    /// Radiance of the sky above the horizon, without the intensity applied
+    fn sky_radiance(&self, direction: DVec3) -> DVec3 {
+        // Keep the Perez function finite at the horizon
+        let cos_theta = direction.y.max(0.001);
+        let gamma = direction.dot(self.sun_direction).clamp(-1.0, 1.0).acos();
+
+        let luminance =
+            self.zenith.x * perez(&self.coefficients[0], cos_theta, gamma) / self.zenith_perez.x;
+        let x =
+            self.zenith.y * perez(&self.coefficients[1], cos_theta, gamma) / self.zenith_perez.y;
+        let y =
+            self.zenith.z * perez(&self.coefficients[2], cos_theta, gamma) / self.zenith_perez.z;
+
+        let xyz = DVec3::new(x / y * luminance, luminance, (1.0 - x - y) / y * luminance);
+        xyz_to_srgb(xyz).max(DVec3::ZERO)
+    }
+
+    /// Irradiance on a surface facing the sun, after the atmosphere has scattered some of it
+    fn sun_irradiance(&self) -> DVec3 {
+        if self.sun_direction.y <= 0.0 {
+            return DVec3::ZERO;
+        }
+
+        // Relative optical air mass, Kasten and Young 1989
+        let zenith_degrees = self.sun_direction.y.acos().to_degrees();
+        let air_mass =
+            1.0 / (self.sun_direction.y + 0.50572 * (96.07995 - zenith_degrees).powf(-1.6364));
+
+        // Rayleigh scattering, then aerosols with Ångström's formula, as in Preetham et al.
+        let beta = 0.04608365822050 * self.turbidity - 0.04586025928522;
+        let transmittance = |wavelength: f64| {
+            let rayleigh = 0.008735 * wavelength.powf(-4.08);
+            let aerosol = beta * wavelength.powf(-1.3);
+            (-air_mass * (rayleigh + aerosol)).exp()
+        };
+
+        DVec3::new(
+            transmittance(CHANNEL_WAVELENGTHS[0]),
+            transmittance(CHANNEL_WAVELENGTHS[1]),
+            transmittance(CHANNEL_WAVELENGTHS[2]),
+        ) * SOLAR_ILLUMINANCE
+    }
+
+    /// Radiance of a diffuse ground lit by the whole sky and the sun
+    fn ground_radiance(&self) -> DVec3 {
+        // Integrate the sky's irradiance on the ground numerically
+        let (rows, columns) = (32, 64);
+        let mut sky_irradiance = DVec3::ZERO;
+        for row in 0..rows {
+            for column in 0..columns {
+                let uv = DVec2::new(
+                    (column as f64 + 0.5) / columns as f64,
+                    (row as f64 + 0.5) / rows as f64 / 2.0,
+                );
+                let direction = uv_to_direction(uv);
+                let solid_angle = (PI / 2.0 / rows as f64) * (2.0 * PI / columns as f64);
+                sky_irradiance +=
+                    self.sky_radiance(direction) * direction.y * (uv.y * PI).sin() * solid_angle;
+            }
+        }
+
+        let sun_irradiance = self.sun_irradiance() * self.sun_direction.y.max(0.0);
+        self.ground_albedo * (sky_irradiance + sun_irradiance) / PI
+    }
+
+    /// Radiance seen looking in `direction`, apart from the sun
+    pub fn radiance(&self, direction: DVec3) -> DVec3 {
+        let direction = direction.normalize();
+        if direction.y < 0.0 {
+            self.ground * self.intensity
+        } else {
+            self.sky_radiance(direction) * self.intensity
+        }
+    }
+
+    /// The sun's disc, to add alongside the sky
+    pub fn sun(&self) -> DistantLight {
+        DistantLight::new(
+            self.sun_direction,
+            self.sun_irradiance() * self.intensity,
+            SUN_ANGULAR_RADIUS,
+        )
+    }
+
+    /// Bakes the sky and ground into an image `width` pixels wide, so it can be importance
+    /// sampled as a light
+    pub fn environment_light(&self, width: u32) -> EnvironmentLight {
+        let height = (width / 2).max(1);
+        let ground = self.ground * self.intensity;
+
+        let mut image = Image::new((width, height));
+        for y in 0..height {
+            for x in 0..width {
+                let uv = DVec2::new(
+                    (x as f64 + 0.5) / width as f64,
+                    (y as f64 + 0.5) / height as f64,
+                );
+                let direction = uv_to_direction(uv);
+                let radiance = if direction.y < 0.0 {
+                    ground
+                } else {
+                    self.sky_radiance(direction) * self.intensity
+                };
+                image.put(x, y, &radiance);
+            }
+        }
+
+        EnvironmentLight::new(image, DQuat::IDENTITY, 1.0)
+    }
+}
+
+// Seen by escaping rays without being sampled, see `environment_light` for lighting
+impl Background for PreethamSky {
+    fn radiance(&self, ray: &Ray) -> DVec3 {
+        PreethamSky::radiance(self, ray.dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{luminance, SampleableLight};
+
+    #[test]
+    fn zenith_matches_model() {
+        let sky = PreethamSky::new(DVec3::new(1.0, 1.0, 0.0), 3.0, DVec3::splat(0.2));
+        let zenith = sky.radiance(DVec3::Y);
+        assert!(
+            (luminance(zenith) / sky.zenith.x - 1.0).abs() < 0.01,
+            "{} vs {}",
+            luminance(zenith),
+            sky.zenith.x
+        );
+
+        // Brighter around the sun than away from it, and blue overhead
+        let near_sun = sky.radiance(DVec3::new(1.0, 0.9, 0.0));
+        let away = sky.radiance(DVec3::new(-1.0, 0.9, 0.0));
+        assert!(luminance(near_sun) > 2.0 * luminance(away));
+        assert!(zenith.z > zenith.x);
+    }
+
+    #[test]
+    fn sun_dims_and_reddens_at_sunset() {
+        let noon = PreethamSky::new(DVec3::new(0.2, 1.0, 0.0), 3.0, DVec3::ZERO).sun();
+        let sunset = PreethamSky::new(DVec3::new(1.0, 0.08, 0.0), 3.0, DVec3::ZERO).sun();
+        assert!(!noon.is_delta());
+
+        assert!(luminance(sunset.irradiance) < 0.5 * luminance(noon.irradiance));
+        assert!(sunset.irradiance.z / sunset.irradiance.x < noon.irradiance.z / noon.irradiance.x);
+        // Strong enough to cast shadows in daylight
+        assert!(luminance(noon.irradiance) > 50.0);
+
+        let set = PreethamSky::new(DVec3::new(1.0, -0.1, 0.0), 3.0, DVec3::ZERO).sun();
+        assert_eq!(set.irradiance, DVec3::ZERO);
+    }
+
+    #[test]
+    fn baked_environment_matches_sky() {
+        let sky =
+            PreethamSky::new(DVec3::new(0.0, 0.5, 1.0), 4.0, DVec3::splat(0.3)).with_intensity(0.5);
+        let environment = sky.environment_light(64);
+
+        for &direction in &[
+            DVec3::new(0.3, 0.8, 0.2),
+            DVec3::new(-0.5, 0.4, -0.6),
+            DVec3::new(0.1, -0.7, 0.3),
+        ] {
+            let baked = luminance(environment.radiance(direction));
+            let exact = luminance(sky.radiance(direction));
+            assert!((baked / exact - 1.0).abs() < 0.25, "{} vs {}", baked, exact);
+        }
+    }
+}
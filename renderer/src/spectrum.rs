@@ -0,0 +1,574 @@
+use std::{f64::consts::PI, sync::OnceLock};
+
+use glam::{DMat3, DVec3};
+
+use crate::xyz_to_srgb;
+
+/// Visible wavelengths in nm that paths are split into
+pub const LAMBDA_MIN: f64 = 380.0;
+pub const LAMBDA_MAX: f64 = 780.0;
+
+/// Lobes of the multi-lobe fit to the CIE 1931 colour matching functions, Wyman et al. 2013,
+/// as (weight, mean, width below the mean, width above it)
+type Lobe = (f64, f64, f64, f64);
+
+const X_LOBES: [Lobe; 3] = [
+    (1.056, 599.8, 37.9, 31.0),
+    (0.362, 442.0, 16.0, 26.7),
+    (-0.065, 501.1, 20.4, 26.2),
+];
+const Y_LOBES: [Lobe; 2] = [(0.821, 568.8, 46.9, 40.5), (0.286, 530.9, 16.3, 31.1)];
+const Z_LOBES: [Lobe; 2] = [(1.217, 437.0, 11.8, 36.0), (0.681, 459.0, 26.0, 13.8)];
+
+fn evaluate_lobes(lobes: &[Lobe], wavelength: f64) -> f64 {
+    lobes
+        .iter()
+        .map(|&(weight, mean, below, above)| {
+            let width = if wavelength < mean { below } else { above };
+            weight * (-0.5 * ((wavelength - mean) / width).powi(2)).exp()
+        })
+        .sum()
+}
+
+/// Integral of the lobes over all wavelengths
+fn integrate_lobes(lobes: &[Lobe]) -> f64 {
+    lobes
+        .iter()
+        .map(|&(weight, _, below, above)| weight * (PI / 2.0).sqrt() * (below + above))
+        .sum()
+}
+
+/// CIE 1931 colour matching functions at `wavelength` in nm
+pub fn cie_xyz(wavelength: f64) -> DVec3 {
+    DVec3::new(
+        evaluate_lobes(&X_LOBES, wavelength),
+        evaluate_lobes(&Y_LOBES, wavelength),
+        evaluate_lobes(&Z_LOBES, wavelength),
+    )
+}
+
+/// Linear sRGB of light at a single wavelength, scaled so that wavelengths sampled uniformly
+/// over the visible range average to white
+pub fn wavelength_to_rgb(wavelength: f64) -> DVec3 {
+    let white = xyz_to_srgb(DVec3::new(
+        integrate_lobes(&X_LOBES),
+        integrate_lobes(&Y_LOBES),
+        integrate_lobes(&Z_LOBES),
+    ));
+    xyz_to_srgb(cie_xyz(wavelength)) / white * (LAMBDA_MAX - LAMBDA_MIN)
+}
+
+/// Maps `u` in [0, 1) to a visible wavelength, uniformly
+pub fn sample_wavelength(u: f64) -> f64 {
+    LAMBDA_MIN + u * (LAMBDA_MAX - LAMBDA_MIN)
+}
+
+/// Three wavelengths evenly spread over the visible range, the first chosen by `u`, one per
+/// channel of the radiance a spectral path carries
+pub fn sample_wavelengths(u: f64) -> DVec3 {
+    DVec3::new(
+        sample_wavelength(u),
+        sample_wavelength((u + 1.0 / 3.0).fract()),
+        sample_wavelength((u + 2.0 / 3.0).fract()),
+    )
+}
+
+/// Linear sRGB of `radiance` carried at `wavelengths`, one per channel
+pub fn wavelengths_to_rgb(radiance: DVec3, wavelengths: DVec3) -> DVec3 {
+    (wavelength_to_rgb(wavelengths.x) * radiance.x
+        + wavelength_to_rgb(wavelengths.y) * radiance.y
+        + wavelength_to_rgb(wavelengths.z) * radiance.z)
+        / 3.0
+}
+
+/// Radiance given as `rgb` at `wavelengths`, or `rgb` itself for paths that carry RGB
+pub fn rgb_at(rgb: DVec3, wavelengths: Option<DVec3>) -> DVec3 {
+    match wavelengths {
+        Some(wavelengths) => RgbSpectrum::emission(rgb).sample(wavelengths),
+        None => rgb,
+    }
+}
+
+/// Wavelengths spectra are integrated over to find their colour
+const QUADRATURE_POINTS: usize = 100;
+
+fn quadrature_wavelength(i: usize) -> f64 {
+    sample_wavelength((i as f64 + 0.5) / QUADRATURE_POINTS as f64)
+}
+
+/// Power or reflectance as a function of wavelength
+pub trait Spectrum: std::fmt::Debug + Send + Sync {
+    /// Value at `wavelength` in nm
+    fn value(&self, wavelength: f64) -> f64;
+
+    /// Values at `wavelengths`, one per channel
+    fn sample(&self, wavelengths: DVec3) -> DVec3 {
+        DVec3::new(
+            self.value(wavelengths.x),
+            self.value(wavelengths.y),
+            self.value(wavelengths.z),
+        )
+    }
+
+    /// Linear sRGB that spectral rendering sees the spectrum as
+    fn to_rgb(&self) -> DVec3 {
+        (0..QUADRATURE_POINTS).fold(DVec3::ZERO, |sum, i| {
+            let wavelength = quadrature_wavelength(i);
+            sum + wavelength_to_rgb(wavelength) * self.value(wavelength)
+        }) / QUADRATURE_POINTS as f64
+    }
+}
+
+/// Ratios of the dimmer channels to the brightest along each side of the sigmoid table
+const TABLE_RES: usize = 32;
+/// Brightnesses of the brightest channel the sigmoid table is fitted at
+const BRIGHTNESS_RES: usize = 16;
+
+fn smoothstep(x: f64) -> f64 {
+    x * x * (3.0 - 2.0 * x)
+}
+
+/// Brightness of level `k` of the sigmoid table, crowded towards black and full brightness
+/// where the fitted spectra change fastest
+fn table_brightness(k: usize) -> f64 {
+    smoothstep(smoothstep((k + 1) as f64 / BRIGHTNESS_RES as f64))
+}
+
+fn sigmoid(x: f64) -> f64 {
+    0.5 + x / (2.0 * (1.0 + x * x).sqrt())
+}
+
+fn sigmoid_derivative(x: f64) -> f64 {
+    0.5 / (1.0 + x * x).powf(1.5)
+}
+
+/// Position across the visible range, which keeps sigmoid coefficients well conditioned
+fn normalised_wavelength(wavelength: f64) -> f64 {
+    (wavelength - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN)
+}
+
+fn polynomial(coefficients: DVec3, t: f64) -> f64 {
+    (coefficients.x * t + coefficients.y) * t + coefficients.z
+}
+
+/// Coefficients of sigmoid spectra fitted to colours, Jakob and Hanika 2019. The table is
+/// indexed by the brightest channel, the brightness of that channel and the ratios of the next
+/// two to it. Sigmoids stay within [0, 1], so reflectances upsample to spectra that can't
+/// reflect more light than they receive.
+struct SigmoidTable {
+    coefficients: Vec<DVec3>,
+}
+
+impl SigmoidTable {
+    fn get() -> &'static SigmoidTable {
+        static TABLE: OnceLock<SigmoidTable> = OnceLock::new();
+        TABLE.get_or_init(SigmoidTable::build)
+    }
+
+    fn index(channel: usize, k: usize, i: usize, j: usize) -> usize {
+        ((channel * BRIGHTNESS_RES + k) * TABLE_RES + j) * TABLE_RES + i
+    }
+
+    fn build() -> SigmoidTable {
+        let samples: Vec<(f64, DVec3)> = (0..QUADRATURE_POINTS)
+            .map(|i| {
+                let wavelength = quadrature_wavelength(i);
+                (
+                    normalised_wavelength(wavelength),
+                    wavelength_to_rgb(wavelength),
+                )
+            })
+            .collect();
+
+        let mut coefficients = vec![DVec3::ZERO; 3 * BRIGHTNESS_RES * TABLE_RES * TABLE_RES];
+        for channel in 0..3 {
+            for k in 0..BRIGHTNESS_RES {
+                let brightness = table_brightness(k);
+                // Grey is a constant spectrum, whose sigmoid is capped short of 1
+                let grey = brightness.min(0.999);
+                let constant = (grey - 0.5) / (grey * (1.0 - grey)).sqrt();
+
+                // Walk away from grey, starting each fit from its neighbour's
+                for j in (0..TABLE_RES).rev() {
+                    for i in (0..TABLE_RES).rev() {
+                        let start = if i + 1 < TABLE_RES {
+                            coefficients[Self::index(channel, k, i + 1, j)]
+                        } else if j + 1 < TABLE_RES {
+                            coefficients[Self::index(channel, k, i, j + 1)]
+                        } else {
+                            DVec3::new(0.0, 0.0, constant)
+                        };
+
+                        let mut rgb = DVec3::ZERO;
+                        rgb[channel] = brightness;
+                        rgb[(channel + 1) % 3] = brightness * i as f64 / (TABLE_RES - 1) as f64;
+                        rgb[(channel + 2) % 3] = brightness * j as f64 / (TABLE_RES - 1) as f64;
+                        coefficients[Self::index(channel, k, i, j)] =
+                            fit_sigmoid(&samples, rgb, start);
+                    }
+                }
+            }
+        }
+        SigmoidTable { coefficients }
+    }
+
+    /// Interpolated coefficients for `brightness` within the fitted levels and the ratios `x`
+    /// and `y` in [0, 1]
+    fn lookup(&self, channel: usize, brightness: f64, x: f64, y: f64) -> DVec3 {
+        let x = x * (TABLE_RES - 1) as f64;
+        let y = y * (TABLE_RES - 1) as f64;
+        let i = (x as usize).min(TABLE_RES - 2);
+        let j = (y as usize).min(TABLE_RES - 2);
+        let fx = x - i as f64;
+        let fy = y - j as f64;
+
+        let k = (0..BRIGHTNESS_RES - 2)
+            .take_while(|&k| table_brightness(k + 1) < brightness)
+            .count();
+        let (z_0, z_1) = (table_brightness(k), table_brightness(k + 1));
+        let fz = ((brightness - z_0) / (z_1 - z_0)).clamp(0.0, 1.0);
+
+        let level = |k| {
+            let row = |j| {
+                self.coefficients[Self::index(channel, k, i, j)]
+                    .lerp(self.coefficients[Self::index(channel, k, i + 1, j)], fx)
+            };
+            row(j).lerp(row(j + 1), fy)
+        };
+        level(k).lerp(level(k + 1), fz)
+    }
+}
+
+/// Gauss-Newton fit of sigmoid coefficients to the spectrum the film sees as `target`, given
+/// the film's response at `samples` of normalised wavelength
+fn fit_sigmoid(samples: &[(f64, DVec3)], target: DVec3, start: DVec3) -> DVec3 {
+    let n = samples.len() as f64;
+    let residual = |coefficients: DVec3| {
+        samples.iter().fold(DVec3::ZERO, |sum, &(t, rgb)| {
+            sum + rgb * sigmoid(polynomial(coefficients, t))
+        }) / n
+            - target
+    };
+
+    let mut coefficients = start;
+    let mut error = residual(coefficients);
+    for _ in 0..50 {
+        if error.length() < 1e-7 {
+            break;
+        }
+
+        let jacobian = samples.iter().fold(DMat3::ZERO, |sum, &(t, rgb)| {
+            let slope = sigmoid_derivative(polynomial(coefficients, t));
+            sum + DMat3::from_cols(rgb * slope * t * t, rgb * slope * t, rgb * slope)
+        }) * (1.0 / n);
+        if jacobian.determinant().abs() < 1e-15 {
+            break;
+        }
+        let step = jacobian.inverse() * error;
+
+        // Shorten steps that overshoot until the fit improves
+        let mut scale = 1.0;
+        loop {
+            let next = coefficients - step * scale;
+            let next_error = residual(next);
+            if next_error.length() < error.length() {
+                coefficients = next;
+                error = next_error;
+                break;
+            }
+            scale *= 0.5;
+            if scale < 1e-4 {
+                return coefficients;
+            }
+        }
+    }
+    coefficients
+}
+
+/// Smooth spectrum that spectral rendering sees as an RGB colour, to upsample colours from
+/// textures and materials
+#[derive(Clone, Copy, Debug)]
+pub struct RgbSpectrum {
+    coefficients: DVec3,
+    scale: f64,
+}
+
+impl RgbSpectrum {
+    /// Spectrum of a reflectance. Colours no brighter than 1 become spectra within [0, 1];
+    /// brighter ones are treated as `emission`.
+    pub fn new(rgb: DVec3) -> RgbSpectrum {
+        let rgb = rgb.max(DVec3::ZERO);
+        let max = rgb.max_element();
+        if max > 1.0 {
+            return RgbSpectrum::emission(rgb);
+        }
+        // Below the dimmest fitted level its spectrum is scaled down
+        let brightness = max.max(table_brightness(0));
+        RgbSpectrum::fitted(rgb, brightness, max / brightness)
+    }
+
+    /// Spectrum of an emitter's colour, unbounded and proportional to it
+    pub fn emission(rgb: DVec3) -> RgbSpectrum {
+        let rgb = rgb.max(DVec3::ZERO);
+        RgbSpectrum::fitted(rgb, 0.5, 2.0 * rgb.max_element())
+    }
+
+    /// The table's sigmoid for the chromaticity of `rgb` at `brightness`, scaled by `scale`
+    fn fitted(rgb: DVec3, brightness: f64, scale: f64) -> RgbSpectrum {
+        let max = rgb.max_element();
+        if max <= 0.0 {
+            return RgbSpectrum {
+                coefficients: DVec3::ZERO,
+                scale: 0.0,
+            };
+        }
+
+        let channel = if rgb.x == max {
+            0
+        } else if rgb.y == max {
+            1
+        } else {
+            2
+        };
+        let coefficients = SigmoidTable::get().lookup(
+            channel,
+            brightness,
+            rgb[(channel + 1) % 3] / max,
+            rgb[(channel + 2) % 3] / max,
+        );
+        RgbSpectrum {
+            coefficients,
+            scale,
+        }
+    }
+}
+
+impl Spectrum for RgbSpectrum {
+    fn value(&self, wavelength: f64) -> f64 {
+        self.scale
+            * sigmoid(polynomial(
+                self.coefficients,
+                normalised_wavelength(wavelength),
+            ))
+    }
+}
+
+/// Planck's law for an ideal emitter at `temperature` in kelvin, scaled to peak at 1
+#[derive(Clone, Copy, Debug)]
+pub struct BlackbodySpectrum {
+    temperature: f64,
+    normalisation: f64,
+}
+
+impl BlackbodySpectrum {
+    pub fn new(temperature: f64) -> BlackbodySpectrum {
+        // Wien's displacement law
+        let peak = 2.8977721e-3 / temperature * 1e9;
+        BlackbodySpectrum {
+            temperature,
+            normalisation: 1.0 / planck(peak, temperature),
+        }
+    }
+}
+
+/// Spectral radiance of a blackbody in W/(sr m^3)
+fn planck(wavelength: f64, temperature: f64) -> f64 {
+    const C: f64 = 299792458.0;
+    const H: f64 = 6.62606957e-34;
+    const KB: f64 = 1.3806488e-23;
+    let metres = wavelength * 1e-9;
+    2.0 * H * C * C / (metres.powi(5) * ((H * C / (metres * KB * temperature)).exp() - 1.0))
+}
+
+impl Spectrum for BlackbodySpectrum {
+    fn value(&self, wavelength: f64) -> f64 {
+        planck(wavelength, self.temperature) * self.normalisation
+    }
+}
+
+/// Piecewise linear through measured `(wavelength, value)` samples, zero outside them
+#[derive(Clone, Debug)]
+pub struct TabulatedSpectrum {
+    samples: Vec<(f64, f64)>,
+}
+
+impl TabulatedSpectrum {
+    pub fn new(mut samples: Vec<(f64, f64)>) -> TabulatedSpectrum {
+        samples.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        TabulatedSpectrum { samples }
+    }
+}
+
+impl Spectrum for TabulatedSpectrum {
+    fn value(&self, wavelength: f64) -> f64 {
+        let i = self
+            .samples
+            .partition_point(|&(sample, _)| sample <= wavelength);
+        if i == 0 || i == self.samples.len() {
+            // Exactly on the last sample still counts
+            return match self.samples.last() {
+                Some(&(last, value)) if i > 0 && last == wavelength => value,
+                _ => 0.0,
+            };
+        }
+        let (w0, v0) = self.samples[i - 1];
+        let (w1, v1) = self.samples[i];
+        v0 + (v1 - v0) * (wavelength - w0) / (w1 - w0)
+    }
+}
+
+/// Index of refraction as a function of wavelength
+#[derive(Clone, Copy, Debug)]
+pub enum Dispersion {
+    /// n = a + b / λ², with λ in μm
+    Cauchy { a: f64, b: f64 },
+    /// n² = 1 + Σ bᵢλ² / (λ² - cᵢ), with λ in μm and cᵢ in μm²
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
+}
+
+impl Dispersion {
+    /// Schott N-BK7 crown glass
+    pub fn bk7() -> Dispersion {
+        Dispersion::Sellmeier {
+            b: [1.03961212, 0.231792344, 1.01046945],
+            c: [0.00600069867, 0.0200179144, 103.560653],
+        }
+    }
+
+    /// Schott F2 flint glass, which splits light more than crown glass
+    pub fn flint() -> Dispersion {
+        Dispersion::Sellmeier {
+            b: [1.34533359, 0.209073176, 0.937357162],
+            c: [0.00997743871, 0.0470450767, 111.886764],
+        }
+    }
+
+    /// Diamond, whose strong dispersion gives it its fire
+    pub fn diamond() -> Dispersion {
+        Dispersion::Sellmeier {
+            b: [0.3306, 4.3356, 0.0],
+            c: [0.030625, 0.011236, 0.0],
+        }
+    }
+
+    /// Index of refraction at `wavelength` in nm
+    pub fn ior(&self, wavelength: f64) -> f64 {
+        let micrometres = wavelength / 1000.0;
+        let lambda_2 = micrometres * micrometres;
+        match self {
+            Dispersion::Cauchy { a, b } => a + b / lambda_2,
+            Dispersion::Sellmeier { b, c } => (1.0
+                + b.iter()
+                    .zip(c.iter())
+                    .map(|(b, c)| b * lambda_2 / (lambda_2 - c))
+                    .sum::<f64>())
+            .sqrt(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wavelengths_average_to_white() {
+        let steps = 4000;
+        let mut total = DVec3::ZERO;
+        for i in 0..steps {
+            total += wavelength_to_rgb(sample_wavelength((i as f64 + 0.5) / steps as f64));
+        }
+        let average = total / steps as f64;
+        assert!(
+            (average - DVec3::ONE).abs().max_element() < 0.01,
+            "{}",
+            average
+        );
+
+        // Ends of the spectrum are red and blue
+        let red = wavelength_to_rgb(650.0);
+        let blue = wavelength_to_rgb(450.0);
+        assert!(red.x > red.y && red.x > red.z);
+        assert!(blue.z > blue.x && blue.z > blue.y);
+    }
+
+    #[test]
+    fn glass_disperses() {
+        // Refractive indices at the sodium D line
+        assert!((Dispersion::bk7().ior(587.6) - 1.5168).abs() < 1e-4);
+        assert!((Dispersion::diamond().ior(587.6) - 2.417).abs() < 2e-3);
+
+        let cauchy = Dispersion::Cauchy { a: 1.5, b: 0.004 };
+        assert!(cauchy.ior(450.0) > cauchy.ior(650.0));
+        assert!(Dispersion::flint().ior(450.0) - Dispersion::flint().ior(650.0) > 0.02);
+    }
+
+    #[test]
+    fn colours_survive_upsampling() {
+        for &rgb in &[
+            DVec3::ONE,
+            DVec3::new(0.8, 0.3, 0.1),
+            DVec3::new(0.2, 0.5, 0.9),
+            DVec3::new(0.1, 0.6, 0.2),
+            DVec3::new(4.0, 2.5, 1.0),
+        ] {
+            let spectrum = RgbSpectrum::new(rgb);
+            let round_trip = spectrum.to_rgb();
+            assert!(
+                ((round_trip - rgb) / rgb.max_element()).abs().max_element() < 0.01,
+                "{} became {}",
+                rgb,
+                round_trip
+            );
+        }
+        assert_eq!(RgbSpectrum::new(DVec3::ZERO).value(500.0), 0.0);
+
+        // Reflectances never reflect more than they receive, however saturated
+        for &rgb in &[
+            DVec3::ONE,
+            DVec3::new(0.9, 0.1, 0.1),
+            DVec3::new(0.1, 0.9, 0.1),
+            DVec3::new(0.05, 0.05, 0.95),
+            DVec3::new(1.0, 0.0, 0.0),
+            DVec3::new(0.8, 0.3, 0.1),
+        ] {
+            let spectrum = RgbSpectrum::new(rgb);
+            for i in 0..=100 {
+                let wavelength = 360.0 + 4.7 * i as f64;
+                let value = spectrum.value(wavelength);
+                assert!(
+                    (0.0..=1.0).contains(&value),
+                    "{} is {} at {}nm",
+                    rgb,
+                    value,
+                    wavelength
+                );
+            }
+        }
+
+        // Scaling an emitter's colour scales its spectrum, as does scaling a reflectance past 1
+        let dim = RgbSpectrum::emission(DVec3::new(1.6, 0.6, 0.2)).value(600.0);
+        let bright = RgbSpectrum::new(DVec3::new(3.2, 1.2, 0.4)).value(600.0);
+        assert!((bright - 2.0 * dim).abs() < 1e-12);
+    }
+
+    #[test]
+    fn blackbodies_redden_as_they_cool() {
+        let candle = BlackbodySpectrum::new(1900.0).to_rgb();
+        let daylight = BlackbodySpectrum::new(6500.0).to_rgb();
+        assert!(candle.x > candle.y && candle.y > candle.z);
+        assert!(daylight.z / daylight.x > 5.0 * candle.z / candle.x);
+        // Peaks at 1 at Wien's wavelength
+        assert!((BlackbodySpectrum::new(5000.0).value(579.55) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn tabulated_spectra_interpolate() {
+        let spectrum = TabulatedSpectrum::new(vec![(500.0, 1.0), (400.0, 0.0), (600.0, 0.5)]);
+        assert_eq!(spectrum.value(450.0), 0.5);
+        assert_eq!(spectrum.value(550.0), 0.75);
+        assert_eq!(spectrum.value(600.0), 0.5);
+        assert_eq!(spectrum.value(399.0), 0.0);
+        assert_eq!(spectrum.value(700.0), 0.0);
+    }
+}
@@ -1,12 +1,43 @@
 use std::sync::Arc;
 
-use glam::{DMat4, DVec3, DVec4, Vec4Swizzles};
+use glam::{DMat4, DQuat, DVec3, DVec4, Vec4Swizzles};
 
-use crate::{HitRecord, Hittable, Ray, AABB};
+use crate::{
+    scene::traced_light_sample, shape::bounding_sphere_pdf, HitRecord, Hittable, LightBounds,
+    LightSample, Ray, SampleableLight, AABB, PDF,
+};
+
+// Number of time samples used to bound a moving transform
+const MOTION_BOUNDS_STEPS: usize = 16;
+
+/// Transform keys at the start and end of a motion, decomposed so the rotation can be slerped
+#[derive(Clone)]
+struct TransformMotion {
+    time_0: f64,
+    time_1: f64,
+    start: (DVec3, DQuat, DVec3),
+    end: (DVec3, DQuat, DVec3),
+}
+
+impl TransformMotion {
+    fn interpolate(&self, time: f64) -> DMat4 {
+        let s = ((time - self.time_0) / (self.time_1 - self.time_0)).clamp(0.0, 1.0);
+
+        let (scale_0, rotation_0, translation_0) = self.start;
+        let (scale_1, rotation_1, translation_1) = self.end;
+
+        DMat4::from_scale_rotation_translation(
+            scale_0.lerp(scale_1, s),
+            rotation_0.slerp(rotation_1, s),
+            translation_0.lerp(translation_1, s),
+        )
+    }
+}
 
 pub struct Transformed {
     t: DMat4,
     t_inv: DMat4,
+    motion: Option<TransformMotion>,
     hittable: Arc<dyn Hittable>,
 }
 
@@ -15,23 +46,68 @@ impl Transformed {
         Transformed {
             t: transformation,
             t_inv: transformation.inverse(),
+            motion: None,
             hittable,
         }
     }
-}
 
-impl Hittable for Transformed {
-    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        let new_origin = self.t_inv * DVec4::from((ray.origin, 1.0));
-        let new_dir = self.t_inv * DVec4::from((ray.dir, 0.0));
+    /// Transform blending from `transform_0` at `time_0` to `transform_1` at `time_1`.
+    /// Both must be made of scale, rotation and translation only.
+    pub fn new_moving(
+        transform_0: DMat4,
+        transform_1: DMat4,
+        time_0: f64,
+        time_1: f64,
+        hittable: Arc<dyn Hittable>,
+    ) -> Transformed {
+        Transformed {
+            motion: Some(TransformMotion {
+                time_0,
+                time_1,
+                start: transform_0.to_scale_rotation_translation(),
+                end: transform_1.to_scale_rotation_translation(),
+            }),
+            ..Transformed::new(transform_0, hittable)
+        }
+    }
+
+    /// Middle of the motion, where uniform samples are taken
+    fn mid_time(&self) -> f64 {
+        self.motion
+            .as_ref()
+            .map_or(0.0, |motion| (motion.time_0 + motion.time_1) / 2.0)
+    }
 
-        let transformed = Ray {
+    /// Object to world transform and its inverse at `time`
+    fn matrices(&self, time: f64) -> (DMat4, DMat4) {
+        match &self.motion {
+            Some(motion) => {
+                let t = motion.interpolate(time);
+                (t, t.inverse())
+            }
+            None => (self.t, self.t_inv),
+        }
+    }
+
+    fn to_local(ray: &Ray, t_inv: &DMat4) -> Ray {
+        let new_origin = *t_inv * DVec4::from((ray.origin, 1.0));
+        let new_dir = *t_inv * DVec4::from((ray.dir, 0.0));
+
+        Ray {
             origin: new_origin.xyz(),
             dir: new_dir.xyz(),
             time: ray.time,
-        };
+            wavelengths: ray.wavelengths,
+        }
+    }
+}
+
+impl Hittable for Transformed {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        let (t, t_inv) = self.matrices(ray.time);
+        let transformed = Transformed::to_local(ray, &t_inv);
 
-        let inverse_transpose = self.t_inv.transpose();
+        let inverse_transpose = t_inv.transpose();
 
         self.hittable
             .hit(&transformed, t_min, t_max)
@@ -40,6 +116,11 @@ impl Hittable for Transformed {
                 normal: (inverse_transpose * DVec4::from((hr.normal, 0.0)))
                     .xyz()
                     .normalize(),
+                geometric_normal: (inverse_transpose * DVec4::from((hr.geometric_normal, 0.0)))
+                    .xyz()
+                    .normalize(),
+                dpdu: t.transform_vector3(hr.dpdu),
+                dpdv: t.transform_vector3(hr.dpdv),
                 material: hr.material,
                 t: hr.t,
                 u: hr.u,
@@ -48,24 +129,251 @@ impl Hittable for Transformed {
             })
     }
 
+    fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+        let (_, t_inv) = self.matrices(ray.time);
+        self.hittable
+            .occluded(&Transformed::to_local(ray, &t_inv), t_min, t_max)
+    }
+
     fn bounding_box(&self, time_0: f64, time_1: f64) -> Option<AABB> {
         let bb = self.hittable.bounding_box(time_0, time_1)?;
 
-        let max4 = DVec4::from((bb.max, 1.0));
-        let min4 = DVec4::from((bb.min, 1.0));
+        let motion = match &self.motion {
+            Some(motion) => motion,
+            None => return Some(bb.transform(&self.t)),
+        };
+
+        let steps = if time_1 > time_0 {
+            MOTION_BOUNDS_STEPS
+        } else {
+            1
+        };
+        let bbox = (0..=steps)
+            .map(|step| {
+                let time = time_0 + (time_1 - time_0) * (step as f64 / steps as f64);
+                bb.transform(&motion.interpolate(time))
+            })
+            .reduce(|a, b| AABB::surrounding_box(&a, &b))?;
+
+        // Rotating corners bulge outwards between samples, pad by the worst case sagitta
+        let angle = motion.start.1.angle_between(motion.end.1) / steps as f64;
+        let max_scale = motion.start.0.max(motion.end.0).max_element();
+        let radius = bb.min.abs().max(bb.max.abs()).length() * max_scale;
+        let padding = DVec3::splat(radius * (1.0 - (angle / 2.0).cos()));
+
+        Some(AABB {
+            min: bbox.min - padding,
+            max: bbox.max + padding,
+        })
+    }
+
+    /// Uniform over the object's own surface, mapped into the world in the middle of its motion
+    fn sample_uniform(&self, rng: &mut dyn rand::RngCore) -> DVec3 {
+        let (t, _) = self.matrices(self.mid_time());
+        t.transform_point3(self.hittable.sample_uniform(rng))
+    }
+
+    fn pdf_uniform(&self, point: DVec3) -> f64 {
+        let (t, t_inv) = self.matrices(self.mid_time());
+        let local_pdf = self.hittable.pdf_uniform(t_inv.transform_point3(point));
+        world_area_density(local_pdf, &t)
+    }
+
+    fn emissive_lights(self: Arc<Self>) -> Vec<Arc<dyn SampleableLight>> {
+        self.hittable
+            .clone()
+            .emissive_lights()
+            .into_iter()
+            .map(|light| {
+                Arc::new(TransformedLight {
+                    transformed: Transformed {
+                        t: self.t,
+                        t_inv: self.t_inv,
+                        motion: self.motion.clone(),
+                        hittable: light.clone(),
+                    },
+                    light,
+                }) as Arc<dyn SampleableLight>
+            })
+            .collect()
+    }
+}
+
+/// Solid angle density of the world space `direction` for a light whose directions in its own
+/// space have density `local_pdf`. Directions map as `w' = M w / |M w|`, which scales solid
+/// angles by `|det M| / |M w|^3`, where `M^-1 w' = w / |M w|`.
+fn world_density(local_pdf: f64, local_direction: DVec3, t: &DMat4) -> f64 {
+    local_pdf / (t.determinant().abs() * local_direction.length().powi(3))
+}
+
+/// Factor `t` scales surface areas by. Exact for rotations, translations and uniform scales,
+/// non-uniform scales stretch areas by an amount depending on their orientation.
+fn area_scale(t: &DMat4) -> f64 {
+    t.determinant().abs().powf(2.0 / 3.0)
+}
+
+/// Area density of points mapped by `t` from a surface where they have density `local_pdf`
+fn world_area_density(local_pdf: f64, t: &DMat4) -> f64 {
+    local_pdf / area_scale(t)
+}
+
+/// Directions sampled by a light's pdf in its own space, mapped into the world
+struct TransformedPDF {
+    pdf: Box<dyn PDF>,
+    t: DMat4,
+    t_inv: DMat4,
+}
+
+impl PDF for TransformedPDF {
+    fn value(&self, direction: DVec3) -> f64 {
+        let local = self.t_inv.transform_vector3(direction.normalize());
+        world_density(self.pdf.value(local.normalize()), local, &self.t)
+    }
+
+    fn generate(&self, rng: &mut dyn rand::RngCore) -> DVec3 {
+        self.t.transform_vector3(self.pdf.generate(rng)).normalize()
+    }
+
+    fn is_delta_distribution(&self) -> bool {
+        self.pdf.is_delta_distribution()
+    }
+}
+
+/// An emissive object inside a `Transformed`, sampled in its own space and mapped into the
+/// world. Moving transforms sample towards everywhere the light goes instead.
+struct TransformedLight {
+    /// Transform applied to the light, for intersections
+    transformed: Transformed,
+    light: Arc<dyn SampleableLight>,
+}
+
+impl TransformedLight {
+    fn shutter(&self) -> (f64, f64) {
+        self.transformed
+            .motion
+            .as_ref()
+            .map_or((0.0, 0.0), |motion| (motion.time_0, motion.time_1))
+    }
+}
+
+impl Hittable for TransformedLight {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        self.transformed.hit(ray, t_min, t_max)
+    }
+
+    fn occluded(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+        self.transformed.occluded(ray, t_min, t_max)
+    }
+
+    fn bounding_box(&self, time_0: f64, time_1: f64) -> Option<AABB> {
+        self.transformed.bounding_box(time_0, time_1)
+    }
+
+    fn sample_uniform(&self, rng: &mut dyn rand::RngCore) -> DVec3 {
+        self.transformed.sample_uniform(rng)
+    }
+
+    fn pdf_uniform(&self, point: DVec3) -> f64 {
+        self.transformed.pdf_uniform(point)
+    }
+}
+
+impl SampleableLight for TransformedLight {
+    fn pdf_for_point(&self, point: DVec3) -> Box<dyn PDF> {
+        if self.transformed.motion.is_none() {
+            let (t, t_inv) = (self.transformed.t, self.transformed.t_inv);
+            return Box::new(TransformedPDF {
+                pdf: self.light.pdf_for_point(t_inv.transform_point3(point)),
+                t,
+                t_inv,
+            });
+        }
+
+        let (time_0, time_1) = self.shutter();
+        match self.bounding_box(time_0, time_1) {
+            Some(bbox) => bounding_sphere_pdf(
+                point,
+                (bbox.min + bbox.max) / 2.0,
+                (bbox.max - bbox.min).length() / 2.0,
+            ),
+            None => self.light.pdf_for_point(point),
+        }
+    }
 
-        let max = (self.t * max4).xyz();
-        let min = (self.t * min4).xyz();
+    fn power(&self) -> f64 {
+        match self.light.light_bounds() {
+            Some(_) => self.light.power() * area_scale(&self.transformed.t),
+            None => self.light.power(),
+        }
+    }
 
-        Some(AABB { max, min })
+    fn light_bounds(&self) -> Option<LightBounds> {
+        self.light.light_bounds()?;
+        let (time_0, time_1) = self.shutter();
+        Some(LightBounds::omnidirectional(
+            self.bounding_box(time_0, time_1)?,
+            self.power(),
+        ))
     }
 
-    fn sample_uniform(&self, _: &mut dyn rand::RngCore) -> DVec3 {
-        todo!()
+    fn sample_li(
+        &self,
+        point: DVec3,
+        time: f64,
+        wavelengths: Option<DVec3>,
+        rng: &mut dyn rand::RngCore,
+    ) -> Option<LightSample> {
+        if self.transformed.motion.is_some() {
+            return traced_light_sample(self, point, time, wavelengths, rng);
+        }
+
+        let (t, t_inv) = (self.transformed.t, self.transformed.t_inv);
+        let sample = self
+            .light
+            .sample_li(t_inv.transform_point3(point), time, wavelengths, rng)?;
+
+        let direction = t.transform_vector3(sample.direction);
+        let stretch = direction.length();
+        let distance = sample.distance * stretch;
+        let (radiance, pdf) = if self.light.is_delta() {
+            // Point lights fall off with the distance in the world
+            let falloff = if distance.is_finite() {
+                1.0 / (stretch * stretch)
+            } else {
+                1.0
+            };
+            (sample.radiance * falloff, sample.pdf)
+        } else {
+            let local_direction = t_inv.transform_vector3(direction / stretch);
+            (
+                sample.radiance,
+                world_density(sample.pdf, local_direction, &t),
+            )
+        };
+
+        Some(LightSample {
+            direction: direction / stretch,
+            distance,
+            radiance,
+            pdf,
+        })
     }
 
-    fn pdf_uniform(&self, point: glam::DVec3) -> f64 {
-        todo!()
+    fn pdf_li(&self, point: DVec3, direction: DVec3) -> f64 {
+        if self.transformed.motion.is_some() {
+            return self.pdf_for_point(point).value(direction);
+        }
+
+        let (t, t_inv) = (self.transformed.t, self.transformed.t_inv);
+        let local = t_inv.transform_vector3(direction.normalize());
+        let local_pdf = self
+            .light
+            .pdf_li(t_inv.transform_point3(point), local.normalize());
+        world_density(local_pdf, local, &t)
+    }
+
+    fn is_delta(&self) -> bool {
+        self.light.is_delta()
     }
 }
 
@@ -74,7 +382,12 @@ mod tests {
 
     use glam::DVec3;
 
-    use crate::{Lambertian, Sphere};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{
+        create_mesh, rand_in_unit_sphere, AARect, BVHNode, DiffuseLight, Lambertian, Scene,
+        SolidColour, Sphere,
+    };
 
     use super::*;
 
@@ -90,6 +403,7 @@ mod tests {
             origin: DVec3::new(0.0, 2.0, 1.0),
             dir: DVec3::new(0.0, 0.0, -1.0),
             time: 0.0,
+            wavelengths: None,
         };
 
         assert!(sphere.hit(&ray, 0.0, 100.0).is_none());
@@ -99,4 +413,261 @@ mod tests {
 
         assert!(transformed_sphere.hit(&ray, 0.0, 100.0).is_some());
     }
+
+    #[test]
+    fn transformed_sphere_occluded() {
+        let sphere = Sphere {
+            center: DVec3::ZERO,
+            radius: 1.0,
+            material: Arc::new(Lambertian::new(DVec3::splat(0.5))),
+        };
+
+        let transformed_sphere = Transformed::new(
+            DMat4::from_translation(DVec3::new(0.0, 0.0, -5.0)),
+            Arc::new(sphere),
+        );
+
+        let ray = Ray {
+            origin: DVec3::ZERO,
+            dir: DVec3::new(0.0, 0.0, -1.0),
+            time: 0.0,
+            wavelengths: None,
+        };
+
+        assert!(transformed_sphere.occluded(&ray, 0.0, 100.0));
+        assert!(!transformed_sphere.occluded(&ray, 0.0, 3.9));
+    }
+
+    #[test]
+    fn moving_transform() {
+        let sphere = Arc::new(Sphere {
+            center: DVec3::ZERO,
+            radius: 1.0,
+            material: Arc::new(Lambertian::new(DVec3::splat(0.5))),
+        });
+
+        let moving = Transformed::new_moving(
+            DMat4::IDENTITY,
+            DMat4::from_translation(DVec3::new(10.0, 0.0, 0.0)),
+            0.0,
+            1.0,
+            sphere,
+        );
+
+        let ray_at = |x: f64, time: f64| Ray {
+            origin: DVec3::new(x, 0.0, 5.0),
+            dir: DVec3::new(0.0, 0.0, -1.0),
+            time,
+            wavelengths: None,
+        };
+
+        assert!(moving.hit(&ray_at(0.0, 0.0), 0.0, 100.0).is_some());
+        assert!(moving.hit(&ray_at(0.0, 1.0), 0.0, 100.0).is_none());
+        assert!(moving.hit(&ray_at(10.0, 1.0), 0.0, 100.0).is_some());
+        assert!(moving.occluded(&ray_at(5.0, 0.5), 0.0, 100.0));
+
+        let bbox = moving.bounding_box(0.0, 1.0).unwrap();
+        assert!(bbox.min.x <= -1.0 && bbox.max.x >= 11.0);
+    }
+
+    fn contains(bbox: &AABB, point: DVec3) -> bool {
+        let epsilon = 1e-9;
+        (bbox.min - DVec3::splat(epsilon)).cmple(point).all()
+            && point.cmple(bbox.max + DVec3::splat(epsilon)).all()
+    }
+
+    #[test]
+    fn rotated_sphere_bounds() {
+        let sphere = Arc::new(Sphere {
+            center: DVec3::new(2.0, 0.0, 0.0),
+            radius: 1.0,
+            material: Arc::new(Lambertian::new(DVec3::splat(0.5))),
+        });
+
+        let rotation = DMat4::from_rotation_y(std::f64::consts::FRAC_PI_2);
+        let rotated = Transformed::new(rotation, sphere);
+
+        // Sphere centre moves from +x to -z
+        let bbox = rotated.bounding_box(0.0, 0.0).unwrap();
+        assert!(contains(&bbox, DVec3::new(0.0, 0.0, -3.0)));
+        assert!(contains(&bbox, DVec3::new(0.0, 0.0, -1.0)));
+        assert!(contains(&bbox, DVec3::new(1.0, 1.0, -2.0)));
+        assert!(!contains(&bbox, DVec3::new(2.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn rotated_triangle_bounds() {
+        let triangles = create_mesh(
+            vec![
+                DVec3::new(0.0, 0.0, 0.0),
+                DVec3::new(1.0, 0.0, 0.0),
+                DVec3::new(0.0, 1.0, 0.0),
+            ],
+            vec![DVec3::Z; 3],
+            vec![glam::DVec2::ZERO; 3],
+            vec![[0, 1, 2]],
+            Arc::new(Lambertian::new(DVec3::splat(0.5))),
+        );
+
+        let rotated = Transformed::new(
+            DMat4::from_rotation_z(std::f64::consts::FRAC_PI_2),
+            Arc::new(triangles),
+        );
+
+        let bbox = rotated.bounding_box(0.0, 0.0).unwrap();
+        assert!(contains(&bbox, DVec3::new(-1.0, 0.0, 0.0)));
+        assert!(contains(&bbox, DVec3::new(0.0, 1.0, 0.0)));
+        assert!(bbox.min.x <= bbox.max.x && bbox.min.y <= bbox.max.y);
+    }
+
+    #[test]
+    fn nested_transforms_are_not_culled() {
+        let material = Arc::new(Lambertian::new(DVec3::splat(0.5)));
+        let sphere = Arc::new(Sphere {
+            center: DVec3::ZERO,
+            radius: 1.0,
+            material: material.clone(),
+        });
+
+        let inner = Arc::new(Transformed::new(
+            DMat4::from_translation(DVec3::new(3.0, 0.0, 0.0))
+                * DMat4::from_scale(DVec3::new(2.0, 0.5, 0.5)),
+            sphere,
+        ));
+        let outer: Arc<dyn Hittable> = Arc::new(Transformed::new(
+            DMat4::from_rotation_z(std::f64::consts::FRAC_PI_3)
+                * DMat4::from_scale(DVec3::new(-1.0, 1.0, 1.0)),
+            inner,
+        ));
+
+        let other: Arc<dyn Hittable> = Arc::new(Sphere {
+            center: DVec3::new(0.0, 0.0, -20.0),
+            radius: 1.0,
+            material,
+        });
+        let bvh = BVHNode::new(&[outer.clone(), other], 0.0, 0.0);
+
+        let bbox = outer.bounding_box(0.0, 0.0).unwrap();
+        let to_world = DMat4::from_rotation_z(std::f64::consts::FRAC_PI_3)
+            * DMat4::from_scale(DVec3::new(-1.0, 1.0, 1.0))
+            * DMat4::from_translation(DVec3::new(3.0, 0.0, 0.0))
+            * DMat4::from_scale(DVec3::new(2.0, 0.5, 0.5));
+
+        for i in 0..32 {
+            let phi = i as f64 / 32.0 * 2.0 * std::f64::consts::PI;
+            let local = DVec3::new(phi.cos(), phi.sin(), 0.0);
+            let world = to_world.transform_point3(local);
+            assert!(contains(&bbox, world));
+
+            let ray = Ray {
+                origin: DVec3::new(world.x, world.y, 10.0),
+                dir: DVec3::new(0.0, 0.0, -1.0),
+                time: 0.0,
+                wavelengths: None,
+            };
+            assert!(bvh.hit(&ray, 0.0, 100.0).is_some());
+        }
+    }
+
+    #[test]
+    fn transformed_uniform_samples() {
+        // A unit quad doubled in size, stood up and moved to z = -3
+        let quad = Arc::new(AARect {
+            x_range: (0.0, 1.0),
+            y_range: (0.0, 1.0),
+            z: 0.0,
+            material: Arc::new(Lambertian::new(DVec3::splat(0.5))),
+        });
+        let transform = DMat4::from_translation(DVec3::new(0.0, 0.0, -3.0))
+            * DMat4::from_rotation_x(std::f64::consts::FRAC_PI_2)
+            * DMat4::from_scale(DVec3::splat(2.0));
+        let transformed = Transformed::new(transform, quad.clone());
+
+        let mut rng = StdRng::seed_from_u64(5);
+        for _ in 0..100 {
+            let point = transformed.sample_uniform(&mut rng);
+            assert!((point.y).abs() < 1e-12);
+            assert!((0.0..=2.0).contains(&point.x));
+            assert!((-3.0..=-1.0).contains(&point.z));
+            assert!((transformed.pdf_uniform(point) - 0.25).abs() < 1e-12);
+        }
+
+        // Moving objects are sampled in the middle of their motion
+        let moving = Transformed::new_moving(
+            DMat4::IDENTITY,
+            DMat4::from_translation(DVec3::new(0.0, 0.0, 4.0)),
+            0.0,
+            1.0,
+            quad,
+        );
+        for _ in 0..100 {
+            let point = moving.sample_uniform(&mut rng);
+            assert!((point.z - 2.0).abs() < 1e-12);
+            assert!((moving.pdf_uniform(point) - 1.0).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn transformed_emissive_quad_is_sampled() {
+        // A 2 by 0.5 quad at y = 2, facing down towards the origin
+        let quad = Arc::new(AARect {
+            x_range: (0.0, 1.0),
+            y_range: (0.0, 1.0),
+            z: 0.0,
+            material: Arc::new(DiffuseLight {
+                emit_colour: Arc::new(SolidColour { colour: DVec3::ONE }),
+            }),
+        });
+        let transform = DMat4::from_translation(DVec3::new(0.0, 2.0, 0.0))
+            * DMat4::from_rotation_x(std::f64::consts::FRAC_PI_2)
+            * DMat4::from_scale(DVec3::new(2.0, 0.5, 1.0));
+        let mut builder = Scene::build();
+        builder.add_object(Arc::new(Transformed::new(transform, quad)));
+        let scene = builder.build();
+        assert_eq!(scene.lights.len(), 1);
+        let light = &scene.lights[0];
+
+        let point = DVec3::new(0.5, 0.0, 0.1);
+        let mut rng = StdRng::seed_from_u64(2);
+        let samples = 20_000;
+
+        // Samples land on the quad, with the density `pdf_li` reports
+        let mut light_estimate = 0.0;
+        for _ in 0..samples {
+            let sample = light.sample_li(point, 0.0, None, &mut rng).unwrap();
+            let ray = Ray {
+                origin: point,
+                dir: sample.direction,
+                time: 0.0,
+                wavelengths: None,
+            };
+            let hr = light.hit(&ray, 0.001, 100.0).unwrap();
+            assert!((hr.t - sample.distance).abs() < 1e-6);
+            let pdf_li = light.pdf_li(point, sample.direction);
+            assert!((pdf_li - sample.pdf).abs() < 1e-6 * sample.pdf);
+            light_estimate += sample.radiance.x / sample.pdf;
+        }
+        light_estimate /= samples as f64;
+
+        // Both estimate the solid angle of the quad, as it emits 1
+        let sphere_samples = 200_000;
+        let hits = (0..sphere_samples)
+            .filter(|_| {
+                let ray = Ray {
+                    origin: point,
+                    dir: rand_in_unit_sphere(&mut rng).normalize(),
+                    time: 0.0,
+                    wavelengths: None,
+                };
+                light.hit(&ray, 0.001, 100.0).is_some()
+            })
+            .count();
+        let solid_angle = hits as f64 / sphere_samples as f64 * 4.0 * std::f64::consts::PI;
+        assert!(
+            (light_estimate - solid_angle).abs() < 0.05 * solid_angle,
+            "{} {}",
+            light_estimate,
+            solid_angle
+        );
+    }
 }